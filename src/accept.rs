@@ -0,0 +1,99 @@
+/// Picks the type from `offered` that the `Accept` header likes best, going by the q-value of
+/// the most specific media range matching each type. Ties go to the earlier offered type, and
+/// without an `Accept` header the first one is picked. `None` means nothing is acceptable.
+pub fn negotiate<'a>(accept: Option<&String>, offered: &[&'a str]) -> Option<&'a str> {
+    let Some(accept) = accept else {
+        return offered.first().copied();
+    };
+
+    let ranges = parse_accept(accept);
+    offered.iter()
+        .filter_map(|offered| {
+            let quality = ranges.iter()
+                .filter_map(|(range, quality)| specificity(range, offered).map(|specificity| (specificity, *quality)))
+                .max_by_key(|(specificity, _)| *specificity)
+                .map(|(_, quality)| quality)?;
+            (quality > 0.0).then_some((*offered, quality))
+        })
+        .fold(None, |best: Option<(&str, f32)>, candidate| match best {
+            Some((_, quality)) if quality >= candidate.1 => best,
+            _ => Some(candidate),
+        })
+        .map(|(offered, _)| offered)
+}
+
+/// How closely a media range such as `text/*` matches `media_type`, or `None` if it doesn't.
+fn specificity(range: &str, media_type: &str) -> Option<u8> {
+    let (range_type, range_subtype) = range.split_once('/')?;
+    let (media_type, media_subtype) = media_type.split_once('/')?;
+    match (range_type, range_subtype) {
+        ("*", "*") => Some(0),
+        (range_type, "*") if range_type.eq_ignore_ascii_case(media_type) => Some(1),
+        (range_type, range_subtype)
+            if range_type.eq_ignore_ascii_case(media_type) && range_subtype.eq_ignore_ascii_case(media_subtype) => Some(2),
+        _ => None,
+    }
+}
+
+/// Splits an `Accept` value into media ranges and their `q` values. Other parameters are
+/// ignored and entries whose quality doesn't parse are skipped.
+fn parse_accept(value: &str) -> Vec<(String, f32)> {
+    value.split(',')
+        .filter_map(|entry| {
+            let mut params = entry.split(';');
+            let range = params.next()?.trim();
+            if !range.contains('/') {
+                return None;
+            }
+
+            let mut quality = 1.0;
+            for param in params {
+                if let Some((name, value)) = param.split_once('=') {
+                    if name.trim().eq_ignore_ascii_case("q") {
+                        quality = value.trim().parse::<f32>().ok().filter(|q| (0.0..=1.0).contains(q))?;
+                    }
+                }
+            }
+
+            Some((range.to_string(), quality))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use super::negotiate;
+
+    const OFFERED: &[&str] = &["text/plain", "application/json", "text/html"];
+
+    fn pick(accept: &str) -> Option<&'static str> {
+        negotiate(Some(&accept.to_string()), OFFERED)
+    }
+
+    #[test]
+    fn without_accept_the_first_type_is_picked() {
+        assert_eq!(negotiate(None, OFFERED), Some("text/plain"));
+    }
+
+    #[test]
+    fn quality_and_specificity_decide() {
+        assert_eq!(pick("application/json"), Some("application/json"));
+        assert_eq!(pick("text/html;q=0.9, application/json;q=0.5"), Some("text/html"));
+        assert_eq!(pick("text/*, text/plain;q=0.1"), Some("text/html"));
+        assert_eq!(pick("*/*;q=0.2, application/json"), Some("application/json"));
+        assert_eq!(pick("TEXT/HTML"), Some("text/html"));
+        // Equal qualities keep the server's order
+        assert_eq!(pick("text/html, text/plain"), Some("text/plain"));
+    }
+
+    #[test]
+    fn excluded_or_unmatched_types_are_not_acceptable() {
+        assert_eq!(pick("image/png"), None);
+        assert_eq!(pick("*/*;q=0"), None);
+        assert_eq!(pick("text/*;q=0, application/json;q=0"), None);
+        assert_eq!(pick("*/*, text/plain;q=0"), Some("application/json"));
+        // A quality that doesn't parse drops its entry rather than the whole header
+        assert_eq!(pick("application/json;q=2, text/html"), Some("text/html"));
+    }
+}
@@ -0,0 +1,216 @@
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{bail, Result};
+use crate::headers::HeaderMap;
+use crate::{HttpMethod, HttpVersion};
+
+const COMMON_FORMAT: &str = r#"{remote} - {user} [{time}] "{method} {path} {version}" {status} {bytes}"#;
+const COMBINED_FORMAT: &str = r#"{remote} - {user} [{time}] "{method} {path} {version}" {status} {bytes} "{referer}" "{user_agent}""#;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Literal(String),
+    Remote,
+    User,
+    Time,
+    Method,
+    Path,
+    Version,
+    Status,
+    Bytes,
+    BytesIn,
+    DurationMs,
+    UserAgent,
+    Referer,
+}
+
+impl TryFrom<&str> for Token {
+    type Error = anyhow::Error;
+
+    fn try_from(name: &str) -> Result<Self> {
+        Ok(match name {
+            "remote" => Self::Remote,
+            "user" => Self::User,
+            "time" => Self::Time,
+            "method" => Self::Method,
+            "path" => Self::Path,
+            "version" => Self::Version,
+            "status" => Self::Status,
+            "bytes" => Self::Bytes,
+            "bytes_in" => Self::BytesIn,
+            "duration_ms" => Self::DurationMs,
+            "user_agent" => Self::UserAgent,
+            "referer" => Self::Referer,
+            _ => bail!("Unknown access log token {{{}}}", name),
+        })
+    }
+}
+
+/// A parsed `--access-log-format` template. `common` and `combined` select the
+/// Common and Combined Log Formats; anything else is a template of `{token}`s.
+#[derive(Clone, Debug)]
+pub struct AccessLogFormat {
+    tokens: Vec<Token>,
+}
+
+pub struct AccessLogEntry<'a> {
+    pub remote: SocketAddr,
+    pub user: Option<&'a str>,
+    pub time: SystemTime,
+    pub method: &'a HttpMethod,
+    pub path: &'a str,
+    pub version: HttpVersion,
+    pub status: u16,
+    pub bytes: u64,
+    pub bytes_in: u64,
+    pub duration: Duration,
+    pub headers: &'a HeaderMap,
+}
+
+impl AccessLogFormat {
+    pub fn parse(template: &str) -> Result<Self> {
+        let template = match template {
+            "common" => COMMON_FORMAT,
+            "combined" => COMBINED_FORMAT,
+            template => template,
+        };
+
+        let mut tokens = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => bail!("Unterminated {{{} in access log format", name),
+                        }
+                    }
+                    if !literal.is_empty() {
+                        tokens.push(Token::Literal(std::mem::take(&mut literal)));
+                    }
+                    tokens.push(Token::try_from(name.as_str())?);
+                }
+                '}' => bail!("Unmatched '}}' in access log format"),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            tokens.push(Token::Literal(literal));
+        }
+
+        Ok(Self { tokens })
+    }
+
+    pub fn render(&self, entry: &AccessLogEntry) -> String {
+        let header = |name: &str| entry.headers.get(name).map(String::as_str).unwrap_or("-");
+
+        let mut line = String::new();
+        for token in &self.tokens {
+            match token {
+                Token::Literal(literal) => line.push_str(literal),
+                Token::Remote => line.push_str(&entry.remote.ip().to_string()),
+                Token::User => line.push_str(entry.user.unwrap_or("-")),
+                Token::Time => line.push_str(&format_clf_time(entry.time)),
+                Token::Method => line.push_str(&entry.method.to_string()),
+                Token::Path => line.push_str(entry.path),
+                Token::Version => line.push_str(&entry.version.to_string()),
+                Token::Status => line.push_str(&entry.status.to_string()),
+                Token::Bytes => line.push_str(&entry.bytes.to_string()),
+                Token::BytesIn => line.push_str(&entry.bytes_in.to_string()),
+                Token::DurationMs => line.push_str(&entry.duration.as_millis().to_string()),
+                Token::UserAgent => line.push_str(header("User-Agent")),
+                Token::Referer => line.push_str(header("Referer")),
+            }
+        }
+
+        line
+    }
+}
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Formats a time as `10/Oct/2000:13:55:36 +0000`, always in UTC.
+fn format_clf_time(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs_of_day = secs % 86400;
+
+    format!("{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day, MONTHS[month as usize - 1], year, secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60)
+}
+
+/// Converts days since the Unix epoch into a (year, month, day) civil date.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+    use pretty_assertions::assert_eq;
+    use crate::headers::HeaderMap;
+    use crate::{HttpMethod, HttpVersion};
+    use super::{AccessLogEntry, AccessLogFormat};
+
+    fn render(template: &str) -> String {
+        let headers: HeaderMap = [
+            ("User-Agent".to_string(), "curl/8.0".to_string()),
+            ("Referer".to_string(), "http://example.com/".to_string()),
+        ].into_iter().collect();
+        let entry = AccessLogEntry {
+            remote: "192.0.2.7:51234".parse().unwrap(),
+            user: Some("alice"),
+            time: UNIX_EPOCH + Duration::from_secs(971_186_136),
+            method: &HttpMethod::Get,
+            path: "/index.html",
+            version: HttpVersion::Http11,
+            status: 200,
+            bytes: 2326,
+            bytes_in: 78,
+            duration: Duration::from_millis(12),
+            headers: &headers,
+        };
+        AccessLogFormat::parse(template).unwrap().render(&entry)
+    }
+
+    #[test]
+    fn renders_combined_format() {
+        assert_eq!(render("combined"),
+            r#"192.0.2.7 - alice [10/Oct/2000:13:55:36 +0000] "GET /index.html HTTP/1.1" 200 2326 "http://example.com/" "curl/8.0""#);
+    }
+
+    #[test]
+    fn renders_custom_template() {
+        assert_eq!(render(r#"{remote} {method} {path} {status} {bytes} {duration_ms} "{user_agent}" {{{bytes_in}}}"#),
+            r#"192.0.2.7 GET /index.html 200 2326 12 "curl/8.0" {78}"#);
+    }
+
+    #[test]
+    fn rejects_bad_templates_up_front() {
+        assert!(AccessLogFormat::parse("{remote} {nope}").is_err());
+        assert!(AccessLogFormat::parse("{remote").is_err());
+        assert!(AccessLogFormat::parse("remote}").is_err());
+    }
+}
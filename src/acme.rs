@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, bail, Context, Result};
+use instant_acme::{Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, NewAccount, NewOrder, OrderStatus, RetryPolicy};
+use once_cell::sync::Lazy;
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tracing::{error, info};
+use crate::tls::certificate_subject;
+use crate::CONFIG;
+
+pub const CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// Certificates are renewed once they have less than this much validity left.
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+const RENEW_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// Key authorizations for the pending http-01 challenges, by token.
+static CHALLENGES: Lazy<Mutex<HashMap<String, String>>> = Lazy::new(Default::default);
+
+/// The certificate currently served for the `--acme-domain`s, swapped in place on renewal.
+static CERTIFICATE: Lazy<RwLock<Option<Arc<CertifiedKey>>>> = Lazy::new(Default::default);
+
+pub fn certificate() -> Option<Arc<CertifiedKey>> {
+    CERTIFICATE.read().unwrap().clone()
+}
+
+/// The key authorization to answer an http-01 challenge request for `path` with, if it is one.
+pub fn challenge_response(path: &str) -> Option<String> {
+    let token = path.strip_prefix(CHALLENGE_PREFIX)?;
+    CHALLENGES.lock().unwrap().get(token).cloned()
+}
+
+/// Loads the cached certificate for the `--acme-domain`s or orders a new one, then keeps it
+/// renewed in the background. Does nothing when no domains are configured.
+pub async fn start() -> Result<()> {
+    let (domains, cache) = {
+        let config = CONFIG.read().await;
+        (config.acme_domains.clone(), config.acme_cache.clone())
+    };
+    if domains.is_empty() {
+        return Ok(());
+    }
+
+    tokio::fs::create_dir_all(&cache).await
+        .with_context(|| format!("Failed to create ACME cache directory {}", cache.display()))?;
+
+    let cached = load_cached(&cache, &domains).await?;
+    let expires = match cached {
+        Some((key, expires)) if !renewal_due(expires) => {
+            info!("Using cached certificate for {}", domains.join(", "));
+            *CERTIFICATE.write().unwrap() = Some(Arc::new(key));
+            expires
+        }
+        _ => obtain(&cache, &domains).await?,
+    };
+
+    tokio::spawn(renew(cache, domains, expires));
+    Ok(())
+}
+
+async fn renew(cache: PathBuf, domains: Vec<String>, mut expires: SystemTime) {
+    loop {
+        tokio::time::sleep(RENEW_CHECK_INTERVAL).await;
+        if !renewal_due(expires) {
+            continue;
+        }
+
+        match obtain(&cache, &domains).await {
+            Ok(renewed) => expires = renewed,
+            Err(e) => error!("Failed to renew certificate for {}: {}", domains.join(", "), e),
+        }
+    }
+}
+
+fn renewal_due(expires: SystemTime) -> bool {
+    expires.duration_since(SystemTime::now()).map_or(true, |left| left < RENEW_BEFORE)
+}
+
+/// Orders a certificate, answering its http-01 challenges through `challenge_response`, and
+/// stores it in the cache. Returns when the new certificate expires.
+async fn obtain(cache: &Path, domains: &[String]) -> Result<SystemTime> {
+    info!("Ordering certificate for {}", domains.join(", "));
+    let account = account(cache).await?;
+    let identifiers: Vec<_> = domains.iter().map(|domain| Identifier::Dns(domain.clone())).collect();
+    let mut order = account.new_order(&NewOrder::new(&identifiers)).await?;
+
+    let mut tokens = Vec::new();
+    let mut authorizations = order.authorizations();
+    while let Some(authorization) = authorizations.next().await {
+        let mut authorization = authorization?;
+        let domain = authorization.identifier().to_string();
+        match authorization.status {
+            AuthorizationStatus::Pending => {}
+            AuthorizationStatus::Valid => continue,
+            status => bail!("Authorization for {} is {:?}", domain, status),
+        }
+
+        let mut challenge = authorization.challenge(ChallengeType::Http01)
+            .ok_or_else(|| anyhow!("No http-01 challenge offered for {}", domain))?;
+        let key_authorization = challenge.key_authorization().as_str().to_string();
+        CHALLENGES.lock().unwrap().insert(challenge.token.clone(), key_authorization);
+        tokens.push(challenge.token.clone());
+        challenge.set_ready().await?;
+    }
+
+    let status = order.poll_ready(&RetryPolicy::default()).await;
+    CHALLENGES.lock().unwrap().retain(|token, _| !tokens.contains(token));
+
+    let status = status?;
+    if status != OrderStatus::Ready {
+        bail!("Order for {} ended up {:?}", domains.join(", "), status);
+    }
+
+    let key_pem = order.finalize().await?;
+    let cert_pem = order.poll_certificate(&RetryPolicy::default()).await?;
+    tokio::fs::write(cache.join("key.pem"), &key_pem).await?;
+    tokio::fs::write(cache.join("cert.pem"), &cert_pem).await?;
+
+    let (key, expires) = certified_key(cert_pem.as_bytes(), key_pem.as_bytes())?;
+    *CERTIFICATE.write().unwrap() = Some(Arc::new(key));
+    info!("Obtained certificate for {}", domains.join(", "));
+    Ok(expires)
+}
+
+/// Restores the account stored in the cache, or registers a new one and stores it.
+async fn account(cache: &Path) -> Result<Account> {
+    let path = cache.join("account.json");
+    if let Ok(credentials) = tokio::fs::read(&path).await {
+        let credentials: AccountCredentials = serde_json::from_slice(&credentials)
+            .with_context(|| format!("Invalid ACME account in {}", path.display()))?;
+        return Ok(Account::builder()?.from_credentials(credentials).await?);
+    }
+
+    let (directory, email) = {
+        let config = CONFIG.read().await;
+        (config.acme_directory.clone(), config.acme_email.clone())
+    };
+    let contact: Vec<_> = email.iter().map(|email| format!("mailto:{}", email)).collect();
+    let contact: Vec<_> = contact.iter().map(String::as_str).collect();
+    let new_account = NewAccount { contact: &contact, terms_of_service_agreed: true, only_return_existing: false };
+    let (account, credentials) = Account::builder()?.create(&new_account, directory, None).await?;
+    tokio::fs::write(&path, serde_json::to_vec(&credentials)?).await?;
+    Ok(account)
+}
+
+/// Reads the cached certificate if it still covers all of `domains`.
+async fn load_cached(cache: &Path, domains: &[String]) -> Result<Option<(CertifiedKey, SystemTime)>> {
+    let (Ok(cert_pem), Ok(key_pem)) = (tokio::fs::read(cache.join("cert.pem")).await, tokio::fs::read(cache.join("key.pem")).await) else {
+        return Ok(None);
+    };
+
+    let (key, expires) = certified_key(&cert_pem, &key_pem)
+        .with_context(|| format!("Invalid cached certificate in {}", cache.display()))?;
+    let names = certificate_subject(&key.cert).map(|subject| subject.subject_alt_names).unwrap_or_default();
+    if !domains.iter().all(|domain| names.iter().any(|name| name.eq_ignore_ascii_case(domain))) {
+        return Ok(None);
+    }
+
+    Ok(Some((key, expires)))
+}
+
+fn certified_key(cert_pem: &[u8], key_pem: &[u8]) -> Result<(CertifiedKey, SystemTime)> {
+    let certs = rustls_pemfile::certs(&mut &*cert_pem).collect::<std::result::Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut &*key_pem)?.context("No private key found")?;
+    let expires = not_after(certs.first().context("No certificate found")?)?;
+    Ok((CertifiedKey::from_der(certs, key, &default_provider())?, expires))
+}
+
+fn not_after(cert: &CertificateDer) -> Result<SystemTime> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert)?;
+    let timestamp = cert.validity().not_after.timestamp();
+    Ok(UNIX_EPOCH + Duration::from_secs(timestamp.max(0) as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use rcgen::{date_time_ymd, CertificateParams, KeyPair};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use crate::redirect_to_https;
+    use crate::testing::{configure, header, temp_dir};
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    /// Writes a certificate for `names` expiring at the start of 2040 to `cache`, as if ordered.
+    fn write_cached(cache: &Path, names: &[&str]) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(names.iter().map(|name| name.to_string()).collect::<Vec<_>>()).unwrap();
+        params.not_after = date_time_ymd(2040, 1, 1);
+        std::fs::write(cache.join("cert.pem"), params.self_signed(&key).unwrap().pem()).unwrap();
+        std::fs::write(cache.join("key.pem"), key.serialize_pem()).unwrap();
+    }
+
+    #[test]
+    fn challenge_response_is_the_key_authorization_for_the_token() {
+        CHALLENGES.lock().unwrap().insert("known-token".to_string(), "known-token.thumbprint".to_string());
+
+        assert_eq!(challenge_response("/.well-known/acme-challenge/known-token").as_deref(), Some("known-token.thumbprint"));
+        assert_eq!(challenge_response("/.well-known/acme-challenge/other-token"), None);
+        assert_eq!(challenge_response("/known-token"), None);
+    }
+
+    #[tokio::test]
+    async fn challenge_is_answered_on_the_redirect_listener() {
+        let _config = configure(&[]).await;
+        CHALLENGES.lock().unwrap().insert("served-token".to_string(), "served-token.thumbprint".to_string());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let redirector = tokio::spawn(redirect_to_https(listener, 443));
+
+        let fetch = |target: &'static str| async move {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(format!("GET {} HTTP/1.1\r\nHost: example.com\r\n\r\n", target).as_bytes()).await.unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            String::from_utf8(response).unwrap()
+        };
+
+        let response = fetch("/.well-known/acme-challenge/served-token").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nserved-token.thumbprint"), "{}", response);
+
+        // Unknown tokens aren't redirected, since the CA fetches over plain HTTP
+        let response = fetch("/.well-known/acme-challenge/unknown-token").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+        assert_eq!(header(&response, "Location"), None);
+        redirector.abort();
+    }
+
+    #[test]
+    fn renewal_is_due_within_thirty_days_of_expiry() {
+        let now = SystemTime::now();
+        assert!(renewal_due(now - DAY));
+        assert!(renewal_due(now + 29 * DAY));
+        assert!(!renewal_due(now + 31 * DAY));
+        assert!(!renewal_due(now + 90 * DAY));
+    }
+
+    #[tokio::test]
+    async fn cached_certificate_is_used_only_if_it_covers_every_domain() {
+        let cache = temp_dir("acme-cache");
+        let domains = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        assert!(load_cached(&cache, &domains(&["example.com"])).await.unwrap().is_none());
+
+        write_cached(&cache, &["example.com", "www.example.com"]);
+        let (_, expires) = load_cached(&cache, &domains(&["WWW.example.com", "example.com"])).await.unwrap().unwrap();
+        assert_eq!(expires.duration_since(UNIX_EPOCH).unwrap().as_secs(), 2208988800);
+        assert!(load_cached(&cache, &domains(&["example.com", "api.example.com"])).await.unwrap().is_none());
+
+        std::fs::write(cache.join("cert.pem"), "not a certificate").unwrap();
+        assert!(load_cached(&cache, &domains(&["example.com"])).await.is_err());
+    }
+}
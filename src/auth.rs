@@ -0,0 +1,473 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, bail, Context, Result};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde_json::{Map, Value};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use itertools::Itertools;
+use md5::Md5;
+use once_cell::sync::Lazy;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use tracing::{debug, warn};
+use crate::error::HttpResult;
+use crate::http::{HttpResponse, HttpStatus};
+use crate::{RequestContext, CONFIG};
+
+/// The key and algorithm bearer tokens must be signed with, from `--jwt-secret` or
+/// `--jwt-public-key`.
+static JWT_KEY: OnceLock<(DecodingKey, Algorithm)> = OnceLock::new();
+
+/// How long a Digest nonce may be used before clients are told it's stale.
+const NONCE_LIFETIME: Duration = Duration::from_secs(5 * 60);
+const NONCE_BYTES: usize = 16;
+
+/// Digest nonces handed out, with when they were issued and the highest nonce count seen so far.
+static NONCES: Lazy<Mutex<HashMap<String, (Instant, u32)>>> = Lazy::new(Default::default);
+
+/// A `--basic-auth` or `--digest-auth` `PREFIX=FILE` entry: paths starting with `prefix` need
+/// a user from the htpasswd or htdigest `file`.
+#[derive(Clone, Debug)]
+pub struct AuthRule {
+    pub prefix: String,
+    pub file: String,
+}
+
+impl AuthRule {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.split_once('=') {
+            Some((prefix, file)) if prefix.starts_with('/') && !file.is_empty() => {
+                Ok(Self { prefix: prefix.to_string(), file: file.to_string() })
+            }
+            _ => bail!("Expected PREFIX=FILE with PREFIX starting with / but got {}", value),
+        }
+    }
+}
+
+/// Loads the key `--jwt-auth` tokens are verified with.
+pub async fn init() -> Result<()> {
+    let config = CONFIG.read().await;
+    let key = match (&config.jwt_secret, &config.jwt_public_key) {
+        (Some(secret), _) => (DecodingKey::from_secret(secret.as_bytes()), Algorithm::HS256),
+        (None, Some(path)) => {
+            let pem = tokio::fs::read(path).await.with_context(|| format!("Failed to read JWT public key {}", path))?;
+            (DecodingKey::from_rsa_pem(&pem).with_context(|| format!("Invalid JWT public key {}", path))?, Algorithm::RS256)
+        }
+        (None, None) if config.jwt_auth.is_empty() => return Ok(()),
+        (None, None) => bail!("--jwt-auth requires --jwt-secret or --jwt-public-key"),
+    };
+
+    JWT_KEY.set(key).map_err(|_| anyhow!("JWT key already loaded"))
+}
+
+/// Checks the request's credentials when its path is protected, recording the user or token
+/// claims on success. Returns the 401 challenge to send instead of the response when they're
+/// missing or wrong.
+pub async fn authenticate(ctx: &mut RequestContext) -> HttpResult<Option<HttpResponse>> {
+    if let Some(challenge) = basic(ctx).await? {
+        return Ok(Some(challenge));
+    }
+    if let Some(challenge) = digest(ctx).await? {
+        return Ok(Some(challenge));
+    }
+    Ok(bearer(ctx).await)
+}
+
+/// The most specific rule covering `path`.
+fn matching_rule(rules: &[AuthRule], path: &str) -> Option<AuthRule> {
+    rules.iter()
+        .filter(|rule| path.starts_with(&rule.prefix))
+        .max_by_key(|rule| rule.prefix.len())
+        .cloned()
+}
+
+async fn basic(ctx: &mut RequestContext) -> HttpResult<Option<HttpResponse>> {
+    let (rule, realm) = {
+        let config = CONFIG.read().await;
+        (matching_rule(&config.basic_auth, &ctx.path), config.auth_realm.clone())
+    };
+    let Some(rule) = rule else {
+        return Ok(None);
+    };
+
+    if let Some((user, password)) = ctx.headers.get("Authorization").and_then(|value| basic_credentials(value)) {
+        let htpasswd = tokio::fs::read_to_string(&rule.file).await?;
+        let hash = htpasswd.lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| *name == user)
+            .map(|(_, hash)| hash.trim().to_string());
+
+        if let Some(hash) = hash {
+            let verify_password = password.clone();
+            if tokio::task::spawn_blocking(move || verify(&verify_password, &hash)).await.map_err(anyhow::Error::from)? {
+                ctx.user = Some(user);
+                return Ok(None);
+            }
+        }
+        debug!("Rejected credentials for {} on {}", user, ctx.path);
+    }
+
+    let challenge = format!("Basic realm=\"{}\", charset=\"UTF-8\"", quotable(&realm));
+    Ok(Some(HttpResponse::new(HttpStatus::Unauthorized).with_header("WWW-Authenticate", challenge)))
+}
+
+/// Verifies the `Authorization: Bearer` token on `--jwt-auth` paths and exposes its claims.
+async fn bearer(ctx: &mut RequestContext) -> Option<HttpResponse> {
+    let realm = {
+        let config = CONFIG.read().await;
+        if !config.jwt_auth.iter().any(|prefix| ctx.path.starts_with(prefix)) {
+            return None;
+        }
+        quotable(&config.auth_realm)
+    };
+
+    let token = ctx.headers.get("Authorization")
+        .and_then(|value| value.trim().split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| token.trim());
+    // Clients that didn't try get a bare challenge, ones with a bad token are told why (RFC 6750)
+    let challenge = match token {
+        None => format!("Bearer realm=\"{}\"", realm),
+        Some(token) => {
+            let (key, algorithm) = JWT_KEY.get()?;
+            match jsonwebtoken::decode::<Map<String, Value>>(token, key, &Validation::new(*algorithm)) {
+                Ok(data) => {
+                    ctx.claims = Some(data.claims);
+                    return None;
+                }
+                Err(e) => {
+                    debug!("Rejected bearer token on {}: {}", ctx.path, e);
+                    let description = match e.kind() {
+                        ErrorKind::ExpiredSignature => "The token has expired",
+                        _ => "The token is invalid",
+                    };
+                    format!("Bearer realm=\"{}\", error=\"invalid_token\", error_description=\"{}\"", realm, description)
+                }
+            }
+        }
+    };
+
+    Some(HttpResponse::new(HttpStatus::Unauthorized).with_header("WWW-Authenticate", challenge))
+}
+
+/// Checks RFC 7616 Digest credentials on `--digest-auth` paths against an htdigest file, whose
+/// `user:realm:hash` lines may hold MD5 or SHA-256 hashes of `user:realm:password`.
+async fn digest(ctx: &mut RequestContext) -> HttpResult<Option<HttpResponse>> {
+    let (rule, realm) = {
+        let config = CONFIG.read().await;
+        (matching_rule(&config.digest_auth, &ctx.path), config.auth_realm.clone())
+    };
+    let Some(rule) = rule else {
+        return Ok(None);
+    };
+
+    let credentials = ctx.headers.get("Authorization")
+        .and_then(|value| value.trim().split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("digest"))
+        .map(|(_, params)| parse_auth_params(params));
+    let mut stale = false;
+    if let Some(params) = credentials {
+        let htdigest = tokio::fs::read_to_string(&rule.file).await?;
+        match verify_digest(&params, &ctx.method.to_string(), &ctx.target, &realm, &htdigest) {
+            DigestOutcome::Valid(user) => {
+                ctx.user = Some(user);
+                return Ok(None);
+            }
+            DigestOutcome::Stale => stale = true,
+            DigestOutcome::Invalid => debug!("Rejected Digest credentials on {}", ctx.path),
+        }
+    }
+
+    // Either algorithm can be checked, depending on what the user's htdigest line holds
+    let realm = quotable(&realm);
+    let challenges = ["SHA-256", "MD5"].map(|algorithm| format!(
+        "Digest realm=\"{}\", qop=\"auth\", algorithm={}, nonce=\"{}\"{}",
+        realm, algorithm, new_nonce(), if stale { ", stale=true" } else { "" },
+    ));
+    Ok(Some(HttpResponse::new(HttpStatus::Unauthorized).with_header("WWW-Authenticate", challenges.join(", "))))
+}
+
+enum DigestOutcome {
+    Valid(String),
+    /// The credentials were right but used an expired or unknown nonce
+    Stale,
+    Invalid,
+}
+
+fn verify_digest(params: &HashMap<String, String>, method: &str, target: &str, realm: &str, htdigest: &str) -> DigestOutcome {
+    let param = |name: &str| params.get(name).map(String::as_str);
+    let (Some(user), Some(nonce), Some(uri), Some(response), Some(nc), Some(cnonce)) =
+        (param("username"), param("nonce"), param("uri"), param("response"), param("nc"), param("cnonce")) else {
+        return DigestOutcome::Invalid;
+    };
+    if param("realm") != Some(realm) || param("qop") != Some("auth") || uri != target {
+        return DigestOutcome::Invalid;
+    }
+    let Ok(nonce_count) = u32::from_str_radix(nc, 16) else {
+        return DigestOutcome::Invalid;
+    };
+
+    let Some(ha1) = htdigest.lines()
+        .filter_map(|line| line.splitn(3, ':').collect_tuple())
+        .find(|(name, line_realm, _)| *name == user && *line_realm == realm)
+        .map(|(_, _, ha1)| ha1.trim().to_ascii_lowercase()) else {
+        return DigestOutcome::Invalid;
+    };
+    let algorithm = param("algorithm").unwrap_or("MD5");
+    let hash: fn(&str) -> String = match ha1.len() {
+        32 if algorithm.eq_ignore_ascii_case("MD5") => |data| hex::<Md5>(data),
+        64 if algorithm.eq_ignore_ascii_case("SHA-256") => |data| hex::<Sha256>(data),
+        _ => return DigestOutcome::Invalid,
+    };
+
+    let ha2 = hash(&format!("{}:{}", method, uri));
+    let expected = hash(&format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2));
+    if !constant_time_eq(expected.as_bytes(), response.to_ascii_lowercase().as_bytes()) {
+        return DigestOutcome::Invalid;
+    }
+
+    let mut nonces = NONCES.lock().unwrap();
+    match nonces.get_mut(nonce) {
+        Some((issued, _)) if issued.elapsed() > NONCE_LIFETIME => DigestOutcome::Stale,
+        // A nonce count that doesn't go up means the request is being replayed
+        Some((_, last_nc)) if nonce_count <= *last_nc => DigestOutcome::Invalid,
+        Some((_, last_nc)) => {
+            *last_nc = nonce_count;
+            DigestOutcome::Valid(user.to_string())
+        }
+        None => DigestOutcome::Stale,
+    }
+}
+
+fn new_nonce() -> String {
+    let mut bytes = [0; NONCE_BYTES];
+    getrandom::getrandom(&mut bytes).expect("No system randomness available");
+    let nonce: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+    let mut nonces = NONCES.lock().unwrap();
+    nonces.retain(|_, (issued, _)| issued.elapsed() <= NONCE_LIFETIME);
+    nonces.insert(nonce.clone(), (Instant::now(), 0));
+    nonce
+}
+
+fn hex<D: Digest>(data: &str) -> String {
+    D::digest(data.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Splits the `name=value` and `name="quoted value"` pairs of an authorization header.
+fn parse_auth_params(params: &str) -> HashMap<String, String> {
+    let mut parsed = HashMap::new();
+    let mut chars = params.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace() || *c == ',').is_some() {}
+        let name: String = chars.by_ref().take_while(|c| *c != '=').collect();
+        if name.is_empty() {
+            break;
+        }
+
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => value.extend(chars.next()),
+                    c => value.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| *c != ',') {
+                value.push(c);
+            }
+        }
+        parsed.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+    }
+    parsed
+}
+
+/// Drops the characters that would end a quoted-string early.
+fn quotable(value: &str) -> String {
+    value.replace(['\\', '"'], "")
+}
+
+/// Decodes `Basic <base64 user:password>`.
+fn basic_credentials(value: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+
+    let decoded = String::from_utf8(BASE64_STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+/// Checks a password against an htpasswd hash, either bcrypt (`$2y$...`) or `{SHA}`.
+fn verify(password: &str, hash: &str) -> bool {
+    if let Some(expected) = hash.strip_prefix("{SHA}") {
+        let actual = BASE64_STANDARD.encode(Sha1::digest(password.as_bytes()));
+        return constant_time_eq(actual.as_bytes(), expected.as_bytes());
+    }
+    if hash.starts_with("$2") {
+        return bcrypt::verify(password, hash).unwrap_or(false);
+    }
+
+    warn!("Unsupported htpasswd hash format, only bcrypt and {{SHA}} are accepted");
+    false
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use crate::testing::{configure, context, temp_dir};
+    use super::*;
+
+    /// What `authenticate` makes of a GET for `path` carrying `authorization`, if any.
+    async fn attempt(path: &str, authorization: Option<String>) -> (RequestContext, Option<HttpResponse>) {
+        let authorization = authorization.map_or(String::new(), |value| format!("Authorization: {}\r\n", value));
+        let mut ctx = context(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n{}\r\n", path, authorization).as_bytes()).await;
+        let challenge = authenticate(&mut ctx).await.unwrap();
+        (ctx, challenge)
+    }
+
+    fn basic_header(user: &str, password: &str) -> Option<String> {
+        Some(format!("Basic {}", BASE64_STANDARD.encode(format!("{}:{}", user, password))))
+    }
+
+    fn challenge_of(response: &HttpResponse) -> &str {
+        assert_eq!(response.status(), HttpStatus::Unauthorized);
+        response.headers().get("WWW-Authenticate").unwrap()
+    }
+
+    #[tokio::test]
+    async fn basic_credentials_are_checked_against_htpasswd() {
+        let dir = temp_dir("basic-auth");
+        let htpasswd = dir.join("htpasswd");
+        let sha = BASE64_STANDARD.encode(Sha1::digest(b"swordfish"));
+        std::fs::write(&htpasswd, format!("alice:{}\nbob:{{SHA}}{}\n", bcrypt::hash("hunter2", 4).unwrap(), sha)).unwrap();
+        let rule = format!("/private={}", htpasswd.display());
+        let _config = configure(&["--basic-auth", &rule, "--auth-realm", "Staff"]).await;
+
+        let (ctx, challenge) = attempt("/private/report", basic_header("alice", "hunter2")).await;
+        assert!(challenge.is_none());
+        assert_eq!(ctx.user.as_deref(), Some("alice"));
+        let (ctx, challenge) = attempt("/private/report", basic_header("bob", "swordfish")).await;
+        assert!(challenge.is_none());
+        assert_eq!(ctx.user.as_deref(), Some("bob"));
+
+        for authorization in [
+            basic_header("alice", "wrong"),
+            basic_header("bob", "hunter2"),
+            basic_header("mallory", "hunter2"),
+            Some("Basic not-base64!".to_string()),
+            Some(format!("Basic {}", BASE64_STANDARD.encode("no-colon"))),
+            None,
+        ] {
+            let (ctx, response) = attempt("/private/report", authorization.clone()).await;
+            assert_eq!(ctx.user, None, "{:?}", authorization);
+            assert!(challenge_of(&response.unwrap()).starts_with("Basic realm=\"Staff\""), "{:?}", authorization);
+        }
+
+        let (ctx, challenge) = attempt("/public", None).await;
+        assert!(challenge.is_none());
+        assert_eq!(ctx.user, None);
+    }
+
+    #[tokio::test]
+    async fn bearer_tokens_must_be_valid_hs256() {
+        use jsonwebtoken::{encode, EncodingKey, Header};
+
+        let _config = configure(&["--jwt-auth", "/api", "--jwt-secret", "s3cret"]).await;
+        // The key is loaded once per process, and this is the only test that needs one
+        init().await.unwrap();
+        let now = jsonwebtoken::get_current_timestamp();
+        let token = |algorithm: Algorithm, secret: &str, exp: u64| {
+            let claims = serde_json::json!({ "sub": "alice", "role": "admin", "exp": exp });
+            Some(format!("Bearer {}", encode(&Header::new(algorithm), &claims, &EncodingKey::from_secret(secret.as_bytes())).unwrap()))
+        };
+
+        let (ctx, challenge) = attempt("/api/items", token(Algorithm::HS256, "s3cret", now + 3600)).await;
+        assert!(challenge.is_none());
+        let claims = ctx.claims.unwrap();
+        assert_eq!(claims["sub"], "alice");
+        assert_eq!(claims["role"], "admin");
+
+        let (_, response) = attempt("/api/items", token(Algorithm::HS256, "s3cret", now - 3600)).await;
+        let expired = challenge_of(response.as_ref().unwrap());
+        assert!(expired.contains("error=\"invalid_token\""), "{}", expired);
+        assert!(expired.contains("expired"), "{}", expired);
+
+        for authorization in [token(Algorithm::HS256, "other", now + 3600), token(Algorithm::HS512, "s3cret", now + 3600)] {
+            let (ctx, response) = attempt("/api/items", authorization).await;
+            assert_eq!(ctx.claims, None);
+            let rejected = challenge_of(response.as_ref().unwrap());
+            assert!(rejected.contains("error=\"invalid_token\""), "{}", rejected);
+        }
+
+        let (_, response) = attempt("/api/items", None).await;
+        assert_eq!(challenge_of(response.as_ref().unwrap()), "Bearer realm=\"Restricted\"");
+    }
+
+    /// The Digest `Authorization` a client knowing `password` would send for `GET uri`.
+    fn digest_header<D: Digest>(algorithm: &str, user: &str, password: &str, nonce: &str, nc: &str, uri: &str) -> String {
+        let ha1 = hex::<D>(&format!("{}:Staff:{}", user, password));
+        let ha2 = hex::<D>(&format!("GET:{}", uri));
+        let response = hex::<D>(&format!("{}:{}:{}:0a4f113b:auth:{}", ha1, nonce, nc, ha2));
+        format!(
+            "Digest username=\"{}\", realm=\"Staff\", nonce=\"{}\", uri=\"{}\", algorithm={}, qop=auth, nc={}, cnonce=\"0a4f113b\", response=\"{}\"",
+            user, nonce, uri, algorithm, nc, response,
+        )
+    }
+
+    #[tokio::test]
+    async fn digest_credentials_are_checked_once_per_nonce_count() {
+        let dir = temp_dir("digest-auth");
+        let htdigest = dir.join("htdigest");
+        std::fs::write(&htdigest, format!(
+            "alice:Staff:{}\nbob:Staff:{}\n",
+            hex::<Md5>("alice:Staff:hunter2"),
+            hex::<Sha256>("bob:Staff:swordfish"),
+        )).unwrap();
+        let rule = format!("/private={}", htdigest.display());
+        let _config = configure(&["--digest-auth", &rule, "--auth-realm", "Staff"]).await;
+
+        let nonce = new_nonce();
+        let (ctx, challenge) = attempt("/private/a", Some(digest_header::<Md5>("MD5", "alice", "hunter2", &nonce, "00000001", "/private/a"))).await;
+        assert!(challenge.is_none());
+        assert_eq!(ctx.user.as_deref(), Some("alice"));
+        let (ctx, challenge) = attempt("/private/a", Some(digest_header::<Sha256>("SHA-256", "bob", "swordfish", &nonce, "00000002", "/private/a"))).await;
+        assert!(challenge.is_none());
+        assert_eq!(ctx.user.as_deref(), Some("bob"));
+
+        // Replaying a nonce count, or going back to an earlier one, is refused
+        for nc in ["00000002", "00000001"] {
+            let (ctx, response) = attempt("/private/a", Some(digest_header::<Md5>("MD5", "alice", "hunter2", &nonce, nc, "/private/a"))).await;
+            assert_eq!(ctx.user, None);
+            assert!(!challenge_of(response.as_ref().unwrap()).contains("stale"));
+        }
+        for authorization in [
+            digest_header::<Md5>("MD5", "alice", "wrong", &nonce, "00000003", "/private/a"),
+            digest_header::<Md5>("MD5", "alice", "hunter2", &nonce, "00000003", "/private/b"),
+            digest_header::<Sha256>("SHA-256", "alice", "hunter2", &nonce, "00000003", "/private/a"),
+        ] {
+            let (ctx, response) = attempt("/private/a", Some(authorization)).await;
+            assert_eq!(ctx.user, None);
+            let challenge = challenge_of(response.as_ref().unwrap());
+            assert!(challenge.starts_with("Digest realm=\"Staff\", qop=\"auth\", algorithm=SHA-256"), "{}", challenge);
+            assert!(!challenge.contains("stale"), "{}", challenge);
+        }
+
+        // Right credentials on a nonce past its lifetime only earn a fresh one
+        let expired = new_nonce();
+        NONCES.lock().unwrap().get_mut(&expired).unwrap().0 = Instant::now() - NONCE_LIFETIME - Duration::from_secs(1);
+        let (ctx, response) = attempt("/private/a", Some(digest_header::<Md5>("MD5", "alice", "hunter2", &expired, "00000001", "/private/a"))).await;
+        assert_eq!(ctx.user, None);
+        let challenge = challenge_of(response.as_ref().unwrap());
+        assert!(challenge.contains(", stale=true"), "{}", challenge);
+    }
+}
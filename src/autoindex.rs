@@ -0,0 +1,117 @@
+use std::path::Path;
+use std::time::SystemTime;
+use crate::error::HttpResult;
+use crate::http::{escape_html, HttpResponse};
+
+struct Entry {
+    name: String,
+    is_dir: bool,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+/// An HTML index of `directory`, listed under the URL path `path`, which should end in `/` so
+/// the relative links resolve inside it. Directories come first, then everything by name, after
+/// a link to the parent unless the directory is the root being served.
+pub async fn listing(directory: &Path, path: &str, has_parent: bool) -> HttpResult<HttpResponse> {
+    let mut entries = Vec::new();
+    let mut dir = tokio::fs::read_dir(directory).await?;
+    while let Some(entry) = dir.next_entry().await? {
+        // Entries that vanish or can't be looked at while listing are left out
+        let Ok(metadata) = entry.metadata().await else {
+            continue;
+        };
+        entries.push(Entry {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            is_dir: metadata.is_dir(),
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        });
+    }
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+    let title = escape_html(path);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {}</title></head>\n<body>\n<h1>Index of {}</h1>\n<table>\n<tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n",
+        title, title);
+    if has_parent {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in &entries {
+        let suffix = if entry.is_dir { "/" } else { "" };
+        let size = if entry.is_dir { "-".to_string() } else { entry.len.to_string() };
+        let modified = entry.modified.map(httpdate::fmt_http_date).unwrap_or_default();
+        html.push_str(&format!(
+            "<tr><td><a href=\"./{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            escape_html(&encode_segment(&entry.name)), suffix, escape_html(&entry.name), suffix, size, modified));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+
+    Ok(HttpResponse::html(html))
+}
+
+/// Percent-encodes everything in a file name that would otherwise end or change a path segment,
+/// such as `/`, `?`, `#` and `%` itself.
+fn encode_segment(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for b in name.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use tokio::io::AsyncReadExt;
+    use crate::testing::temp_dir;
+    use super::*;
+
+    async fn listed(directory: &Path, path: &str, has_parent: bool) -> String {
+        let response = listing(directory, path, has_parent).await.unwrap();
+        let mut html = String::new();
+        response.content().unwrap().content().unwrap().read_to_string(&mut html).await.unwrap();
+        html
+    }
+
+    /// The link targets of the listing's rows, in order.
+    fn links(html: &str) -> Vec<&str> {
+        html.split("<a href=\"").skip(1).filter_map(|rest| rest.split_once('"')).map(|(href, _)| href).collect()
+    }
+
+    #[tokio::test]
+    async fn directories_come_first_then_names_in_order() {
+        let dir = temp_dir("autoindex-order");
+        for file in ["beta.txt", "Alpha.txt", "alpha.txt"] {
+            std::fs::write(dir.join(file), "x").unwrap();
+        }
+        for sub in ["zeta", "docs"] {
+            std::fs::create_dir(dir.join(sub)).unwrap();
+        }
+
+        let html = listed(&dir, "/files/", false).await;
+        assert_eq!(links(&html), ["./docs/", "./zeta/", "./Alpha.txt", "./alpha.txt", "./beta.txt"]);
+        assert!(html.contains("<td><a href=\"./beta.txt\">beta.txt</a></td><td>1</td>"), "{}", html);
+
+        let html = listed(&dir, "/files/sub/", true).await;
+        assert_eq!(links(&html).first(), Some(&"../"));
+    }
+
+    #[tokio::test]
+    async fn names_and_path_are_escaped() {
+        let dir = temp_dir("autoindex-escaping");
+        std::fs::write(dir.join("<img src=x onerror=alert(1)>.html"), "x").unwrap();
+        std::fs::write(dir.join("a&b \"quoted\" #1?.txt"), "x").unwrap();
+
+        let html = listed(&dir, "/files/<b>/", false).await;
+        assert!(!html.contains("<img"), "{}", html);
+        assert!(!html.contains("<b>"), "{}", html);
+        assert!(html.contains("<title>Index of /files/&lt;b&gt;/</title>"), "{}", html);
+        assert!(html.contains("<a href=\"./%3Cimg%20src=x%20onerror=alert(1)%3E.html\">&lt;img src=x onerror=alert(1)&gt;.html</a>"), "{}", html);
+        assert!(html.contains("<a href=\"./a&amp;b%20%22quoted%22%20%231%3F.txt\">a&amp;b &quot;quoted&quot; #1?.txt</a>"), "{}", html);
+    }
+}
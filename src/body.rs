@@ -0,0 +1,371 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use crate::headers::HeaderMap;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, Take};
+
+const MAX_CHUNK_LINE_LENGTH: usize = 4096;
+const MAX_TRAILERS: usize = 32;
+
+/// Fields that only make sense in the header section and are dropped from request trailers.
+const FORBIDDEN_TRAILERS: &[&str] = &["Content-Length", "Host", "Trailer", "Transfer-Encoding"];
+const RESPONSE_CHUNK_SIZE: usize = 8192;
+
+#[derive(Debug)]
+pub struct BodyTooLarge;
+
+impl Display for BodyTooLarge {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Request body exceeds the configured maximum size")
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+/// How the length of a request body is determined.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BodyFraming {
+    None,
+    Length(u64),
+    Chunked,
+    /// The body is whatever the protocol delivers until the request stream ends, as in HTTP/2
+    Stream,
+}
+
+/// Reads a `Content-Length` body, never past its end and failing with `UnexpectedEof` if the
+/// connection closes before all of it arrived.
+pub struct LengthReader<R> {
+    inner: Take<R>,
+}
+
+impl<R: AsyncRead + Unpin> LengthReader<R> {
+    pub fn new(inner: R, length: u64) -> Self {
+        Self { inner: inner.take(length) }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for LengthReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let remaining = self.inner.limit();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        if remaining > 0 && buf.remaining() > 0 && buf.filled().len() == before {
+            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Fails with `BodyTooLarge` as soon as more than `limit` bytes have been read through it,
+/// whatever the body's declared length or transfer encoding.
+pub struct LimitedReader<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R> LimitedReader<R> {
+    pub fn new(inner: R, limit: u64) -> Self {
+        Self { inner, remaining: limit }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for LimitedReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let read = (buf.filled().len() - before) as u64;
+        if read > self.remaining {
+            // A failed read mustn't hand back any data
+            buf.set_filled(before);
+            return Poll::Ready(Err(io::Error::other(BodyTooLarge)));
+        }
+
+        self.remaining -= read;
+        Poll::Ready(Ok(()))
+    }
+}
+
+enum ChunkedState {
+    Size,
+    Data(u64),
+    DataEnd,
+    Trailers,
+    Done,
+}
+
+/// Decodes a `Transfer-Encoding: chunked` body, stopping exactly after the terminating
+/// empty line so that any bytes following it stay buffered in the underlying reader.
+pub struct ChunkedReader<'a, R> {
+    inner: R,
+    state: ChunkedState,
+    line: Vec<u8>,
+    trailer_count: usize,
+    trailers: Option<&'a mut HeaderMap>,
+}
+
+impl<'a, R: AsyncBufRead + Unpin> ChunkedReader<'a, R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            state: ChunkedState::Size,
+            line: Vec::new(),
+            trailer_count: 0,
+            trailers: None,
+        }
+    }
+
+    /// Collects the trailer fields following the last chunk into `trailers`, which are
+    /// otherwise skipped.
+    pub fn with_trailers(mut self, trailers: &'a mut HeaderMap) -> Self {
+        self.trailers = Some(trailers);
+        self
+    }
+
+    fn add_trailer(&mut self, line: &[u8]) -> io::Result<()> {
+        self.trailer_count += 1;
+        if self.trailer_count > MAX_TRAILERS {
+            return Err(invalid_data("too many trailer fields"));
+        }
+
+        let line = std::str::from_utf8(line).map_err(|_| invalid_data("trailer field is not valid"))?;
+        let (name, value) = line.split_once(':').ok_or_else(|| invalid_data("trailer field is missing a colon"))?;
+        if name.is_empty() || name.ends_with([' ', '\t']) {
+            return Err(invalid_data("trailer field name is not valid"));
+        }
+
+        let Some(trailers) = self.trailers.as_mut() else {
+            return Ok(());
+        };
+        if FORBIDDEN_TRAILERS.iter().any(|forbidden| forbidden.eq_ignore_ascii_case(name)) {
+            return Ok(());
+        }
+
+        let value = value.trim_matches([' ', '\t']);
+        trailers.append(name, value.to_string());
+        Ok(())
+    }
+
+    fn poll_line(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            let buf = ready!(Pin::new(&mut self.inner).poll_fill_buf(cx))?;
+            if buf.is_empty() {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+
+            let (len, done) = match buf.iter().position(|b| *b == b'\n') {
+                Some(index) => (index + 1, true),
+                None => (buf.len(), false),
+            };
+            if self.line.len() + len > MAX_CHUNK_LINE_LENGTH {
+                return Poll::Ready(Err(invalid_data("chunk line too long")));
+            }
+
+            self.line.extend_from_slice(&buf[..len]);
+            Pin::new(&mut self.inner).consume(len);
+            if done {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+
+    fn take_line(&mut self) -> io::Result<Vec<u8>> {
+        let mut line = std::mem::take(&mut self.line);
+        if !line.ends_with(b"\r\n") {
+            return Err(invalid_data("chunk line is not terminated by CRLF"));
+        }
+
+        line.truncate(line.len() - 2);
+        Ok(line)
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for ChunkedReader<'_, R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, out: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            match this.state {
+                ChunkedState::Size => {
+                    ready!(this.poll_line(cx))?;
+                    let line = this.take_line()?;
+                    let size = parse_chunk_size(&line)?;
+                    this.state = if size == 0 { ChunkedState::Trailers } else { ChunkedState::Data(size) };
+                }
+
+                ChunkedState::Data(remaining) => {
+                    if out.remaining() == 0 {
+                        return Poll::Ready(Ok(()));
+                    }
+
+                    let buf = ready!(Pin::new(&mut this.inner).poll_fill_buf(cx))?;
+                    if buf.is_empty() {
+                        return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                    }
+
+                    let len = buf.len().min(out.remaining()).min(remaining.try_into().unwrap_or(usize::MAX));
+                    out.put_slice(&buf[..len]);
+                    Pin::new(&mut this.inner).consume(len);
+
+                    let remaining = remaining - len as u64;
+                    this.state = if remaining == 0 { ChunkedState::DataEnd } else { ChunkedState::Data(remaining) };
+                    return Poll::Ready(Ok(()));
+                }
+
+                ChunkedState::DataEnd => {
+                    ready!(this.poll_line(cx))?;
+                    if !this.take_line()?.is_empty() {
+                        return Poll::Ready(Err(invalid_data("chunk data is not terminated by CRLF")));
+                    }
+                    this.state = ChunkedState::Size;
+                }
+
+                ChunkedState::Trailers => {
+                    ready!(this.poll_line(cx))?;
+                    let line = this.take_line()?;
+                    if line.is_empty() {
+                        this.state = ChunkedState::Done;
+                    } else {
+                        this.add_trailer(&line)?;
+                    }
+                }
+
+                ChunkedState::Done => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+/// Copies `reader` to `writer` with `Transfer-Encoding: chunked` framing, ending with the empty
+/// last chunk and the fields from `trailers`, which is called once `reader` is exhausted.
+/// Returns the number of bytes written, framing included.
+pub async fn write_chunked<R, W, T>(reader: &mut R, writer: &mut W, trailers: T) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin,
+    T: FnOnce() -> Vec<(String, String)>,
+{
+    let mut written = 0;
+    let mut buf = vec![0; RESPONSE_CHUNK_SIZE];
+    loop {
+        let len = reader.read(&mut buf).await?;
+        if len == 0 {
+            break;
+        }
+
+        let size_line = format!("{:x}\r\n", len);
+        writer.write_all(size_line.as_bytes()).await?;
+        writer.write_all(&buf[..len]).await?;
+        writer.write_all(b"\r\n").await?;
+        // Streamed content such as server-sent events has to reach the client as it's produced
+        writer.flush().await?;
+        written += (size_line.len() + len + 2) as u64;
+    }
+
+    let mut last_chunk = "0\r\n".to_string();
+    for (name, value) in trailers() {
+        last_chunk.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    last_chunk.push_str("\r\n");
+
+    writer.write_all(last_chunk.as_bytes()).await?;
+    Ok(written + last_chunk.len() as u64)
+}
+
+fn parse_chunk_size(line: &[u8]) -> io::Result<u64> {
+    let size = match line.iter().position(|b| *b == b';') {
+        Some(index) => &line[..index],
+        None => line,
+    };
+    let size = std::str::from_utf8(size)
+        .map_err(|_| invalid_data("chunk size is not valid"))?
+        .trim_matches(|c| c == ' ' || c == '\t');
+
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(invalid_data("chunk size is not valid hex"));
+    }
+
+    u64::from_str_radix(size, 16).map_err(|_| invalid_data("chunk size is too large"))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use super::*;
+
+    async fn decode(mut body: &[u8]) -> (io::Result<Vec<u8>>, &[u8]) {
+        let mut decoded = Vec::new();
+        let result = ChunkedReader::new(&mut body).read_to_end(&mut decoded).await;
+        (result.map(|_| decoded), body)
+    }
+
+    #[tokio::test]
+    async fn chunked_body_is_decoded() {
+        let (decoded, rest) = decode(b"4\r\nWiki\r\n5;name=value\r\npedia\r\n0\r\n\r\n").await;
+        assert_eq!(decoded.unwrap(), b"Wikipedia");
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn chunked_body_leaves_the_next_request_unread() {
+        let (decoded, rest) = decode(b"3\r\nabc\r\n0\r\n\r\nGET / HTTP/1.1\r\n\r\n").await;
+        assert_eq!(decoded.unwrap(), b"abc");
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn malformed_chunks_are_invalid_data() {
+        for body in [&b"zz\r\nabc\r\n0\r\n\r\n"[..], b"\r\n", b"3\r\nabcd\r\n0\r\n\r\n", b"3\nabc\r\n0\r\n\r\n", b"-3\r\nabc\r\n0\r\n\r\n"] {
+            let (decoded, _) = decode(body).await;
+            assert_eq!(decoded.unwrap_err().kind(), io::ErrorKind::InvalidData, "{:?}", String::from_utf8_lossy(body));
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_chunked_body_is_unexpected_eof() {
+        let (decoded, _) = decode(b"a\r\nabc").await;
+        assert_eq!(decoded.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn length_body_stops_at_its_end() {
+        let mut input = &b"helloGET / HTTP/1.1\r\n\r\n"[..];
+        let mut body = Vec::new();
+        LengthReader::new(&mut input, 5).read_to_end(&mut body).await.unwrap();
+        assert_eq!(body, b"hello");
+        assert_eq!(input, b"GET / HTTP/1.1\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn length_body_cut_short_is_unexpected_eof() {
+        let error = LengthReader::new(&b"hel"[..], 5).read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn limit_counts_the_bytes_actually_read() {
+        let mut within = Vec::new();
+        LimitedReader::new(&b"0123456789"[..], 10).read_to_end(&mut within).await.unwrap();
+        assert_eq!(within, b"0123456789");
+
+        // However the body was framed, what matters is how much of it arrives
+        let mut body = &b"3\r\nabc\r\n8\r\ndefghijk\r\n0\r\n\r\n"[..];
+        let error = LimitedReader::new(ChunkedReader::new(&mut body), 10).read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert!(error.get_ref().is_some_and(|error| error.is::<BodyTooLarge>()), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn trailers_are_collected_except_forbidden_ones() {
+        let mut body = &b"3\r\nabc\r\n0\r\nChecksum: 1234\r\nContent-Length: 3\r\n\r\n"[..];
+        let mut trailers = HeaderMap::new();
+        let mut decoded = Vec::new();
+        ChunkedReader::new(&mut body).with_trailers(&mut trailers).read_to_end(&mut decoded).await.unwrap();
+
+        assert_eq!(trailers.get("checksum").map(String::as_str), Some("1234"));
+        assert!(!trailers.contains_key("Content-Length"));
+    }
+}
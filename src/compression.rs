@@ -0,0 +1,314 @@
+use async_compression::Level;
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder, ZlibEncoder, ZstdEncoder};
+use anyhow::Result;
+use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
+use crate::CONFIG;
+use crate::http::{HttpContent, HttpResponse, HttpStatus};
+use crate::sse::EVENT_STREAM_TYPE;
+
+const BROTLI_QUALITY: i32 = 5;
+
+/// Bodies up to this size are encoded up front so the response can still carry a
+/// `Content-Length`; larger ones are encoded while they're being sent.
+const MAX_BUFFERED_ENCODE: usize = 1024 * 1024;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Brotli,
+    Deflate,
+    Gzip,
+    Zstd,
+}
+
+impl ContentEncoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Deflate => "deflate",
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    fn encoder<'a>(&self, raw: Box<dyn AsyncRead + Send + Sync + Unpin + 'a>) -> Box<dyn AsyncRead + Send + Sync + Unpin + 'a> {
+        let raw = BufReader::new(raw);
+        match self {
+            Self::Brotli => Box::new(BrotliEncoder::with_quality(raw, Level::Precise(BROTLI_QUALITY))),
+            // HTTP's "deflate" is the zlib format, not a raw deflate stream
+            Self::Deflate => Box::new(ZlibEncoder::new(raw)),
+            Self::Gzip => Box::new(GzipEncoder::new(raw)),
+            Self::Zstd => Box::new(ZstdEncoder::new(raw)),
+        }
+    }
+}
+
+/// Picks the encoding with the highest quality the client gives it. Ties go to brotli for text
+/// where it compresses best and zstd otherwise, with gzip and then deflate as fallbacks for older
+/// clients. `None` means the body should be sent as is.
+pub fn negotiate(accept_encoding: Option<&String>, content_type: &str) -> Option<ContentEncoding> {
+    let accepted = parse_accept_encoding(accept_encoding?);
+    let quality = |name: &str| accepted.iter()
+        .find(|(coding, _)| coding == name)
+        .or_else(|| accepted.iter().find(|(coding, _)| coding == "*"))
+        .map(|(_, quality)| *quality);
+
+    let preferred = if is_compressible(content_type, &["text/*".to_string()]) {
+        [ContentEncoding::Brotli, ContentEncoding::Zstd, ContentEncoding::Gzip, ContentEncoding::Deflate]
+    } else {
+        [ContentEncoding::Zstd, ContentEncoding::Gzip, ContentEncoding::Deflate, ContentEncoding::Brotli]
+    };
+    let (encoding, best) = preferred.into_iter()
+        .filter_map(|encoding| quality(encoding.name()).filter(|q| *q > 0.0).map(|q| (encoding, q)))
+        .fold(None, |best, candidate| match best {
+            Some((_, q)) if q >= candidate.1 => best,
+            _ => Some(candidate),
+        })?;
+
+    // An explicitly listed identity only wins if the client actually prefers it
+    let identity = accepted.iter().find(|(coding, _)| coding == "identity").map(|(_, q)| *q);
+    if identity.is_some_and(|identity| identity > best) {
+        return None;
+    }
+
+    Some(encoding)
+}
+
+/// Splits an `Accept-Encoding` value into lowercased codings and their `q` values, skipping
+/// entries whose quality doesn't parse.
+fn parse_accept_encoding(value: &str) -> Vec<(String, f32)> {
+    value.split(',')
+        .filter_map(|entry| {
+            let mut params = entry.split(';');
+            let coding = match params.next()?.trim().to_ascii_lowercase() {
+                coding if coding.is_empty() => return None,
+                coding if coding == "x-gzip" => "gzip".to_string(),
+                coding => coding,
+            };
+
+            let mut quality = 1.0;
+            for param in params {
+                if let Some((name, value)) = param.split_once('=') {
+                    if name.trim().eq_ignore_ascii_case("q") {
+                        quality = value.trim().parse::<f32>().ok().filter(|q| (0.0..=1.0).contains(q))?;
+                    }
+                }
+            }
+
+            Some((coding, quality))
+        })
+        .collect()
+}
+
+fn is_compressible(content_type: &str, patterns: &[String]) -> bool {
+    let content_type = content_type.split(';').next().unwrap_or_default().trim();
+    patterns.iter().any(|pattern| match pattern.strip_suffix("/*") {
+        Some(prefix) => content_type
+            .split('/')
+            .next()
+            .is_some_and(|kind| kind.eq_ignore_ascii_case(prefix)),
+        None => pattern.eq_ignore_ascii_case(content_type),
+    })
+}
+
+/// Replaces the response content with an encoded copy when the content type is on the
+/// `--compress-types` list, the body reaches `--compress-min-size` and the client accepts it.
+/// Partial content is left alone since its `Content-Range` describes the unencoded bytes, and
+/// so are bodies with trailers, whose digests are of the bytes before encoding.
+pub async fn compress(response: HttpResponse, accept_encoding: Option<&String>) -> Result<HttpResponse> {
+    let Some(content) = response.content() else {
+        return Ok(response);
+    };
+    // Events have to go out one by one, which an encoder buffering its input would prevent
+    if matches!(response.status(), HttpStatus::PartialContent)
+        || response.headers().contains_key("Content-Encoding")
+        || content.content_type() == EVENT_STREAM_TYPE
+        || !content.trailer_names().is_empty() {
+        return Ok(response);
+    }
+
+    let content_length = content.content_length();
+    {
+        let config = CONFIG.read().await;
+        if content_length == Some(0)
+            || content_length.is_some_and(|length| length < config.compress_min_size)
+            || !is_compressible(content.content_type(), &config.compress_types) {
+            return Ok(response);
+        }
+    }
+
+    let Some(encoding) = negotiate(accept_encoding, content.content_type()) else {
+        return Ok(response.with_vary("Accept-Encoding"));
+    };
+
+    let mut response = response.with_vary("Accept-Encoding")
+        .with_header("Content-Encoding", encoding.name().to_string());
+    // The encoded bytes differ from the ones the strong validator stands for
+    if let Some(etag) = response.headers().get("ETag").filter(|etag| !etag.starts_with("W/")) {
+        let weak = format!("W/{}", etag);
+        response = response.with_header("ETag", weak);
+    }
+    match response.content() {
+        Some(content) if content_length.is_some_and(|length| length <= MAX_BUFFERED_ENCODE) => {
+            let content_type = content.content_type().to_string();
+            let mut encoded = Vec::new();
+            encoding.encoder(content.content()?).read_to_end(&mut encoded).await?;
+            Ok(response.with_content(EncodedContent::new(content_type, encoded)))
+        }
+        _ => Ok(response.map_content(|content| StreamEncodedContent::new(content, encoding))),
+    }
+}
+
+pub struct EncodedContent {
+    content_type: String,
+    encoded: Vec<u8>,
+}
+
+impl EncodedContent {
+    pub fn new(content_type: String, encoded: Vec<u8>) -> Box<Self> {
+        Box::new(Self { content_type, encoded })
+    }
+}
+
+impl HttpContent for EncodedContent {
+    fn content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    fn content_length(&self) -> Option<usize> {
+        Some(self.encoded.len())
+    }
+
+    fn content(&self) -> Result<Box<dyn AsyncRead + Send + Sync + Unpin + '_>, anyhow::Error> {
+        Ok(Box::new(std::io::Cursor::new(self.encoded.as_slice())))
+    }
+}
+
+/// Encodes the wrapped content while it's being sent, so its encoded length isn't known.
+pub struct StreamEncodedContent {
+    inner: Box<dyn HttpContent + Send + Sync>,
+    encoding: ContentEncoding,
+}
+
+impl StreamEncodedContent {
+    pub fn new(inner: Box<dyn HttpContent + Send + Sync>, encoding: ContentEncoding) -> Box<Self> {
+        Box::new(Self { inner, encoding })
+    }
+}
+
+impl HttpContent for StreamEncodedContent {
+    fn content_type(&self) -> &str {
+        self.inner.content_type()
+    }
+
+    fn content_length(&self) -> Option<usize> {
+        None
+    }
+
+    fn content(&self) -> Result<Box<dyn AsyncRead + Send + Sync + Unpin + '_>, anyhow::Error> {
+        Ok(self.encoding.encoder(self.inner.content()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use crate::http::{DigestContent, FileContent};
+    use crate::testing::{configure, temp_dir};
+    use super::*;
+
+    #[test]
+    fn compressible_types_match_exactly_or_by_family() {
+        let patterns = ["text/*".to_string(), "application/json".to_string()];
+        assert!(is_compressible("text/html; charset=utf-8", &patterns));
+        assert!(is_compressible("Application/JSON", &patterns));
+        assert!(!is_compressible("image/png", &patterns));
+        assert!(!is_compressible("application/json-seq", &patterns));
+        assert!(!is_compressible("textual/plain", &patterns));
+    }
+
+    fn negotiated(accept_encoding: &str, content_type: &str) -> Option<ContentEncoding> {
+        negotiate(Some(&accept_encoding.to_string()), content_type)
+    }
+
+    #[test]
+    fn zero_quality_rules_a_coding_out() {
+        assert_eq!(negotiated("gzip;q=0", "text/html"), None);
+        assert_eq!(negotiated("gzip;q=0, deflate;q=0.1", "text/html"), Some(ContentEncoding::Deflate));
+        assert_eq!(negotiated("*, br;q=0", "text/html"), Some(ContentEncoding::Zstd));
+    }
+
+    #[test]
+    fn wildcard_stands_for_unlisted_codings() {
+        assert_eq!(negotiated("*", "text/html"), Some(ContentEncoding::Brotli));
+        assert_eq!(negotiated("*;q=0.5, gzip", "text/html"), Some(ContentEncoding::Gzip));
+        assert_eq!(negotiated("*;q=0", "text/html"), None);
+    }
+
+    #[test]
+    fn preferred_identity_wins() {
+        assert_eq!(negotiated("identity;q=1, gzip;q=0.5", "text/html"), None);
+        assert_eq!(negotiated("identity;q=0.5, gzip", "text/html"), Some(ContentEncoding::Gzip));
+    }
+
+    #[test]
+    fn x_gzip_is_gzip() {
+        assert_eq!(negotiated("x-gzip", "application/json"), Some(ContentEncoding::Gzip));
+    }
+
+    #[test]
+    fn invalid_quality_drops_its_entry() {
+        assert_eq!(negotiated("br;q=2, gzip", "text/html"), Some(ContentEncoding::Gzip));
+        assert_eq!(negotiated("br;q=high", "text/html"), None);
+        assert_eq!(negotiated("br;q=-1, deflate;q=0.2", "text/html"), Some(ContentEncoding::Deflate));
+    }
+
+    #[test]
+    fn ties_go_to_brotli_for_text_and_zstd_otherwise() {
+        assert_eq!(negotiated("gzip, deflate, zstd, br", "text/css"), Some(ContentEncoding::Brotli));
+        assert_eq!(negotiated("gzip, deflate, zstd, br", "application/json"), Some(ContentEncoding::Zstd));
+        assert_eq!(negotiated("gzip, deflate", "application/json"), Some(ContentEncoding::Gzip));
+        assert_eq!(negotiated("gzip;q=0.8, br;q=0.9", "application/json"), Some(ContentEncoding::Brotli));
+    }
+
+    #[tokio::test]
+    async fn html_is_compressed() {
+        let _config = configure(&[]).await;
+        let response = HttpResponse::html("<p>hello</p>".repeat(1000));
+
+        let response = compress(response, Some(&"gzip".to_string())).await.unwrap();
+        assert_eq!(response.headers().get("Content-Encoding").map(String::as_str), Some("gzip"));
+        assert!(response.content().unwrap().content_length().unwrap() < 12000);
+    }
+
+    #[tokio::test]
+    async fn large_png_is_left_alone() {
+        let _config = configure(&[]).await;
+        let path = temp_dir("compress-png").join("image.png");
+        std::fs::write(&path, vec![0; 100_000]).unwrap();
+        let content = FileContent::open(path).unwrap().with_content_type("image/png".to_string());
+        let response = HttpResponse::new(HttpStatus::Ok).with_content(content);
+
+        let response = compress(response, Some(&"gzip, br".to_string())).await.unwrap();
+        assert_eq!(response.headers().get("Content-Encoding"), None);
+        assert_eq!(response.content().unwrap().content_length(), Some(100_000));
+    }
+
+    #[tokio::test]
+    async fn compress_types_can_be_narrowed() {
+        let _config = configure(&["--compress-types", "application/json"]).await;
+        let response = HttpResponse::html("<p>hello</p>".repeat(1000));
+
+        let response = compress(response, Some(&"gzip".to_string())).await.unwrap();
+        assert_eq!(response.headers().get("Content-Encoding"), None);
+    }
+
+    #[tokio::test]
+    async fn digested_content_is_left_alone() {
+        let _config = configure(&[]).await;
+        let response = HttpResponse::html("<p>hello</p>".repeat(1000)).map_content(|content| DigestContent::new(content));
+
+        let response = compress(response, Some(&"gzip".to_string())).await.unwrap();
+        assert_eq!(response.headers().get("Content-Encoding"), None);
+        assert_eq!(response.content().unwrap().trailer_names(), vec!["Repr-Digest".to_string()]);
+    }
+}
@@ -0,0 +1,223 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::error::{HttpError, HttpResult};
+use crate::headers::HeaderMap;
+use crate::http::{FileContent, FileError, HttpResponse, HttpStatus};
+use crate::HttpMethod;
+
+/// Tags successful responses with their ETag and turns GETs and HEADs for a representation
+/// the client already has into a bodiless 304 Not Modified. `If-None-Match` takes precedence,
+/// `If-Modified-Since` is only looked at without it.
+pub fn not_modified(method: &HttpMethod, headers: &HeaderMap, response: HttpResponse) -> HttpResponse {
+    if !matches!(response.status(), HttpStatus::Ok | HttpStatus::PartialContent) {
+        return response;
+    }
+
+    let etag = response.headers().get("ETag").cloned()
+        .or_else(|| response.content().and_then(|content| content.etag()));
+    let response = match &etag {
+        Some(etag) => response.with_header("ETag", etag.clone()),
+        None => response,
+    };
+    if !matches!(method, HttpMethod::Get | HttpMethod::Head) {
+        return response;
+    }
+
+    let unchanged = match (headers.get("If-None-Match"), headers.get("If-Modified-Since")) {
+        (Some(if_none_match), _) => etag_list_matches(if_none_match, etag.as_deref()),
+        (None, Some(if_modified_since)) => {
+            let last_modified = response.headers().get("Last-Modified");
+            match (httpdate::parse_http_date(if_modified_since), last_modified.map(|date| httpdate::parse_http_date(date))) {
+                (Ok(since), Some(Ok(last_modified))) => last_modified <= since,
+                _ => false,
+            }
+        }
+        (None, None) => false,
+    };
+    if !unchanged {
+        return response;
+    }
+
+    let mut not_modified = HttpResponse::new(HttpStatus::NotModified);
+    for name in ["ETag", "Last-Modified"] {
+        if let Some(value) = response.headers().get(name) {
+            not_modified = not_modified.with_header(name, value.clone());
+        }
+    }
+    not_modified
+}
+
+/// Weak comparison of `etag` against a comma-separated list of entity tags, or whether the
+/// representation exists at all for `*`.
+fn etag_list_matches(list: &str, etag: Option<&str>) -> bool {
+    if list.trim() == "*" {
+        return true;
+    }
+
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    etag.is_some_and(|etag| list.split(',').any(|tag| opaque(tag) == opaque(etag)))
+}
+
+/// Whether a `Range` request may be answered partially given its `If-Range` validator, which
+/// must still identify the current representation. Weak ETags never match.
+pub fn if_range_matches(if_range: &str, etag: &str, modified: Option<SystemTime>) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        return if_range == etag;
+    }
+
+    match (httpdate::parse_http_date(if_range), modified) {
+        (Ok(date), Some(modified)) => date == truncate_to_seconds(modified),
+        _ => false,
+    }
+}
+
+/// HTTP-dates only have whole seconds, so file times are truncated before comparing with them.
+fn truncate_to_seconds(time: SystemTime) -> SystemTime {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// Checks the `If-Match` and `If-Unmodified-Since` preconditions of a request that changes the
+/// file at `path`, failing with 412 when the client's copy is no longer the current one.
+/// `If-Unmodified-Since` is only looked at without `If-Match`.
+pub fn check_write_preconditions(headers: &HeaderMap, path: &Path) -> HttpResult<()> {
+    let (if_match, if_unmodified_since) = (headers.get("If-Match"), headers.get("If-Unmodified-Since"));
+    if if_match.is_none() && if_unmodified_since.is_none() {
+        return Ok(());
+    }
+
+    let current = match FileContent::open(path.to_path_buf()) {
+        Ok(content) => Some(content),
+        Err(FileError::NotFound) => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    let holds = match (if_match, if_unmodified_since) {
+        (Some(if_match), _) => match &current {
+            _ if if_match.trim() == "*" => current.is_some(),
+            // Only strong comparison is allowed here, weak tags never match
+            Some(content) => if_match.split(',').any(|tag| tag.trim() == content.etag()),
+            None => false,
+        },
+        (None, Some(if_unmodified_since)) => match httpdate::parse_http_date(if_unmodified_since) {
+            Ok(since) => match current {
+                Some(content) => content.modified().is_some_and(|modified| truncate_to_seconds(modified) <= since),
+                // Nothing there can have been modified since
+                None => true,
+            },
+            // An invalid date is ignored
+            Err(_) => true,
+        },
+        (None, None) => true,
+    };
+
+    if !holds {
+        return Err(HttpError::PreconditionFailed);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use crate::http::PlainTextContent;
+    use crate::testing::temp_dir;
+    use super::*;
+
+    fn check(path: &Path, name: &str, value: &str) -> HttpResult<()> {
+        check_write_preconditions(&[(name.to_string(), value.to_string())].into_iter().collect(), path)
+    }
+
+    #[test]
+    fn if_unmodified_since_compares_the_file_time() {
+        let path = temp_dir("unmodified-since").join("note.txt");
+        std::fs::write(&path, "hello").unwrap();
+
+        assert!(check(&path, "If-Unmodified-Since", "Fri, 31 Dec 9999 23:59:59 GMT").is_ok());
+        assert!(matches!(check(&path, "If-Unmodified-Since", "Thu, 01 Jan 1970 00:00:00 GMT"), Err(HttpError::PreconditionFailed)));
+        assert!(check(&path, "If-Unmodified-Since", "yesterday").is_ok());
+    }
+
+    #[test]
+    fn if_unmodified_since_holds_for_a_missing_file() {
+        let path = temp_dir("unmodified-since-missing").join("note.txt");
+        assert!(check(&path, "If-Unmodified-Since", "Thu, 01 Jan 1970 00:00:00 GMT").is_ok());
+    }
+
+    #[test]
+    fn if_match_needs_the_current_strong_etag() {
+        let path = temp_dir("if-match").join("note.txt");
+        assert!(matches!(check(&path, "If-Match", "*"), Err(HttpError::PreconditionFailed)));
+
+        std::fs::write(&path, "hello").unwrap();
+        let etag = FileContent::open(path.clone()).unwrap().etag();
+        assert!(check(&path, "If-Match", "*").is_ok());
+        assert!(check(&path, "If-Match", &format!("\"other\", {}", etag)).is_ok());
+        assert!(matches!(check(&path, "If-Match", &format!("W/{}", etag)), Err(HttpError::PreconditionFailed)));
+    }
+
+    #[test]
+    fn if_range_dates_ignore_the_file_times_fraction_of_a_second() {
+        let modified = UNIX_EPOCH + Duration::from_millis(784_111_777_250);
+        assert!(if_range_matches("Sun, 06 Nov 1994 08:49:37 GMT", "\"abc\"", Some(modified)));
+        assert!(!if_range_matches("Sun, 06 Nov 1994 08:49:36 GMT", "\"abc\"", Some(modified)));
+        assert!(!if_range_matches("Sun, 06 Nov 1994 08:49:37 GMT", "\"abc\"", None));
+        assert!(!if_range_matches("not a date", "\"abc\"", Some(modified)));
+    }
+
+    /// A page tagged `"v2"` and last modified at `Sun, 06 Nov 1994 08:49:37 GMT`, answered
+    /// to `method` with the conditional `headers`.
+    fn answer(method: HttpMethod, headers: &[(&str, &str)]) -> HttpResponse {
+        let headers: HeaderMap = headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        let page = HttpResponse::new(HttpStatus::Ok)
+            .with_header("ETag", "\"v2\"".to_string())
+            .with_header("Last-Modified", "Sun, 06 Nov 1994 08:49:37 GMT".to_string())
+            .with_content(PlainTextContent::new("page".to_string()));
+        not_modified(&method, &headers, page)
+    }
+
+    #[test]
+    fn if_none_match_lists_and_wildcards_make_304s() {
+        for if_none_match in ["\"v2\"", "\"v1\", \"v2\"", "W/\"v2\"", "*"] {
+            let response = answer(HttpMethod::Get, &[("If-None-Match", if_none_match)]);
+            assert_eq!(response.status(), HttpStatus::NotModified, "{}", if_none_match);
+            assert_eq!(response.headers().get("ETag").map(String::as_str), Some("\"v2\""));
+            assert!(response.content().is_none());
+        }
+
+        let response = answer(HttpMethod::Head, &[("If-None-Match", "\"v2\"")]);
+        assert_eq!(response.status(), HttpStatus::NotModified);
+        for if_none_match in ["\"v1\"", "\"v2-gzip\""] {
+            assert_eq!(answer(HttpMethod::Get, &[("If-None-Match", if_none_match)]).status(), HttpStatus::Ok);
+        }
+    }
+
+    #[test]
+    fn only_gets_and_heads_are_answered_with_304() {
+        for method in [HttpMethod::Post, HttpMethod::Put, HttpMethod::Delete] {
+            let response = answer(method, &[("If-None-Match", "\"v2\"")]);
+            assert_eq!(response.status(), HttpStatus::Ok);
+            assert!(response.content().is_some());
+        }
+    }
+
+    #[test]
+    fn if_modified_since_makes_a_304_unless_if_none_match_is_sent() {
+        for since in ["Sun, 06 Nov 1994 08:49:37 GMT", "Mon, 07 Nov 1994 00:00:00 GMT"] {
+            let response = answer(HttpMethod::Get, &[("If-Modified-Since", since)]);
+            assert_eq!(response.status(), HttpStatus::NotModified, "{}", since);
+            assert_eq!(response.headers().get("Last-Modified").map(String::as_str), Some("Sun, 06 Nov 1994 08:49:37 GMT"));
+        }
+        for since in ["Sun, 06 Nov 1994 08:49:36 GMT", "last week"] {
+            assert_eq!(answer(HttpMethod::Get, &[("If-Modified-Since", since)]).status(), HttpStatus::Ok, "{}", since);
+        }
+
+        // A changed ETag wins over a date that would have said nothing changed
+        let response = answer(HttpMethod::Get, &[("If-None-Match", "\"v1\""), ("If-Modified-Since", "Mon, 07 Nov 1994 00:00:00 GMT")]);
+        assert_eq!(response.status(), HttpStatus::Ok);
+        let response = answer(HttpMethod::Get, &[("If-None-Match", "\"v2\""), ("If-Modified-Since", "Thu, 01 Jan 1970 00:00:00 GMT")]);
+        assert_eq!(response.status(), HttpStatus::NotModified);
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+/// Parses a `Cookie` header into its name/value pairs, unwrapping quoted values. When a name
+/// repeats, the first value wins, since browsers send cookies with longer paths first.
+pub fn parse_cookies(header: &str) -> HashMap<String, String> {
+    let mut cookies = HashMap::new();
+    for pair in header.split(';') {
+        let Some((name, value)) = pair.split_once('=') else {
+            continue;
+        };
+        let name = name.trim();
+        if name.is_empty() {
+            continue;
+        }
+
+        let value = value.trim();
+        let value = value.strip_prefix('"').and_then(|value| value.strip_suffix('"')).unwrap_or(value);
+        cookies.entry(name.to_string()).or_insert_with(|| value.to_string());
+    }
+    cookies
+}
+
+#[allow(unused)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    /// Only honoured by browsers together with `Secure`
+    None,
+}
+
+impl Display for SameSite {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Strict => write!(f, "Strict"),
+            Self::Lax => write!(f, "Lax"),
+            Self::None => write!(f, "None"),
+        }
+    }
+}
+
+/// A cookie to set on the client, serialized as the value of one `Set-Cookie` header.
+#[derive(Clone, Debug)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    max_age: Option<Duration>,
+    http_only: bool,
+    secure: bool,
+    same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            max_age: None,
+            http_only: false,
+            secure: false,
+            same_site: None,
+        }
+    }
+
+    /// A cookie that makes the client drop its cookie called `name` right away.
+    pub fn removal(name: &str) -> Self {
+        Self::new(name, "").with_max_age(Duration::ZERO)
+    }
+
+    pub fn with_path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn with_http_only(mut self) -> Self {
+        self.http_only = true;
+        self
+    }
+
+    pub fn with_secure(mut self) -> Self {
+        self.secure = true;
+        self
+    }
+
+    pub fn with_same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+}
+
+impl Display for Cookie {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        if self.secure {
+            write!(f, "; Secure")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={}", same_site)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use super::*;
+
+    #[test]
+    fn cookie_header_is_split_into_pairs() {
+        let cookies = parse_cookies("session=abc123; theme=\"dark mode\";lang=en ; empty=");
+        assert_eq!(cookies.len(), 4);
+        assert_eq!(cookies["session"], "abc123");
+        assert_eq!(cookies["theme"], "dark mode");
+        assert_eq!(cookies["lang"], "en");
+        assert_eq!(cookies["empty"], "");
+    }
+
+    #[test]
+    fn malformed_pairs_are_skipped_and_first_value_wins() {
+        let cookies = parse_cookies("flag; =orphan; id=first; ; id=second; token=a=b");
+        assert_eq!(cookies.len(), 2);
+        assert_eq!(cookies["id"], "first");
+        assert_eq!(cookies["token"], "a=b");
+        assert_eq!(parse_cookies("half=\"open").get("half").map(String::as_str), Some("\"open"));
+    }
+
+    #[test]
+    fn set_cookie_lists_the_attributes_given() {
+        assert_eq!(Cookie::new("id", "abc").to_string(), "id=abc");
+        let cookie = Cookie::new("id", "abc")
+            .with_path("/")
+            .with_max_age(Duration::from_secs(3600))
+            .with_http_only()
+            .with_secure()
+            .with_same_site(SameSite::Lax);
+        assert_eq!(cookie.to_string(), "id=abc; Path=/; Max-Age=3600; HttpOnly; Secure; SameSite=Lax");
+        assert_eq!(Cookie::new("id", "abc").with_same_site(SameSite::Strict).to_string(), "id=abc; SameSite=Strict");
+        assert_eq!(Cookie::new("id", "abc").with_secure().with_same_site(SameSite::None).to_string(), "id=abc; Secure; SameSite=None");
+    }
+
+    #[test]
+    fn removal_expires_the_cookie_at_once() {
+        assert_eq!(Cookie::removal("id").with_path("/").to_string(), "id=; Path=/; Max-Age=0");
+    }
+}
@@ -0,0 +1,101 @@
+use anyhow::{bail, Result};
+use crate::headers::HeaderMap;
+use crate::http::HttpResponse;
+use crate::{HttpMethod, CONFIG};
+
+/// Refuses a `--cors-origin '*'` with `--cors-allow-credentials`, which would hand credentials
+/// to whatever site the browser happens to be on.
+pub async fn check_config() -> Result<()> {
+    let config = CONFIG.read().await;
+    if config.cors_allow_credentials && config.cors_origins.iter().any(|origin| origin == "*") {
+        bail!("--cors-allow-credentials can't be combined with --cors-origin '*'");
+    }
+
+    Ok(())
+}
+
+/// Whether the request is a CORS preflight, which browsers send without credentials and so
+/// has to get past authentication.
+pub fn is_preflight(method: &HttpMethod, headers: &HeaderMap) -> bool {
+    *method == HttpMethod::Options
+        && headers.contains_key("Origin")
+        && headers.contains_key("Access-Control-Request-Method")
+}
+
+/// Answers a preflight from an allowed `--cors-origin` with the methods `allow` lists and the
+/// request headers the browser asked about.
+pub async fn preflight(headers: &HeaderMap, response: HttpResponse, allow: String) -> HttpResponse {
+    let config = CONFIG.read().await;
+    if !headers.get("Origin").is_some_and(|origin| is_allowed(&config.cors_origins, origin)) {
+        return response;
+    }
+
+    let response = response
+        .with_header("Access-Control-Allow-Methods", allow)
+        .with_header("Access-Control-Max-Age", config.cors_max_age.to_string());
+    match headers.get("Access-Control-Request-Headers") {
+        Some(requested) => response
+            .with_header("Access-Control-Allow-Headers", requested.clone())
+            .with_vary("Access-Control-Request-Headers"),
+        None => response,
+    }
+}
+
+/// Lets the requesting origin read the response when it's on the `--cors-origin` allow-list.
+pub async fn apply(headers: &HeaderMap, response: HttpResponse) -> HttpResponse {
+    let config = CONFIG.read().await;
+    if config.cors_origins.is_empty() {
+        return response;
+    }
+
+    // The answer depends on the Origin unless every origin gets the same one
+    // (`*` never comes with credentials, `check_config` refuses to start with both)
+    let any_origin = config.cors_origins.iter().any(|allowed| allowed == "*");
+    let response = if any_origin { response } else { response.with_vary("Origin") };
+    let Some(origin) = headers.get("Origin").filter(|origin| is_allowed(&config.cors_origins, origin)) else {
+        return response;
+    };
+
+    let response = response.with_header("Access-Control-Allow-Origin", if any_origin { "*".to_string() } else { origin.clone() });
+    if config.cors_allow_credentials {
+        response.with_header("Access-Control-Allow-Credentials", "true".to_string())
+    } else {
+        response
+    }
+}
+
+fn is_allowed(allowed: &[String], origin: &str) -> bool {
+    allowed.iter().any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use crate::http::HttpStatus;
+    use crate::testing::configure;
+    use super::*;
+
+    async fn allowed_origin(origin: &str) -> Option<String> {
+        let headers: HeaderMap = [("Origin".to_string(), origin.to_string())].into_iter().collect();
+        let response = apply(&headers, HttpResponse::new(HttpStatus::Ok)).await;
+        response.headers().get("Access-Control-Allow-Origin").cloned()
+    }
+
+    #[tokio::test]
+    async fn wildcard_origin_is_refused_with_credentials() {
+        let _config = configure(&["--cors-origin", "*", "--cors-allow-credentials"]).await;
+        assert!(check_config().await.is_err());
+        drop(_config);
+
+        let _config = configure(&["--cors-origin", "https://app.example", "--cors-allow-credentials"]).await;
+        assert!(check_config().await.is_ok());
+        assert_eq!(allowed_origin("https://app.example").await.as_deref(), Some("https://app.example"));
+        assert_eq!(allowed_origin("https://evil.example").await, None);
+    }
+
+    #[tokio::test]
+    async fn wildcard_origin_is_answered_with_a_wildcard() {
+        let _config = configure(&["--cors-origin", "*"]).await;
+        assert_eq!(allowed_origin("https://anywhere.example").await.as_deref(), Some("*"));
+    }
+}
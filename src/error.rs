@@ -0,0 +1,154 @@
+use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
+use crate::body::BodyTooLarge;
+use crate::http::{FileError, HttpResponse, HttpStatus};
+use crate::parser::ParseError;
+
+pub type HttpResult<T> = Result<T, HttpError>;
+
+#[derive(Debug)]
+pub enum HttpError {
+    BadRequest(String),
+    Forbidden,
+    NotFound,
+    NotAcceptable,
+    PreconditionFailed,
+    PayloadTooLarge,
+    UriTooLong,
+    UnsupportedMediaType,
+    RequestHeaderFieldsTooLarge,
+    HttpVersionNotSupported,
+    Internal(anyhow::Error),
+}
+
+impl HttpError {
+    pub fn status(&self) -> HttpStatus {
+        match self {
+            Self::BadRequest(_) => HttpStatus::BadRequest,
+            Self::Forbidden => HttpStatus::Forbidden,
+            Self::NotFound => HttpStatus::NotFound,
+            Self::NotAcceptable => HttpStatus::NotAcceptable,
+            Self::PreconditionFailed => HttpStatus::PreconditionFailed,
+            Self::PayloadTooLarge => HttpStatus::PayloadTooLarge,
+            Self::UriTooLong => HttpStatus::UriTooLong,
+            Self::UnsupportedMediaType => HttpStatus::UnsupportedMediaType,
+            Self::RequestHeaderFieldsTooLarge => HttpStatus::RequestHeaderFieldsTooLarge,
+            Self::HttpVersionNotSupported => HttpStatus::HttpVersionNotSupported,
+            Self::Internal(_) => HttpStatus::InternalServerError,
+        }
+    }
+}
+
+impl Display for HttpError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BadRequest(message) => write!(f, "Bad request: {}", message),
+            Self::Forbidden => write!(f, "Forbidden"),
+            Self::NotFound => write!(f, "Not found"),
+            Self::NotAcceptable => write!(f, "Not acceptable"),
+            Self::PreconditionFailed => write!(f, "Precondition failed"),
+            Self::PayloadTooLarge => write!(f, "Payload too large"),
+            Self::UriTooLong => write!(f, "URI too long"),
+            Self::UnsupportedMediaType => write!(f, "Unsupported media type"),
+            Self::RequestHeaderFieldsTooLarge => write!(f, "Request header fields too large"),
+            Self::HttpVersionNotSupported => write!(f, "HTTP version not supported"),
+            Self::Internal(e) => write!(f, "Internal error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+impl From<anyhow::Error> for HttpError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::Internal(e)
+    }
+}
+
+impl From<std::io::Error> for HttpError {
+    fn from(e: std::io::Error) -> Self {
+        if e.get_ref().is_some_and(|inner| inner.is::<BodyTooLarge>()) {
+            return Self::PayloadTooLarge;
+        }
+
+        match e.kind() {
+            // Raised while decoding a malformed or truncated request body
+            ErrorKind::InvalidData | ErrorKind::UnexpectedEof => Self::BadRequest(e.to_string()),
+            _ => Self::Internal(e.into()),
+        }
+    }
+}
+
+impl From<ParseError> for HttpError {
+    fn from(e: ParseError) -> Self {
+        Self::BadRequest(e.to_string())
+    }
+}
+
+impl From<FileError> for HttpError {
+    fn from(e: FileError) -> Self {
+        match e {
+            FileError::NotFound => Self::NotFound,
+            FileError::PermissionDenied => Self::Forbidden,
+            FileError::Other(e) => Self::Internal(e.into()),
+        }
+    }
+}
+
+impl From<HttpError> for HttpResponse {
+    fn from(e: HttpError) -> Self {
+        HttpResponse::new(e.status())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use pretty_assertions::assert_eq;
+    use super::*;
+
+    #[test]
+    fn each_error_has_its_status() {
+        let cases = [
+            (HttpError::BadRequest("bad".to_string()), HttpStatus::BadRequest),
+            (HttpError::Forbidden, HttpStatus::Forbidden),
+            (HttpError::NotFound, HttpStatus::NotFound),
+            (HttpError::NotAcceptable, HttpStatus::NotAcceptable),
+            (HttpError::PreconditionFailed, HttpStatus::PreconditionFailed),
+            (HttpError::PayloadTooLarge, HttpStatus::PayloadTooLarge),
+            (HttpError::UriTooLong, HttpStatus::UriTooLong),
+            (HttpError::UnsupportedMediaType, HttpStatus::UnsupportedMediaType),
+            (HttpError::RequestHeaderFieldsTooLarge, HttpStatus::RequestHeaderFieldsTooLarge),
+            (HttpError::HttpVersionNotSupported, HttpStatus::HttpVersionNotSupported),
+            (HttpError::Internal(anyhow!("broken")), HttpStatus::InternalServerError),
+        ];
+        for (error, status) in cases {
+            assert_eq!(HttpResponse::from(error).status(), status);
+        }
+    }
+
+    #[test]
+    fn io_errors_map_by_kind() {
+        let status = |e: std::io::Error| HttpError::from(e).status();
+        assert_eq!(status(std::io::Error::other(BodyTooLarge)), HttpStatus::PayloadTooLarge);
+        assert_eq!(status(ErrorKind::InvalidData.into()), HttpStatus::BadRequest);
+        assert_eq!(status(ErrorKind::UnexpectedEof.into()), HttpStatus::BadRequest);
+        assert_eq!(status(ErrorKind::BrokenPipe.into()), HttpStatus::InternalServerError);
+    }
+
+    #[test]
+    fn file_errors_map_to_404_403_and_500() {
+        assert_eq!(HttpError::from(FileError::NotFound).status(), HttpStatus::NotFound);
+        assert_eq!(HttpError::from(FileError::PermissionDenied).status(), HttpStatus::Forbidden);
+        assert_eq!(HttpError::from(FileError::Other(ErrorKind::Other.into())).status(), HttpStatus::InternalServerError);
+    }
+
+    #[test]
+    fn file_open_errors_map_by_kind() {
+        // What opening an unreadable file fails with, which running as root never shows
+        let status = |kind: ErrorKind| HttpError::from(FileError::from(std::io::Error::from(kind))).status();
+        assert_eq!(status(ErrorKind::PermissionDenied), HttpStatus::Forbidden);
+        assert_eq!(status(ErrorKind::NotFound), HttpStatus::NotFound);
+        assert_eq!(status(ErrorKind::InvalidInput), HttpStatus::InternalServerError);
+    }
+}
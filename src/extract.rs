@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::future::Future;
+use serde::de::DeserializeOwned;
+use tokio::io::AsyncReadExt;
+use crate::error::{HttpError, HttpResult};
+use crate::headers::HeaderMap;
+use crate::RequestContext;
+
+/// A handler parameter filled in from the request, so handlers can declare what they need
+/// instead of digging it out of the `RequestContext`.
+pub trait FromRequest: Sized + Send + 'static {
+    fn from_request(ctx: &mut RequestContext) -> impl Future<Output = HttpResult<Self>> + Send + '_;
+}
+
+/// The parameters captured by the route's pattern, as a struct or map with a field per
+/// parameter, or as the value itself when the pattern has just one.
+#[allow(unused)]
+pub struct Path<T>(pub T);
+
+impl<T: DeserializeOwned + Send + 'static> FromRequest for Path<T> {
+    async fn from_request(ctx: &mut RequestContext) -> HttpResult<Self> {
+        let encoded = serde_urlencoded::to_string(&ctx.params).map_err(|e| HttpError::Internal(e.into()))?;
+        if let Ok(params) = serde_urlencoded::from_str(&encoded) {
+            return Ok(Self(params));
+        }
+
+        let [(name, _)] = ctx.params.as_slice() else {
+            return Err(HttpError::BadRequest(format!("Invalid path parameters {}", encoded)));
+        };
+        // A lone parameter decodes as a map entry so that it gets parsed like a field would
+        let mut params: HashMap<String, T> = serde_urlencoded::from_str(&encoded)
+            .map_err(|e| HttpError::BadRequest(format!("Invalid path parameter {}: {}", name, e)))?;
+        params.remove(name).map(Self).ok_or(HttpError::NotFound)
+    }
+}
+
+/// The query string decoded into a struct or map, an absent query counting as an empty one.
+#[allow(unused)]
+pub struct Query<T>(pub T);
+
+impl<T: DeserializeOwned + Send + 'static> FromRequest for Query<T> {
+    async fn from_request(ctx: &mut RequestContext) -> HttpResult<Self> {
+        serde_urlencoded::from_str(ctx.query.as_deref().unwrap_or_default())
+            .map(Self)
+            .map_err(|e| HttpError::BadRequest(format!("Invalid query: {}", e)))
+    }
+}
+
+/// An `application/json` body decoded into `T`.
+#[allow(unused)]
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned + Send + 'static> FromRequest for Json<T> {
+    async fn from_request(ctx: &mut RequestContext) -> HttpResult<Self> {
+        let is_json = ctx.headers.get("Content-Type")
+            .and_then(|content_type| content_type.split(';').next())
+            .is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case("application/json"));
+        if !is_json {
+            return Err(HttpError::UnsupportedMediaType);
+        }
+
+        let Body(body) = Body::from_request(ctx).await?;
+        serde_json::from_slice(&body).map(Self).map_err(|e| HttpError::BadRequest(format!("Invalid JSON body: {}", e)))
+    }
+}
+
+/// A copy of the request headers.
+#[allow(unused)]
+pub struct Headers(pub HeaderMap);
+
+impl FromRequest for Headers {
+    async fn from_request(ctx: &mut RequestContext) -> HttpResult<Self> {
+        Ok(Self(ctx.headers.clone()))
+    }
+}
+
+/// The whole request body, subject to `--max-body-size`.
+#[allow(unused)]
+pub struct Body(pub Vec<u8>);
+
+impl FromRequest for Body {
+    async fn from_request(ctx: &mut RequestContext) -> HttpResult<Self> {
+        let mut body = Vec::new();
+        ctx.body_reader().await?.read_to_end(&mut body).await?;
+        Ok(Self(body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use crate::testing::{configure, context};
+    use super::*;
+
+    async fn get(target: &str) -> RequestContext {
+        context(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target).as_bytes()).await
+    }
+
+    #[tokio::test]
+    async fn query_decodes_into_pairs_or_a_map() {
+        let _config = configure(&[]).await;
+        let mut ctx = get("/search?q=rust+http&page=2&tag=a&tag=b%26c").await;
+        assert_eq!(ctx.path, "/search");
+
+        let Query(pairs) = Query::<Vec<(String, String)>>::from_request(&mut ctx).await.unwrap();
+        assert_eq!(pairs, ctx.query());
+        let pairs: Vec<_> = pairs.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect();
+        assert_eq!(pairs, [("q", "rust http"), ("page", "2"), ("tag", "a"), ("tag", "b&c")]);
+
+        let Query(params) = Query::<HashMap<String, u32>>::from_request(&mut get("/list?page=2&size=50").await).await.unwrap();
+        assert_eq!(params, HashMap::from([("page".to_string(), 2), ("size".to_string(), 50)]));
+        let Query(params) = Query::<HashMap<String, String>>::from_request(&mut get("/list").await).await.unwrap();
+        assert!(params.is_empty());
+
+        let result = Query::<HashMap<String, u32>>::from_request(&mut get("/list?page=two").await).await;
+        assert!(matches!(result, Err(HttpError::BadRequest(_))));
+    }
+
+    fn with_params(mut ctx: RequestContext, params: &[(&str, &str)]) -> RequestContext {
+        ctx.params = params.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        ctx
+    }
+
+    #[tokio::test]
+    async fn path_takes_a_lone_value_or_a_map() {
+        let _config = configure(&[]).await;
+        let mut ctx = with_params(get("/users/42").await, &[("id", "42")]);
+        let Path(id) = Path::<u32>::from_request(&mut ctx).await.unwrap();
+        assert_eq!(id, 42);
+
+        let mut ctx = with_params(get("/users/ada/posts/7").await, &[("user", "ada"), ("post", "7")]);
+        let Path(params) = Path::<HashMap<String, String>>::from_request(&mut ctx).await.unwrap();
+        assert_eq!(params, HashMap::from([("user".to_string(), "ada".to_string()), ("post".to_string(), "7".to_string())]));
+
+        let mut ctx = with_params(get("/users/someone").await, &[("id", "someone")]);
+        assert!(matches!(Path::<u32>::from_request(&mut ctx).await, Err(HttpError::BadRequest(_))));
+    }
+
+    /// A POST of `body` as `content_type`.
+    async fn post(content_type: &str, body: &str) -> RequestContext {
+        context(format!(
+            "POST /items HTTP/1.1\r\nHost: localhost\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+            content_type, body.len(), body,
+        ).as_bytes()).await
+    }
+
+    #[tokio::test]
+    async fn json_needs_a_json_body() {
+        let _config = configure(&[]).await;
+        let mut ctx = post("application/json; charset=utf-8", r#"{"name": "widget", "count": 3}"#).await;
+        let Json(item) = Json::<serde_json::Value>::from_request(&mut ctx).await.unwrap();
+        assert_eq!(item, serde_json::json!({ "name": "widget", "count": 3 }));
+
+        let mut ctx = post("application/json", r#"{"name": "#).await;
+        assert!(matches!(Json::<serde_json::Value>::from_request(&mut ctx).await, Err(HttpError::BadRequest(_))));
+        let mut ctx = post("application/json", r#"{"count": "three"}"#).await;
+        assert!(matches!(Json::<HashMap<String, u32>>::from_request(&mut ctx).await, Err(HttpError::BadRequest(_))));
+        let mut ctx = post("text/plain", r#"{"name": "widget"}"#).await;
+        assert!(matches!(Json::<serde_json::Value>::from_request(&mut ctx).await, Err(HttpError::UnsupportedMediaType)));
+    }
+
+    #[tokio::test]
+    async fn headers_are_a_copy_of_the_request_headers() {
+        let _config = configure(&[]).await;
+        let mut ctx = context(b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Tag: a\r\nx-tag: b\r\n\r\n").await;
+        let Headers(headers) = Headers::from_request(&mut ctx).await.unwrap();
+        assert_eq!(headers, ctx.headers);
+        assert_eq!(headers.get_all("X-Tag").collect::<Vec<_>>(), ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn body_is_read_whole_within_the_size_limit() {
+        let _config = configure(&["--max-body-size", "8"]).await;
+        let Body(body) = Body::from_request(&mut post("text/plain", "12345678").await).await.unwrap();
+        assert_eq!(body, b"12345678");
+        let Body(body) = Body::from_request(&mut get("/").await).await.unwrap();
+        assert!(body.is_empty());
+
+        let result = Body::from_request(&mut post("text/plain", "123456789").await).await;
+        assert!(matches!(result, Err(HttpError::PayloadTooLarge)));
+    }
+}
@@ -0,0 +1,143 @@
+use std::iter::Map;
+use std::slice;
+
+type Iter<'a> = Map<slice::Iter<'a, (String, String)>, fn(&(String, String)) -> (&String, &String)>;
+
+/// Header fields in the order they were added. Names are matched case-insensitively and may
+/// repeat, as `Set-Cookie` has to.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The first value of the field called `name`.
+    pub fn get(&self, name: &str) -> Option<&String> {
+        self.entries.iter()
+            .find(|(existing, _)| existing.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a String> + 'a {
+        self.entries.iter()
+            .filter(move |(existing, _)| existing.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Sets the field to `value` alone, replacing every value it had before.
+    pub fn insert(&mut self, name: &str, value: String) {
+        match self.entries.iter().position(|(existing, _)| existing.eq_ignore_ascii_case(name)) {
+            Some(index) => {
+                self.entries[index].1 = value;
+                let mut position = 0;
+                self.entries.retain(|(existing, _)| {
+                    position += 1;
+                    position - 1 <= index || !existing.eq_ignore_ascii_case(name)
+                });
+            }
+            None => self.append(name, value),
+        }
+    }
+
+    /// Adds another value for the field, after any it already has.
+    pub fn append(&mut self, name: &str, value: String) {
+        self.entries.push((name.to_string(), value));
+    }
+
+    #[allow(unused)]
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        self.entries.iter().map(|(name, value)| (name, value))
+    }
+
+    /// The number of field lines, counting every value of a repeated field.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl FromIterator<(String, String)> for HeaderMap {
+    fn from_iter<T: IntoIterator<Item = (String, String)>>(iter: T) -> Self {
+        Self { entries: iter.into_iter().collect() }
+    }
+}
+
+impl<'a> IntoIterator for &'a HeaderMap {
+    type Item = (&'a String, &'a String);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use super::*;
+
+    fn lines(headers: &HeaderMap) -> Vec<(&str, &str)> {
+        headers.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect()
+    }
+
+    #[test]
+    fn names_match_whatever_their_case() {
+        let mut headers = HeaderMap::new();
+        headers.append("Content-Type", "text/plain".to_string());
+        assert_eq!(headers.get("content-type").map(String::as_str), Some("text/plain"));
+        assert_eq!(headers.get("CONTENT-TYPE").map(String::as_str), Some("text/plain"));
+        assert!(headers.contains_key("Content-type"));
+        assert_eq!(headers.get("Content-Length"), None);
+    }
+
+    #[test]
+    fn append_keeps_every_value_in_order() {
+        let mut headers = HeaderMap::new();
+        headers.append("Set-Cookie", "a=1".to_string());
+        headers.append("Vary", "Origin".to_string());
+        headers.append("set-cookie", "b=2".to_string());
+        assert_eq!(headers.get_all("SET-COOKIE").collect::<Vec<_>>(), ["a=1", "b=2"]);
+        assert_eq!(headers.get("Set-Cookie").map(String::as_str), Some("a=1"));
+        assert_eq!(headers.len(), 3);
+    }
+
+    #[test]
+    fn insert_replaces_every_duplicate_in_place() {
+        let mut headers = HeaderMap::new();
+        headers.append("Server", "first".to_string());
+        headers.append("X-Trace", "1".to_string());
+        headers.append("X-Trace", "2".to_string());
+        headers.append("Date", "today".to_string());
+        headers.append("x-trace", "3".to_string());
+
+        headers.insert("X-TRACE", "only".to_string());
+        assert_eq!(lines(&headers), [("Server", "first"), ("X-Trace", "only"), ("Date", "today")]);
+        headers.insert("Connection", "close".to_string());
+        assert_eq!(lines(&headers).last(), Some(&("Connection", "close")));
+    }
+
+    #[test]
+    fn iteration_keeps_names_as_they_were_given() {
+        let headers: HeaderMap = [
+            ("x-lower".to_string(), "1".to_string()),
+            ("X-Mixed-Case".to_string(), "2".to_string()),
+            ("ETAG".to_string(), "3".to_string()),
+        ].into_iter().collect();
+        assert_eq!(lines(&headers), [("x-lower", "1"), ("X-Mixed-Case", "2"), ("ETAG", "3")]);
+    }
+}
@@ -1,28 +1,255 @@
-use std::collections::HashMap;
-use std::fs;
+use std::fmt::{Display, Formatter};
 use std::fs::File;
-use std::path::PathBuf;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{Cursor, ErrorKind, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
 use nom::ToUsize;
-use tokio::io::{AsyncRead, BufReader};
+use serde::Serialize;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use sha2::{Digest, Sha256};
+use anyhow::anyhow;
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncReadExt, BufReader, ReadBuf};
+use tokio::sync::mpsc::Receiver;
+use crate::cookie::Cookie;
+use crate::headers::HeaderMap;
 
 #[allow(unused)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum HttpStatus {
+    Continue = 100,
+    SwitchingProtocols = 101,
+    Processing = 102,
+    EarlyHints = 103,
     Ok = 200,
     Created = 201,
+    Accepted = 202,
+    NonAuthoritativeInformation = 203,
+    NoContent = 204,
+    ResetContent = 205,
+    PartialContent = 206,
+    MultiStatus = 207,
+    AlreadyReported = 208,
+    ImUsed = 226,
+    MultipleChoices = 300,
+    MovedPermanently = 301,
+    Found = 302,
+    SeeOther = 303,
+    NotModified = 304,
+    UseProxy = 305,
+    TemporaryRedirect = 307,
+    PermanentRedirect = 308,
     BadRequest = 400,
+    Unauthorized = 401,
+    PaymentRequired = 402,
+    Forbidden = 403,
     NotFound = 404,
+    MethodNotAllowed = 405,
+    NotAcceptable = 406,
+    ProxyAuthenticationRequired = 407,
+    RequestTimeout = 408,
+    Conflict = 409,
+    Gone = 410,
+    LengthRequired = 411,
+    PreconditionFailed = 412,
+    PayloadTooLarge = 413,
+    UriTooLong = 414,
+    UnsupportedMediaType = 415,
+    RangeNotSatisfiable = 416,
+    ExpectationFailed = 417,
+    MisdirectedRequest = 421,
+    UnprocessableContent = 422,
+    Locked = 423,
+    FailedDependency = 424,
+    TooEarly = 425,
+    UpgradeRequired = 426,
+    PreconditionRequired = 428,
+    TooManyRequests = 429,
+    RequestHeaderFieldsTooLarge = 431,
+    UnavailableForLegalReasons = 451,
     InternalServerError = 500,
+    NotImplemented = 501,
+    BadGateway = 502,
+    ServiceUnavailable = 503,
+    GatewayTimeout = 504,
+    HttpVersionNotSupported = 505,
+    VariantAlsoNegotiates = 506,
+    InsufficientStorage = 507,
+    LoopDetected = 508,
+    NotExtended = 510,
+    NetworkAuthenticationRequired = 511,
 }
 
-impl Into<&'static str> for HttpStatus {
-    fn into(self) -> &'static str {
-        match self {
+#[allow(unused)]
+impl HttpStatus {
+    /// The status registered under `code`, if it's one of the IANA registered codes.
+    pub fn from_u16(code: u16) -> Option<Self> {
+        Some(match code {
+            100 => Self::Continue,
+            101 => Self::SwitchingProtocols,
+            102 => Self::Processing,
+            103 => Self::EarlyHints,
+            200 => Self::Ok,
+            201 => Self::Created,
+            202 => Self::Accepted,
+            203 => Self::NonAuthoritativeInformation,
+            204 => Self::NoContent,
+            205 => Self::ResetContent,
+            206 => Self::PartialContent,
+            207 => Self::MultiStatus,
+            208 => Self::AlreadyReported,
+            226 => Self::ImUsed,
+            300 => Self::MultipleChoices,
+            301 => Self::MovedPermanently,
+            302 => Self::Found,
+            303 => Self::SeeOther,
+            304 => Self::NotModified,
+            305 => Self::UseProxy,
+            307 => Self::TemporaryRedirect,
+            308 => Self::PermanentRedirect,
+            400 => Self::BadRequest,
+            401 => Self::Unauthorized,
+            402 => Self::PaymentRequired,
+            403 => Self::Forbidden,
+            404 => Self::NotFound,
+            405 => Self::MethodNotAllowed,
+            406 => Self::NotAcceptable,
+            407 => Self::ProxyAuthenticationRequired,
+            408 => Self::RequestTimeout,
+            409 => Self::Conflict,
+            410 => Self::Gone,
+            411 => Self::LengthRequired,
+            412 => Self::PreconditionFailed,
+            413 => Self::PayloadTooLarge,
+            414 => Self::UriTooLong,
+            415 => Self::UnsupportedMediaType,
+            416 => Self::RangeNotSatisfiable,
+            417 => Self::ExpectationFailed,
+            421 => Self::MisdirectedRequest,
+            422 => Self::UnprocessableContent,
+            423 => Self::Locked,
+            424 => Self::FailedDependency,
+            425 => Self::TooEarly,
+            426 => Self::UpgradeRequired,
+            428 => Self::PreconditionRequired,
+            429 => Self::TooManyRequests,
+            431 => Self::RequestHeaderFieldsTooLarge,
+            451 => Self::UnavailableForLegalReasons,
+            500 => Self::InternalServerError,
+            501 => Self::NotImplemented,
+            502 => Self::BadGateway,
+            503 => Self::ServiceUnavailable,
+            504 => Self::GatewayTimeout,
+            505 => Self::HttpVersionNotSupported,
+            506 => Self::VariantAlsoNegotiates,
+            507 => Self::InsufficientStorage,
+            508 => Self::LoopDetected,
+            510 => Self::NotExtended,
+            511 => Self::NetworkAuthenticationRequired,
+            _ => return None,
+        })
+    }
+
+    pub fn reason_phrase(&self) -> &'static str {
+        (*self).into()
+    }
+
+    pub fn is_informational(&self) -> bool {
+        (100..200).contains(&(*self as u16))
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&(*self as u16))
+    }
+
+    pub fn is_redirection(&self) -> bool {
+        (300..400).contains(&(*self as u16))
+    }
+
+    pub fn is_client_error(&self) -> bool {
+        (400..500).contains(&(*self as u16))
+    }
+
+    pub fn is_server_error(&self) -> bool {
+        (500..600).contains(&(*self as u16))
+    }
+
+    /// 1xx, 204 and 304 responses never have a body, whatever their headers say.
+    pub fn permits_body(&self) -> bool {
+        !self.is_informational() && !matches!(self, Self::NoContent | Self::NotModified)
+    }
+}
+
+impl From<HttpStatus> for &'static str {
+    fn from(status: HttpStatus) -> Self {
+        match status {
+            HttpStatus::Continue => "Continue",
+            HttpStatus::SwitchingProtocols => "Switching Protocols",
+            HttpStatus::Processing => "Processing",
+            HttpStatus::EarlyHints => "Early Hints",
             HttpStatus::Ok => "OK",
             HttpStatus::Created => "Created",
-            HttpStatus::BadRequest => "BadRequest",
-            HttpStatus::NotFound => "NotFound",
-            HttpStatus::InternalServerError => "InternalServerError",
+            HttpStatus::Accepted => "Accepted",
+            HttpStatus::NonAuthoritativeInformation => "Non-Authoritative Information",
+            HttpStatus::NoContent => "No Content",
+            HttpStatus::ResetContent => "Reset Content",
+            HttpStatus::PartialContent => "Partial Content",
+            HttpStatus::MultiStatus => "Multi-Status",
+            HttpStatus::AlreadyReported => "Already Reported",
+            HttpStatus::ImUsed => "IM Used",
+            HttpStatus::MultipleChoices => "Multiple Choices",
+            HttpStatus::MovedPermanently => "Moved Permanently",
+            HttpStatus::Found => "Found",
+            HttpStatus::SeeOther => "See Other",
+            HttpStatus::NotModified => "Not Modified",
+            HttpStatus::UseProxy => "Use Proxy",
+            HttpStatus::TemporaryRedirect => "Temporary Redirect",
+            HttpStatus::PermanentRedirect => "Permanent Redirect",
+            HttpStatus::BadRequest => "Bad Request",
+            HttpStatus::Unauthorized => "Unauthorized",
+            HttpStatus::PaymentRequired => "Payment Required",
+            HttpStatus::Forbidden => "Forbidden",
+            HttpStatus::NotFound => "Not Found",
+            HttpStatus::MethodNotAllowed => "Method Not Allowed",
+            HttpStatus::NotAcceptable => "Not Acceptable",
+            HttpStatus::ProxyAuthenticationRequired => "Proxy Authentication Required",
+            HttpStatus::RequestTimeout => "Request Timeout",
+            HttpStatus::Conflict => "Conflict",
+            HttpStatus::Gone => "Gone",
+            HttpStatus::LengthRequired => "Length Required",
+            HttpStatus::PreconditionFailed => "Precondition Failed",
+            HttpStatus::PayloadTooLarge => "Content Too Large",
+            HttpStatus::UriTooLong => "URI Too Long",
+            HttpStatus::UnsupportedMediaType => "Unsupported Media Type",
+            HttpStatus::RangeNotSatisfiable => "Range Not Satisfiable",
+            HttpStatus::ExpectationFailed => "Expectation Failed",
+            HttpStatus::MisdirectedRequest => "Misdirected Request",
+            HttpStatus::UnprocessableContent => "Unprocessable Content",
+            HttpStatus::Locked => "Locked",
+            HttpStatus::FailedDependency => "Failed Dependency",
+            HttpStatus::TooEarly => "Too Early",
+            HttpStatus::UpgradeRequired => "Upgrade Required",
+            HttpStatus::PreconditionRequired => "Precondition Required",
+            HttpStatus::TooManyRequests => "Too Many Requests",
+            HttpStatus::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            HttpStatus::UnavailableForLegalReasons => "Unavailable For Legal Reasons",
+            HttpStatus::InternalServerError => "Internal Server Error",
+            HttpStatus::NotImplemented => "Not Implemented",
+            HttpStatus::BadGateway => "Bad Gateway",
+            HttpStatus::ServiceUnavailable => "Service Unavailable",
+            HttpStatus::GatewayTimeout => "Gateway Timeout",
+            HttpStatus::HttpVersionNotSupported => "HTTP Version Not Supported",
+            HttpStatus::VariantAlsoNegotiates => "Variant Also Negotiates",
+            HttpStatus::InsufficientStorage => "Insufficient Storage",
+            HttpStatus::LoopDetected => "Loop Detected",
+            HttpStatus::NotExtended => "Not Extended",
+            HttpStatus::NetworkAuthenticationRequired => "Network Authentication Required",
         }
     }
 }
@@ -30,8 +257,10 @@ impl Into<&'static str> for HttpStatus {
 pub struct HttpResponse {
     status: HttpStatus,
     status_message: Option<String>,
-    headers: HashMap<String, String>,
+    headers: HeaderMap,
     content: Option<Box<dyn HttpContent + Send + Sync>>,
+    /// Set for responses to HEAD, which describe their content in the head but don't send it
+    omit_body: bool,
 }
 
 impl HttpResponse {
@@ -39,27 +268,83 @@ impl HttpResponse {
         Self {
             status,
             status_message: None,
-            headers: HashMap::new(),
+            headers: HeaderMap::new(),
             content: None,
+            omit_body: false,
         }
     }
 
+    /// A 301, 302, 303, 307 or 308 response sending the client to `location`, with anything
+    /// that isn't allowed in a URI percent-encoded.
+    pub fn redirect(status: HttpStatus, location: &str) -> Self {
+        debug_assert!(matches!(status,
+            HttpStatus::MovedPermanently | HttpStatus::Found | HttpStatus::SeeOther
+            | HttpStatus::TemporaryRedirect | HttpStatus::PermanentRedirect));
+        Self::new(status).with_header("Location", encode_uri(location))
+    }
+
+    /// A 200 response with `value` serialized as its JSON body.
+    pub fn json<T: Serialize + ?Sized>(value: &T) -> anyhow::Result<Self> {
+        Ok(Self::new(HttpStatus::Ok).with_content(JsonContent::new(value)?))
+    }
+
+    /// A 200 response with `html` as its body.
+    pub fn html(html: String) -> Self {
+        Self::new(HttpStatus::Ok).with_content(HtmlContent::new(html))
+    }
+
     pub fn with_status_message(self, message: String) -> Self {
-        Self {
-            status: self.status,
-            status_message: Some(message),
-            headers: self.headers,
-            content: self.content,
-        }
+        Self { status_message: Some(message), ..self }
+    }
+
+    pub fn map_content<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(Box<dyn HttpContent + Send + Sync>) -> Box<dyn HttpContent + Send + Sync>,
+    {
+        self.content = self.content.map(f);
+        self
     }
 
     pub fn with_content(self, content: Box<dyn HttpContent + Send + Sync>) -> Self {
-        Self {
-            status: self.status,
-            status_message: self.status_message,
-            headers: self.headers,
-            content: Some(content),
-        }
+        Self { content: Some(content), ..self }
+    }
+
+    /// Keeps the content's `Content-Type` and length in the head but sends none of it, as a
+    /// response to HEAD must.
+    pub fn without_body(self) -> Self {
+        Self { omit_body: true, ..self }
+    }
+
+    /// Sets `name`, replacing any value it already had. A `Content-Type` set this way overrides
+    /// the content's own.
+    pub fn with_header(mut self, name: &str, value: String) -> Self {
+        self.insert_header(name, value);
+        self
+    }
+
+    #[allow(unused)]
+    pub fn insert_header(&mut self, name: &str, value: String) {
+        self.headers.insert(name, value);
+    }
+
+    /// Adds `name` to the `Vary` header, keeping whatever the response already varies on.
+    pub fn with_vary(self, name: &str) -> Self {
+        let vary = match self.headers.get("Vary") {
+            Some(vary) if vary.split(',').any(|existing| existing.trim().eq_ignore_ascii_case(name)) => return self,
+            Some(vary) => format!("{}, {}", vary, name),
+            None => name.to_string(),
+        };
+        self.with_header("Vary", vary)
+    }
+
+    /// Asks the client to save the body as `filename` instead of displaying it.
+    pub fn with_attachment(self, filename: &str) -> Self {
+        self.with_header("Content-Disposition", content_disposition(filename))
+    }
+
+    pub fn with_cookie(mut self, cookie: Cookie) -> Self {
+        self.headers.append("Set-Cookie", cookie.to_string());
+        self
     }
 
     pub fn status(&self) -> HttpStatus {
@@ -70,23 +355,69 @@ impl HttpResponse {
         self.status_message.as_ref()
     }
 
-    pub fn content(&self) -> Option<&Box<dyn HttpContent + Send + Sync>> {
-        self.content.as_ref()
+    pub fn content(&self) -> Option<&(dyn HttpContent + Send + Sync)> {
+        self.content.as_deref()
+    }
+
+    /// The content that actually goes out after the head, if any.
+    pub fn body(&self) -> Option<&(dyn HttpContent + Send + Sync)> {
+        self.content().filter(|_| !self.omit_body && self.status.permits_body())
     }
 
-    pub fn headers(&self) -> &HashMap<String, String> {
+    pub fn headers(&self) -> &HeaderMap {
         &self.headers
     }
+
+    /// The headers to send as they were set, which leaves out `Content-Type`, sent once from
+    /// `content_type()`. `Content-Length` and `Transfer-Encoding` are worked out from the body
+    /// that's actually sent instead, except that a 304 may describe the representation it stands
+    /// in for.
+    pub fn sent_headers(&self) -> impl Iterator<Item = (&String, &String)> {
+        let keep_framing = self.status == HttpStatus::NotModified;
+        self.headers.iter().filter(move |(name, _)| {
+            if name.eq_ignore_ascii_case("Content-Type") {
+                return false;
+            }
+            keep_framing || !(name.eq_ignore_ascii_case("Content-Length") || name.eq_ignore_ascii_case("Transfer-Encoding"))
+        })
+    }
+
+    /// The `Content-Type` set on the response, or else the content's.
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.get("Content-Type").map(String::as_str)
+            .or_else(|| self.content().filter(|_| self.status.permits_body()).map(|content| content.content_type()))
+    }
 }
 
 pub trait HttpContent {
     fn content_type(&self) -> &str;
-    fn content_length(&self) -> usize;
+    /// The body's length in bytes, or `None` if it's only known once the body has been read.
+    fn content_length(&self) -> Option<usize>;
     fn content(&self) -> Result<Box<dyn AsyncRead + Send + Sync + Unpin + '_>, anyhow::Error>;
+    /// A strong validator for this body, if it has one.
+    fn etag(&self) -> Option<String> {
+        None
+    }
+    /// Names of the trailer fields sent after a chunked body, announced in the `Trailer` header.
+    fn trailer_names(&self) -> Vec<String> {
+        Vec::new()
+    }
+    /// The trailer fields for a chunked body, asked for once `content()` has been read to the
+    /// end so they can describe what was sent.
+    fn trailers(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+}
+
+/// A strong validator for an in-memory body, derived from a hash of its bytes.
+fn content_etag(content: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
 }
 
 pub struct PlainTextContent {
-    text: String
+    text: String,
 }
 
 impl PlainTextContent {
@@ -100,33 +431,349 @@ impl HttpContent for PlainTextContent {
         "text/plain"
     }
 
-    fn content_length(&self) -> usize {
-        self.text.len()
+    fn content_length(&self) -> Option<usize> {
+        Some(self.text.len())
     }
 
     fn content(&self) -> Result<Box<dyn AsyncRead + Send + Sync + Unpin + '_>, anyhow::Error> {
         let cursor = std::io::Cursor::new(self.text.as_bytes());
         Ok(Box::new(cursor))
     }
+
+    fn etag(&self) -> Option<String> {
+        Some(content_etag(self.text.as_bytes()))
+    }
+}
+
+/// A rendered HTML page or fragment, such as a generated listing. Anything interpolated into it
+/// should have gone through `escape_html` first.
+pub struct HtmlContent {
+    html: String,
+}
+
+impl HtmlContent {
+    pub fn new(html: String) -> Box<Self> {
+        Box::new(Self { html })
+    }
+}
+
+impl HttpContent for HtmlContent {
+    fn content_type(&self) -> &str {
+        "text/html; charset=utf-8"
+    }
+
+    fn content_length(&self) -> Option<usize> {
+        Some(self.html.len())
+    }
+
+    fn content(&self) -> Result<Box<dyn AsyncRead + Send + Sync + Unpin + '_>, anyhow::Error> {
+        Ok(Box::new(Cursor::new(self.html.as_bytes())))
+    }
+
+    fn etag(&self) -> Option<String> {
+        Some(content_etag(self.html.as_bytes()))
+    }
+}
+
+pub struct JsonContent {
+    json: Vec<u8>,
+}
+
+impl JsonContent {
+    /// Serializes `value` up front, so the length and validator are known before sending.
+    pub fn new<T: Serialize + ?Sized>(value: &T) -> anyhow::Result<Box<Self>> {
+        Ok(Box::new(Self { json: serde_json::to_vec(value)? }))
+    }
+}
+
+impl HttpContent for JsonContent {
+    fn content_type(&self) -> &str {
+        "application/json"
+    }
+
+    fn content_length(&self) -> Option<usize> {
+        Some(self.json.len())
+    }
+
+    fn content(&self) -> Result<Box<dyn AsyncRead + Send + Sync + Unpin + '_>, anyhow::Error> {
+        Ok(Box::new(Cursor::new(self.json.as_slice())))
+    }
+
+    fn etag(&self) -> Option<String> {
+        Some(content_etag(&self.json))
+    }
+}
+
+/// A body produced while it's being sent, such as proxied or generated output, whose length isn't
+/// known up front. It goes out chunked, or delimited by closing the connection for HTTP/1.0.
+#[allow(unused)]
+pub struct StreamContent {
+    reader: Mutex<Option<Box<dyn AsyncRead + Send + Sync + Unpin>>>,
+    content_type: String,
+}
+
+#[allow(unused)]
+impl StreamContent {
+    pub fn new(reader: impl AsyncRead + Send + Sync + Unpin + 'static, content_type: &str) -> Box<Self> {
+        Box::new(Self { reader: Mutex::new(Some(Box::new(reader))), content_type: content_type.to_string() })
+    }
+
+    /// Sends the chunks arriving on a channel as they come, ending once every sender has been
+    /// dropped.
+    pub fn from_channel(chunks: Receiver<Bytes>, content_type: &str) -> Box<Self> {
+        Self::new(ChannelReader { chunks, chunk: Bytes::new() }, content_type)
+    }
+}
+
+impl HttpContent for StreamContent {
+    fn content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    fn content_length(&self) -> Option<usize> {
+        None
+    }
+
+    fn content(&self) -> Result<Box<dyn AsyncRead + Send + Sync + Unpin + '_>, anyhow::Error> {
+        self.reader.lock().unwrap().take()
+            .ok_or_else(|| anyhow!("Streamed body can only be sent once"))
+    }
+}
+
+struct ChannelReader {
+    chunks: Receiver<Bytes>,
+    /// What's left of the chunk the last read didn't have room for
+    chunk: Bytes,
+}
+
+impl AsyncRead for ChannelReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        while self.chunk.is_empty() {
+            match ready!(self.chunks.poll_recv(cx)) {
+                Some(chunk) => self.chunk = chunk,
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let len = self.chunk.len().min(buf.remaining());
+        let data = self.chunk.split_to(len);
+        buf.put_slice(&data);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Percent-encodes the bytes of `uri` that can't appear in a URI, leaving existing escapes and
+/// reserved characters alone.
+pub fn encode_uri(uri: &str) -> String {
+    let mut encoded = String::with_capacity(uri.len());
+    for b in uri.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~:/?#[]@!$&'()*+,;=%".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+/// An RFC 6266 `attachment` disposition. Names that aren't plain ASCII get an approximation in
+/// `filename` for old clients, and the exact name as UTF-8 in `filename*`.
+fn content_disposition(filename: &str) -> String {
+    let fallback: String = filename.chars()
+        .map(|c| match c {
+            '"' | '\\' => format!("\\{}", c),
+            c if c.is_ascii() && !c.is_ascii_control() => c.to_string(),
+            _ => "_".to_string(),
+        })
+        .collect();
+    if filename.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+        return format!("attachment; filename=\"{}\"", fallback);
+    }
+
+    let mut encoded = String::with_capacity(filename.len());
+    for b in filename.bytes() {
+        if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, encoded)
+}
+
+/// The second the cached `Date` value was formatted for, and the value.
+static DATE: Mutex<(u64, String)> = Mutex::new((0, String::new()));
+
+/// The current time as an HTTP-date for the `Date` header. It only changes once a second, so
+/// it's formatted once and reused until then.
+pub fn http_date() -> String {
+    let now = SystemTime::now();
+    let second = now.duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or_default();
+    let mut date = DATE.lock().unwrap();
+    if date.0 != second || date.1.is_empty() {
+        *date = (second, httpdate::fmt_http_date(now));
+    }
+    date.1.clone()
+}
+
+/// Escapes text for use in HTML element content or quoted attribute values.
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[derive(Debug)]
+pub enum FileError {
+    NotFound,
+    PermissionDenied,
+    Other(std::io::Error),
+}
+
+impl From<std::io::Error> for FileError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::NotFound => Self::NotFound,
+            ErrorKind::PermissionDenied => Self::PermissionDenied,
+            _ => Self::Other(e),
+        }
+    }
+}
+
+impl Display for FileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "File not found"),
+            Self::PermissionDenied => write!(f, "Permission denied"),
+            Self::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for FileError {}
+
+pub struct MessageContent {
+    message: String
+}
+
+impl MessageContent {
+    pub fn new(message: String) -> Box<Self> {
+        Box::new(Self { message })
+    }
+}
+
+impl HttpContent for MessageContent {
+    fn content_type(&self) -> &str {
+        "message/http"
+    }
+
+    fn content_length(&self) -> Option<usize> {
+        Some(self.message.len())
+    }
+
+    fn content(&self) -> Result<Box<dyn AsyncRead + Send + Sync + Unpin + '_>, anyhow::Error> {
+        let cursor = std::io::Cursor::new(self.message.as_bytes());
+        Ok(Box::new(cursor))
+    }
 }
 
+pub const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+const SNIFF_LENGTH: usize = 512;
+
 pub struct FileContent {
     path: PathBuf,
+    len: u64,
+    modified: Option<SystemTime>,
+    content_type: String,
 }
 
 impl FileContent {
-    pub fn new(path: PathBuf) -> Box<Self> {
-        Box::new(Self { path })
+    pub fn open(path: PathBuf) -> Result<Box<Self>, FileError> {
+        let file = File::open(&path)?;
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            return Err(FileError::NotFound);
+        }
+
+        Ok(Box::new(Self {
+            path,
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            content_type: DEFAULT_CONTENT_TYPE.to_string(),
+        }))
+    }
+
+    pub fn with_content_type(mut self: Box<Self>, content_type: String) -> Box<Self> {
+        self.content_type = content_type;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn file_len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    /// A strong validator built from the file's size and modification time, which changes
+    /// whenever the file is rewritten.
+    pub fn etag(&self) -> String {
+        let modified = self.modified
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        format!("\"{:x}-{:x}\"", self.len, modified.as_nanos())
+    }
+
+    /// Guesses the content type from the first bytes of the file. The peeked bytes are read
+    /// through a separate handle, so `content()` still streams the file from the start.
+    pub fn sniff_content_type(&self) -> Result<Option<&'static str>, FileError> {
+        let mut head = Vec::with_capacity(SNIFF_LENGTH);
+        File::open(&self.path)?.take(SNIFF_LENGTH as u64).read_to_end(&mut head)?;
+        Ok(sniff_content_type(&head))
+    }
+}
+
+fn sniff_content_type(head: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xFF\xD8\xFF", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+    ];
+
+    if let Some((_, content_type)) = SIGNATURES.iter().find(|(signature, _)| head.starts_with(signature)) {
+        return Some(content_type);
     }
+
+    let text = head.trim_ascii_start();
+    let is_html = [b"<!doctype html".as_slice(), b"<html".as_slice()].iter()
+        .any(|prefix| text.len() >= prefix.len() && text[..prefix.len()].eq_ignore_ascii_case(prefix));
+    is_html.then_some("text/html")
 }
 
 impl HttpContent for FileContent {
     fn content_type(&self) -> &str {
-        "application/octet-stream"
+        &self.content_type
     }
 
-    fn content_length(&self) -> usize {
-        fs::metadata(self.path.as_path()).expect("File doesn't exist?").len().to_usize()
+    fn content_length(&self) -> Option<usize> {
+        Some(self.len.to_usize())
     }
 
     fn content(&self) -> Result<Box<dyn AsyncRead + Send + Sync + Unpin + '_>, anyhow::Error> {
@@ -134,4 +781,615 @@ impl HttpContent for FileContent {
         let file = tokio::fs::File::from(file);
         Ok(Box::new(BufReader::new(file)))
     }
-}
\ No newline at end of file
+
+    fn etag(&self) -> Option<String> {
+        Some(FileContent::etag(self))
+    }
+}
+
+pub struct RangedFileContent {
+    path: PathBuf,
+    content_type: String,
+    range: Range<u64>,
+}
+
+impl RangedFileContent {
+    pub fn new(path: PathBuf, content_type: String, range: Range<u64>) -> Box<Self> {
+        Box::new(Self { path, content_type, range })
+    }
+}
+
+impl HttpContent for RangedFileContent {
+    fn content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    fn content_length(&self) -> Option<usize> {
+        Some((self.range.end - self.range.start).to_usize())
+    }
+
+    fn content(&self) -> Result<Box<dyn AsyncRead + Send + Sync + Unpin + '_>, anyhow::Error> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.range.start))?;
+        let file = tokio::fs::File::from(file);
+        Ok(Box::new(BufReader::new(file).take(self.range.end - self.range.start)))
+    }
+}
+
+/// How a `Range` header applies to a resource.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    /// The header is malformed or not something we serve partially, so the whole resource is sent
+    Ignored,
+    /// The satisfiable ranges in the order they were requested, unsatisfiable ones dropped
+    Satisfiable(Vec<Range<u64>>),
+    /// None of the requested bytes exist in the resource
+    Unsatisfiable,
+}
+
+/// More ranges than this in one request aren't worth the multipart overhead, the whole
+/// resource is sent instead.
+const MAX_RANGES: usize = 64;
+
+/// Parses a `bytes=` range set of `start-end`, `start-` and `-suffix_length` ranges against a
+/// resource of `len` bytes.
+pub fn parse_byte_ranges(value: &str, len: u64) -> ByteRange {
+    let Some(specs) = value.trim().strip_prefix("bytes=") else {
+        return ByteRange::Ignored;
+    };
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(str::trim).filter(|spec| !spec.is_empty()) {
+        match parse_range_spec(spec, len) {
+            Some(Some(range)) => ranges.push(range),
+            Some(None) => {}
+            None => return ByteRange::Ignored,
+        }
+    }
+
+    match ranges.len() {
+        0 => ByteRange::Unsatisfiable,
+        n if n > MAX_RANGES => ByteRange::Ignored,
+        _ => ByteRange::Satisfiable(ranges),
+    }
+}
+
+/// Parses one range of a range set, `None` when it's malformed and `Some(None)` when it's
+/// valid but none of its bytes exist.
+fn parse_range_spec(spec: &str, len: u64) -> Option<Option<Range<u64>>> {
+    let (start, end) = spec.split_once('-')?;
+    match (start.trim(), end.trim()) {
+        ("", suffix_length) => match suffix_length.parse::<u64>().ok()? {
+            0 => Some(None),
+            _ if len == 0 => Some(None),
+            suffix_length => Some(Some(len.saturating_sub(suffix_length)..len)),
+        },
+        (start, end) => {
+            let start = start.parse::<u64>().ok()?;
+            let end = match end {
+                "" => None,
+                end => Some(end.parse::<u64>().ok().filter(|end| *end >= start)?),
+            };
+
+            if start >= len {
+                return Some(None);
+            }
+
+            let end = end.map_or(len - 1, |end| end.min(len - 1));
+            Some(Some(start..end + 1))
+        }
+    }
+}
+
+static BOUNDARY_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Several ranges of a file sent as one `multipart/byteranges` body.
+pub struct MultipartRangesContent {
+    path: PathBuf,
+    part_content_type: String,
+    file_len: u64,
+    ranges: Vec<Range<u64>>,
+    content_type: String,
+    boundary: String,
+}
+
+impl MultipartRangesContent {
+    pub fn new(path: PathBuf, part_content_type: String, file_len: u64, ranges: Vec<Range<u64>>) -> Box<Self> {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.subsec_nanos());
+        let boundary = format!("byteranges-{:08x}{:08x}", nanos, BOUNDARY_COUNTER.fetch_add(1, Ordering::Relaxed));
+        let content_type = format!("multipart/byteranges; boundary={}", boundary);
+        Box::new(Self { path, part_content_type, file_len, ranges, content_type, boundary })
+    }
+
+    fn part_head(&self, range: &Range<u64>) -> String {
+        format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+            self.boundary, self.part_content_type, range.start, range.end - 1, self.file_len,
+        )
+    }
+
+    fn closing_delimiter(&self) -> String {
+        format!("\r\n--{}--\r\n", self.boundary)
+    }
+}
+
+impl HttpContent for MultipartRangesContent {
+    fn content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    fn content_length(&self) -> Option<usize> {
+        let parts: u64 = self.ranges.iter()
+            .map(|range| self.part_head(range).len() as u64 + range.end - range.start)
+            .sum();
+        Some((parts + self.closing_delimiter().len() as u64).to_usize())
+    }
+
+    fn content(&self) -> Result<Box<dyn AsyncRead + Send + Sync + Unpin + '_>, anyhow::Error> {
+        let mut reader: Box<dyn AsyncRead + Send + Sync + Unpin> = Box::new(tokio::io::empty());
+        for range in &self.ranges {
+            let mut file = File::open(&self.path)?;
+            file.seek(SeekFrom::Start(range.start))?;
+            let part = tokio::fs::File::from(file).take(range.end - range.start);
+            reader = Box::new(reader.chain(Cursor::new(self.part_head(range))).chain(part));
+        }
+
+        Ok(Box::new(reader.chain(Cursor::new(self.closing_delimiter()))))
+    }
+}
+
+/// Sends the wrapped content chunked with a `Repr-Digest` trailer (RFC 9530) holding the
+/// SHA-256 of the bytes, computed while they're streamed instead of reading the body twice.
+pub struct DigestContent {
+    inner: Box<dyn HttpContent + Send + Sync>,
+    digest: Arc<Mutex<Option<String>>>,
+}
+
+impl DigestContent {
+    pub fn new(inner: Box<dyn HttpContent + Send + Sync>) -> Box<Self> {
+        Box::new(Self { inner, digest: Arc::default() })
+    }
+}
+
+impl HttpContent for DigestContent {
+    fn content_type(&self) -> &str {
+        self.inner.content_type()
+    }
+
+    fn content_length(&self) -> Option<usize> {
+        // Trailers only exist in chunked bodies
+        None
+    }
+
+    fn content(&self) -> Result<Box<dyn AsyncRead + Send + Sync + Unpin + '_>, anyhow::Error> {
+        Ok(Box::new(DigestReader { inner: self.inner.content()?, hasher: Sha256::new(), digest: self.digest.clone() }))
+    }
+
+    fn etag(&self) -> Option<String> {
+        self.inner.etag()
+    }
+
+    fn trailer_names(&self) -> Vec<String> {
+        vec!["Repr-Digest".to_string()]
+    }
+
+    fn trailers(&self) -> Vec<(String, String)> {
+        match self.digest.lock().unwrap().as_ref() {
+            Some(digest) => vec![("Repr-Digest".to_string(), format!("sha-256=:{}:", digest))],
+            None => Vec::new(),
+        }
+    }
+}
+
+struct DigestReader<R> {
+    inner: R,
+    hasher: Sha256,
+    digest: Arc<Mutex<Option<String>>>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for DigestReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let read = &buf.filled()[before..];
+        if read.is_empty() && buf.remaining() > 0 {
+            let digest = BASE64_STANDARD.encode(self.hasher.finalize_reset());
+            *self.digest.lock().unwrap() = Some(digest);
+        } else {
+            self.hasher.update(read);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+const MULTIPART_READ_SIZE: usize = 8192;
+const MAX_PART_HEAD_LENGTH: usize = 8192;
+
+/// Pulls the `boundary` parameter out of a `multipart/form-data` Content-Type, or `None` if the
+/// body isn't multipart form data.
+pub fn multipart_boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    if !params.next()?.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+
+    params
+        .filter_map(|param| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| unquote(value.trim()))
+        .filter(|boundary| (1..=70).contains(&boundary.len()))
+}
+
+/// Strips the quotes and backslash escapes from a quoted-string parameter value.
+fn unquote(value: &str) -> String {
+    let Some(quoted) = value.strip_prefix('"').and_then(|value| value.strip_suffix('"')) else {
+        return value.to_string();
+    };
+
+    let mut unquoted = String::with_capacity(quoted.len());
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.extend(chars.next()),
+            c => unquoted.push(c),
+        }
+    }
+    unquoted
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum MultipartState {
+    /// Reading a part's body, or the preamble before the first one
+    Body,
+    /// Just past a delimiter, before the line saying whether another part follows
+    Delimited,
+    Done,
+}
+
+/// Splits a `multipart/form-data` body into its parts as it's read, without holding more than
+/// a read's worth of it in memory.
+pub struct MultipartReader<R> {
+    inner: R,
+    /// `CRLF--boundary`, which ends every part
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    eof: bool,
+    state: MultipartState,
+}
+
+impl<R: AsyncRead + Unpin> MultipartReader<R> {
+    pub fn new(inner: R, boundary: &str) -> Self {
+        Self {
+            inner,
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            // The first delimiter may open the body without the usual line break in front of it
+            buf: b"\r\n".to_vec(),
+            eof: false,
+            state: MultipartState::Body,
+        }
+    }
+
+    /// Moves on to the next part, skipping whatever is left of the current one. `None` means
+    /// the closing delimiter was reached.
+    pub async fn next_part(&mut self) -> std::io::Result<Option<MultipartPart<'_, R>>> {
+        while self.state == MultipartState::Body {
+            let mut skipped = [0; MULTIPART_READ_SIZE];
+            std::future::poll_fn(|cx| {
+                let mut skipped = ReadBuf::new(&mut skipped);
+                self.poll_read_body(cx, &mut skipped)
+            }).await?;
+        }
+        if self.state == MultipartState::Done {
+            return Ok(None);
+        }
+
+        let Some(head) = self.read_part_head().await? else {
+            self.state = MultipartState::Done;
+            return Ok(None);
+        };
+
+        let headers = parse_part_headers(&head)?;
+        let disposition = headers.get("Content-Disposition")
+            .ok_or_else(|| multipart_error("Part without Content-Disposition"))?;
+        let mut params = disposition.split(';');
+        if !params.next().is_some_and(|kind| kind.trim().eq_ignore_ascii_case("form-data")) {
+            return Err(multipart_error("Part isn't form-data"));
+        }
+
+        let mut name = None;
+        let mut filename = None;
+        for param in params {
+            if let Some((key, value)) = param.split_once('=') {
+                match key.trim().to_ascii_lowercase().as_str() {
+                    "name" => name = Some(unquote(value.trim())),
+                    "filename" => filename = Some(unquote(value.trim())),
+                    _ => {}
+                }
+            }
+        }
+
+        self.state = MultipartState::Body;
+        Ok(Some(MultipartPart {
+            name: name.ok_or_else(|| multipart_error("Part without a name"))?,
+            filename,
+            content_type: headers.get("Content-Type").cloned(),
+            reader: self,
+        }))
+    }
+
+    /// Reads the rest of a delimiter's line and the part's header section up to its blank
+    /// line, or returns `None` if the delimiter was the closing one.
+    async fn read_part_head(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        loop {
+            if self.buf.starts_with(b"--") {
+                return Ok(None);
+            }
+
+            // Searching from the delimiter line's own CRLF also finds the blank line of a part without headers
+            let line_end = self.buf.windows(2).position(|window| window == b"\r\n");
+            let head_end = line_end.and_then(|line_end| self.buf[line_end..].windows(4)
+                .position(|window| window == b"\r\n\r\n")
+                .map(|at| (line_end, line_end + at + 4)));
+            if let Some((line_end, head_end)) = head_end {
+                // Only transport padding may follow the boundary on its line
+                if !self.buf[..line_end].iter().all(|b| *b == b' ' || *b == b'\t') {
+                    return Err(multipart_error("Malformed multipart delimiter"));
+                }
+                let head = self.buf[line_end + 2..head_end - 2].to_vec();
+                self.buf.drain(..head_end);
+                return Ok(Some(head));
+            }
+
+            if self.buf.len() > MAX_PART_HEAD_LENGTH {
+                return Err(multipart_error("Multipart part headers too long"));
+            }
+            if !std::future::poll_fn(|cx| self.poll_fill(cx)).await? {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+
+    /// Reads more of the body into the buffer, returning `false` once it has ended.
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        if self.eof {
+            return Poll::Ready(Ok(false));
+        }
+
+        let mut chunk = [0; MULTIPART_READ_SIZE];
+        let mut chunk = ReadBuf::new(&mut chunk);
+        ready!(Pin::new(&mut self.inner).poll_read(cx, &mut chunk))?;
+        self.eof = chunk.filled().is_empty();
+        self.buf.extend_from_slice(chunk.filled());
+        Poll::Ready(Ok(!self.eof))
+    }
+
+    /// Copies body bytes up to the next delimiter into `out`, consuming the delimiter and
+    /// reading nothing once it's reached.
+    fn poll_read_body(&mut self, cx: &mut Context<'_>, out: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        loop {
+            if self.state != MultipartState::Body {
+                return Poll::Ready(Ok(()));
+            }
+
+            let found = self.buf.windows(self.delimiter.len()).position(|window| window == self.delimiter);
+            // Without a delimiter in sight, the tail could still be the start of one
+            let available = found.unwrap_or(self.buf.len().saturating_sub(self.delimiter.len() - 1));
+            if available > 0 {
+                let len = available.min(out.remaining());
+                out.put_slice(&self.buf[..len]);
+                self.buf.drain(..len);
+                return Poll::Ready(Ok(()));
+            }
+            if found.is_some() {
+                self.buf.drain(..self.delimiter.len());
+                self.state = MultipartState::Delimited;
+                continue;
+            }
+
+            if !ready!(self.poll_fill(cx))? {
+                return Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+        }
+    }
+}
+
+fn parse_part_headers(head: &[u8]) -> std::io::Result<HeaderMap> {
+    let head = std::str::from_utf8(head).map_err(|_| multipart_error("Part headers aren't UTF-8"))?;
+    head.split("\r\n")
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (name, value) = line.split_once(':').ok_or_else(|| multipart_error("Malformed part header"))?;
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+fn multipart_error(message: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+/// One part of a multipart body, read through its `AsyncRead` implementation.
+pub struct MultipartPart<'a, R> {
+    pub name: String,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    reader: &'a mut MultipartReader<R>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for MultipartPart<'_, R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        self.reader.poll_read_body(cx, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use pretty_assertions::assert_eq;
+    use crate::counting::CountingReader;
+    use crate::testing::temp_dir;
+    use super::*;
+
+    /// Hands out its bytes one at a time, so delimiters are split across reads.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl AsyncRead for Trickle<'_> {
+        fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+            if let Some((first, rest)) = self.0.split_first() {
+                buf.put_slice(&[*first]);
+                self.0 = rest;
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    const FORM: &[u8] = concat!(
+        "preamble\r\n",
+        "--XyZ\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nHoliday\r\n",
+        "--XyZ  \r\nContent-Disposition: form-data; name=\"photo\"; filename=\"a \\\"b\\\".txt\"\r\n",
+        "Content-Type: text/plain\r\n\r\nline one\r\n-XyZ, --XyZ\r\n",
+        "--XyZ--\r\nepilogue",
+    ).as_bytes();
+
+    #[test]
+    fn multipart_boundary_is_taken_from_form_data_only() {
+        assert_eq!(multipart_boundary("multipart/form-data; boundary=XyZ"), Some("XyZ".to_string()));
+        assert_eq!(multipart_boundary("Multipart/Form-Data;charset=utf-8; BOUNDARY=\"a b\""), Some("a b".to_string()));
+        assert_eq!(multipart_boundary("multipart/mixed; boundary=XyZ"), None);
+        assert_eq!(multipart_boundary("multipart/form-data"), None);
+        assert_eq!(multipart_boundary(&format!("multipart/form-data; boundary={}", "x".repeat(71))), None);
+    }
+
+    #[tokio::test]
+    async fn multipart_parts_are_split_however_the_body_arrives() {
+        let mut form = MultipartReader::new(Trickle(FORM), "XyZ");
+
+        let mut part = form.next_part().await.unwrap().unwrap();
+        assert_eq!((part.name.as_str(), part.filename.as_deref(), part.content_type.as_deref()), ("title", None, None));
+        let mut body = String::new();
+        part.read_to_string(&mut body).await.unwrap();
+        assert_eq!(body, "Holiday");
+
+        let mut part = form.next_part().await.unwrap().unwrap();
+        assert_eq!((part.name.as_str(), part.filename.as_deref(), part.content_type.as_deref()), ("photo", Some("a \"b\".txt"), Some("text/plain")));
+        let mut body = String::new();
+        part.read_to_string(&mut body).await.unwrap();
+        // Only a boundary starting a line ends the part
+        assert_eq!(body, "line one\r\n-XyZ, --XyZ");
+
+        assert!(form.next_part().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn unread_parts_are_skipped() {
+        let mut form = MultipartReader::new(FORM, "XyZ");
+        form.next_part().await.unwrap().unwrap();
+        assert_eq!(form.next_part().await.unwrap().unwrap().name, "photo");
+        assert!(form.next_part().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn malformed_multipart_bodies_fail() {
+        for body in [
+            &b"--XyZ\r\nContent-Disposition: attachment; name=a\r\n\r\nx\r\n--XyZ--"[..],
+            b"--XyZ\r\nContent-Disposition: form-data\r\n\r\nx\r\n--XyZ--",
+            b"--XyZ\r\nContent-Type: text/plain\r\n\r\nx\r\n--XyZ--",
+            b"--XyZjunk\r\nContent-Disposition: form-data; name=a\r\n\r\nx\r\n--XyZ--",
+        ] {
+            let result = MultipartReader::new(body, "XyZ").next_part().await.map(|part| part.is_some());
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData, "{}", String::from_utf8_lossy(body));
+        }
+
+        let mut form = MultipartReader::new(&b"--XyZ\r\nContent-Disposition: form-data; name=a\r\n\r\ncut short"[..], "XyZ");
+        let mut part = form.next_part().await.unwrap().unwrap();
+        assert_eq!(part.read_to_end(&mut Vec::new()).await.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn sniffs_signatures_and_html() {
+        assert_eq!(sniff_content_type(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), Some("image/png"));
+        assert_eq!(sniff_content_type(b"\xFF\xD8\xFF\xE0"), Some("image/jpeg"));
+        assert_eq!(sniff_content_type(b"  \n<HTML lang=en>"), Some("text/html"));
+        assert_eq!(sniff_content_type(b"<htm"), None);
+        assert_eq!(sniff_content_type(b"just text"), None);
+    }
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn byte_ranges_are_clamped_to_the_resource() {
+        use ByteRange::*;
+
+        assert_eq!(parse_byte_ranges("bytes=0-99", 1000), Satisfiable(vec![0..100]));
+        assert_eq!(parse_byte_ranges("bytes=900-", 1000), Satisfiable(vec![900..1000]));
+        assert_eq!(parse_byte_ranges("bytes=-100", 1000), Satisfiable(vec![900..1000]));
+        assert_eq!(parse_byte_ranges("bytes=-5000", 1000), Satisfiable(vec![0..1000]));
+        assert_eq!(parse_byte_ranges("bytes=990-2000", 1000), Satisfiable(vec![990..1000]));
+        assert_eq!(parse_byte_ranges("bytes= 0-0 , 5000-6000, -1", 1000), Satisfiable(vec![0..1, 999..1000]));
+    }
+
+    #[test]
+    fn unsatisfiable_and_ignored_ranges() {
+        use ByteRange::*;
+
+        assert_eq!(parse_byte_ranges("bytes=1000-", 1000), Unsatisfiable);
+        assert_eq!(parse_byte_ranges("bytes=-0", 1000), Unsatisfiable);
+        assert_eq!(parse_byte_ranges("bytes=-10", 0), Unsatisfiable);
+        for value in ["items=0-1", "bytes=5-1", "bytes=a-b", "bytes=1", "0-99"] {
+            assert_eq!(parse_byte_ranges(value, 1000), Ignored, "{}", value);
+        }
+        let many = format!("bytes={}", (0..=MAX_RANGES).map(|i| format!("{}-{}", i * 2, i * 2)).collect::<Vec<_>>().join(","));
+        assert_eq!(parse_byte_ranges(&many, 1000), Ignored);
+    }
+
+    #[tokio::test]
+    async fn range_of_a_large_file_reads_only_the_range() {
+        let path = temp_dir("ranged-file").join("large.bin");
+        let mut file = File::create(&path).unwrap();
+        // Sparse, so the 2 GB cost nothing but the marker
+        file.set_len(2 << 30).unwrap();
+        file.seek(SeekFrom::Start(1_000_000_000)).unwrap();
+        file.write_all(b"marker").unwrap();
+
+        let content = RangedFileContent::new(path, DEFAULT_CONTENT_TYPE.to_string(), 1_000_000_000..1_000_001_001);
+        let mut reader = CountingReader::new(content.content().unwrap());
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).await.unwrap();
+
+        assert_eq!(content.content_length(), Some(1001));
+        assert_eq!(reader.count(), 1001);
+        assert_eq!(read.len(), 1001);
+        assert!(read.starts_with(b"marker"));
+    }
+
+    #[test]
+    fn file_errors_keep_not_found_and_permission_denied_apart() {
+        let missing = FileContent::open(temp_dir("file-errors").join("missing.txt"));
+        assert!(matches!(missing, Err(FileError::NotFound)));
+
+        let denied = FileError::from(std::io::Error::from(ErrorKind::PermissionDenied));
+        assert!(matches!(denied, FileError::PermissionDenied));
+        let other = FileError::from(std::io::Error::from(ErrorKind::InvalidData));
+        assert!(matches!(other, FileError::Other(_)));
+    }
+
+    #[test]
+    fn ascii_names_are_quoted_as_they_are() {
+        assert_eq!(content_disposition("report.pdf"), "attachment; filename=\"report.pdf\"");
+        assert_eq!(content_disposition("my \"best\" \\ notes.txt"), "attachment; filename=\"my \\\"best\\\" \\\\ notes.txt\"");
+    }
+
+    #[test]
+    fn other_names_also_get_a_utf8_filename_star() {
+        assert_eq!(
+            content_disposition("résumé 2024.pdf"),
+            "attachment; filename=\"r_sum_ 2024.pdf\"; filename*=UTF-8''r%C3%A9sum%C3%A9%202024.pdf",
+        );
+        assert_eq!(
+            content_disposition("日本.txt"),
+            "attachment; filename=\"__.txt\"; filename*=UTF-8''%E6%97%A5%E6%9C%AC.txt",
+        );
+        // Control characters can't go into a quoted string
+        assert_eq!(content_disposition("a\tb"), "attachment; filename=\"a_b\"; filename*=UTF-8''a%09b");
+    }
+}
@@ -0,0 +1,367 @@
+use std::future::poll_fn;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use h2::{RecvStream, SendStream};
+use h2::server::SendResponse;
+use http::{Request, Response};
+use http::request::Parts;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, BufReader, BufWriter, ReadBuf};
+use tracing::{debug, error};
+use crate::{log_request, respond, server_header, ConnectionReader, ConnectionWriter, HttpMethod, HttpVersion, RequestContext, RequestHead, CONFIG};
+use crate::counting::CountingReader;
+use crate::headers::HeaderMap;
+use crate::http::{http_date, HttpResponse};
+use crate::tls::CertificateSubject;
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+pub const MAX_DATA_CHUNK: usize = 16 * 1024;
+
+/// Response headers that are specific to an HTTP/1.1 connection and forbidden in HTTP/2.
+const CONNECTION_HEADERS: &[&str] = &["Connection", "Keep-Alive", "Proxy-Connection", "Transfer-Encoding", "Upgrade"];
+
+/// Reads just enough of a new connection to tell whether it starts with the HTTP/2 connection
+/// preface, and hands back the stream with whatever was read put back in front of it.
+pub async fn detect_preface<S: AsyncRead + Unpin>(mut stream: S) -> io::Result<(bool, Rewind<S>)> {
+    let mut prefix = vec![0; PREFACE.len()];
+    let mut len = 0;
+    while len < PREFACE.len() && PREFACE.starts_with(&prefix[..len]) {
+        let read = stream.read(&mut prefix[len..]).await?;
+        if read == 0 {
+            break;
+        }
+        len += read;
+    }
+
+    prefix.truncate(len);
+    Ok((prefix == PREFACE, Rewind { prefix, pos: 0, inner: stream }))
+}
+
+/// Replays bytes that were already read from `inner` before continuing with `inner` itself.
+pub struct Rewind<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if self.pos < self.prefix.len() {
+            let len = (self.prefix.len() - self.pos).min(buf.remaining());
+            buf.put_slice(&self.prefix[self.pos..self.pos + len]);
+            self.pos += len;
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Serves an HTTP/2 connection, handling each stream as its own request on a separate task.
+pub async fn serve<S>(addr: SocketAddr, stream: S, client_cert_subject: Option<CertificateSubject>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    debug!("Serving HTTP/2 to {}", addr);
+    let max_header_size = CONFIG.read().await.max_header_size;
+    let mut connection = h2::server::Builder::new()
+        .max_header_list_size(u32::try_from(max_header_size).unwrap_or(u32::MAX))
+        .handshake(stream)
+        .await?;
+    while let Some(request) = connection.accept().await {
+        let (request, sender) = request?;
+        let client_cert_subject = client_cert_subject.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_stream(addr, request, sender, client_cert_subject).await {
+                error!("Error handling HTTP/2 stream from {}: {}", addr, e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn handle_stream(
+    addr: SocketAddr,
+    request: Request<RecvStream>,
+    mut sender: SendResponse<Bytes>,
+    client_cert_subject: Option<CertificateSubject>,
+) -> Result<()> {
+    let (parts, body) = request.into_parts();
+    let target = parts.uri.path_and_query().map_or("/", |path| path.as_str()).to_string();
+    let head = HttpMethod::try_from(parts.method.as_str())
+        .and_then(|method| RequestHead::new(0, method, target, HttpVersion::Http2, request_headers(&parts)));
+    let head = match head {
+        Ok(head) => head,
+        Err(e) => {
+            send_response(&mut sender, e.into()).await?;
+            return Ok(());
+        }
+    };
+    let reader: ConnectionReader = CountingReader::new(BufReader::new(Box::new(RecvBody { stream: body, chunk: Bytes::new() })));
+    // Responses go out through `sender` as HTTP/2 frames, never as raw bytes
+    let writer: ConnectionWriter = BufWriter::new(Box::new(tokio::io::sink()));
+    let mut ctx = RequestContext::new(reader, writer, head, addr, client_cert_subject);
+    debug!("{} '{}' {:?}", ctx.method, ctx.path, ctx.headers);
+
+    let response = respond(&mut ctx).await?;
+    let status = response.status();
+    let bytes_written = send_response(&mut sender, response).await?;
+
+    let config = CONFIG.read().await;
+    log_request(&ctx, status, bytes_written, config.access_log_format.as_ref());
+    Ok(())
+}
+
+/// Translates the pseudo and regular headers into the same map HTTP/1.1 requests produce.
+pub fn request_headers(parts: &Parts) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Some(authority) = parts.uri.authority() {
+        headers.insert("Host", authority.to_string());
+    }
+
+    for (name, value) in &parts.headers {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+
+        let name = canonical_header_name(name.as_str());
+        // HTTP/2 allows splitting cookies over several fields, which have to be put back together
+        match headers.get(&name) {
+            Some(cookie) if name == "Cookie" => headers.insert(&name, format!("{}; {}", cookie, value)),
+            _ => headers.append(&name, value.to_string()),
+        }
+    }
+
+    headers
+}
+
+/// Turns a lowercase HTTP/2 header name such as `user-agent` into `User-Agent`.
+fn canonical_header_name(name: &str) -> String {
+    name.split('-')
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Builds the response head for HTTP/2 and HTTP/3, leaving out HTTP/1.1 connection headers.
+pub async fn response_head(response: &HttpResponse) -> Result<Response<()>> {
+    let mut builder = Response::builder().status(response.status() as u16);
+    if !response.headers().contains_key("Date") {
+        builder = builder.header("date", http_date());
+    }
+    if let Some(server) = server_header(response).await {
+        builder = builder.header("server", server);
+    }
+    for (name, value) in response.sent_headers() {
+        if !CONNECTION_HEADERS.iter().any(|header| name.eq_ignore_ascii_case(header)) {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+    }
+
+    if let Some(content_type) = response.content_type() {
+        builder = builder.header("content-type", content_type);
+    }
+    if let Some(content) = response.content().filter(|_| response.status().permits_body()) {
+        if let Some(content_length) = content.content_length() {
+            builder = builder.header("content-length", content_length);
+        }
+    }
+
+    Ok(builder.body(())?)
+}
+
+async fn send_response(sender: &mut SendResponse<Bytes>, response: HttpResponse) -> Result<u64> {
+    let content = response.body().filter(|content| content.content_length() != Some(0));
+    let mut stream = sender.send_response(response_head(&response).await?, content.is_none())?;
+    match content {
+        Some(content) => send_body(&mut stream, content.content()?).await,
+        None => Ok(0),
+    }
+}
+
+/// Sends the body as DATA frames, waiting for the peer's flow control window as needed.
+async fn send_body<R: AsyncRead + Unpin>(stream: &mut SendStream<Bytes>, mut reader: R) -> Result<u64> {
+    let mut written = 0;
+    let mut buf = vec![0; MAX_DATA_CHUNK];
+    loop {
+        let len = reader.read(&mut buf).await?;
+        if len == 0 {
+            stream.send_data(Bytes::new(), true)?;
+            return Ok(written);
+        }
+
+        let mut data = Bytes::copy_from_slice(&buf[..len]);
+        while !data.is_empty() {
+            stream.reserve_capacity(data.len());
+            let capacity = poll_fn(|cx| stream.poll_capacity(cx)).await
+                .ok_or_else(|| anyhow!("HTTP/2 stream was reset by the peer"))??;
+
+            let chunk = data.split_to(capacity.min(data.len()));
+            written += chunk.len() as u64;
+            stream.send_data(chunk, false)?;
+        }
+    }
+}
+
+/// Exposes the DATA frames of a request stream as a plain reader.
+struct RecvBody {
+    stream: RecvStream,
+    chunk: Bytes,
+}
+
+impl AsyncRead for RecvBody {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        while self.chunk.is_empty() {
+            match ready!(self.stream.poll_data(cx)) {
+                Some(Ok(data)) => {
+                    let _ = self.stream.flow_control().release_capacity(data.len());
+                    self.chunk = data;
+                }
+                Some(Err(e)) => return Poll::Ready(Err(io::Error::other(e))),
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let len = self.chunk.len().min(buf.remaining());
+        let data = self.chunk.split_to(len);
+        buf.put_slice(&data);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use crate::http::{HttpStatus, PlainTextContent};
+    use crate::handle_connection;
+    use crate::testing::{configure, remote_addr};
+    use super::*;
+
+    #[test]
+    fn request_headers_take_the_authority_and_canonical_names() {
+        let (parts, _) = Request::builder()
+            .uri("https://example.com:8443/files/a.txt?x=1")
+            .header("user-agent", "h3-client")
+            .header("cookie", "a=1")
+            .header("accept", "text/html")
+            .header("cookie", "b=2")
+            .header("x-forwarded-for", "10.0.0.1")
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        let headers = request_headers(&parts);
+        let fields: Vec<_> = headers.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect();
+        assert_eq!(fields, [
+            ("Host", "example.com:8443"),
+            ("User-Agent", "h3-client"),
+            ("Cookie", "a=1; b=2"),
+            ("Accept", "text/html"),
+            ("X-Forwarded-For", "10.0.0.1"),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn response_head_leaves_out_connection_headers() {
+        let _config = configure(&["--server-header", "test/1"]).await;
+        let response = HttpResponse::new(HttpStatus::Ok)
+            .with_header("Connection", "keep-alive".to_string())
+            .with_header("Keep-Alive", "timeout=5".to_string())
+            .with_header("Transfer-Encoding", "chunked".to_string())
+            .with_header("Upgrade", "websocket".to_string())
+            .with_header("Cache-Control", "no-cache".to_string())
+            .with_content(PlainTextContent::new("hello".to_string()));
+
+        let head = response_head(&response).await.unwrap();
+        assert_eq!(head.status(), 200);
+        for name in ["connection", "keep-alive", "transfer-encoding", "upgrade"] {
+            assert!(!head.headers().contains_key(name), "{}", name);
+        }
+        let header = |name: &str| head.headers().get(name).map(|value| value.to_str().unwrap());
+        assert_eq!(header("cache-control"), Some("no-cache"));
+        assert_eq!(header("server"), Some("test/1"));
+        assert_eq!(header("content-type"), Some("text/plain"));
+        assert_eq!(header("content-length"), Some("5"));
+        assert!(header("date").is_some_and(|date| date.ends_with(" GMT")));
+    }
+
+    #[tokio::test]
+    async fn bodiless_statuses_get_no_content_length() {
+        let _config = configure(&[]).await;
+        let response = HttpResponse::new(HttpStatus::NotModified).with_content(PlainTextContent::new("stale".to_string()));
+        let head = response_head(&response).await.unwrap();
+        assert_eq!(head.status(), 304);
+        assert!(!head.headers().contains_key("content-length"));
+    }
+
+    async fn rewound(sent: &[u8]) -> (bool, Vec<u8>) {
+        let (is_http2, mut stream) = detect_preface(sent).await.unwrap();
+        let mut replayed = Vec::new();
+        stream.read_to_end(&mut replayed).await.unwrap();
+        (is_http2, replayed)
+    }
+
+    #[tokio::test]
+    async fn preface_is_detected_and_every_byte_replayed() {
+        let mut sent = PREFACE.to_vec();
+        sent.extend_from_slice(b"\x00\x00\x00\x04\x00\x00\x00\x00\x00");
+        assert_eq!(rewound(&sent).await, (true, sent.clone()));
+
+        // Reading stops at the first byte that doesn't fit the preface
+        let request = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert_eq!(rewound(request).await, (false, request.to_vec()));
+        assert_eq!(rewound(b"PRI * HTTP/1.1\r\n").await, (false, b"PRI * HTTP/1.1\r\n".to_vec()));
+        assert_eq!(rewound(b"PRI").await, (false, b"PRI".to_vec()));
+        assert_eq!(rewound(b"").await, (false, Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn cleartext_preface_is_served_as_http2() {
+        let _config = configure(&[]).await;
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let server = tokio::spawn(handle_connection(remote_addr(), server, None));
+
+        let (mut sender, connection) = h2::client::handshake(client).await.unwrap();
+        let connection = tokio::spawn(connection);
+        let request = Request::get("http://localhost/echo/multiplexed").body(()).unwrap();
+        let (response, _) = sender.send_request(request, true).unwrap();
+        let response = response.await.unwrap();
+        assert_eq!(response.status(), 200);
+
+        let mut body = response.into_body();
+        let mut received = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.unwrap();
+            body.flow_control().release_capacity(chunk.len()).unwrap();
+            received.extend_from_slice(&chunk);
+        }
+        assert_eq!(received, b"multiplexed");
+
+        connection.abort();
+        server.abort();
+    }
+}
@@ -0,0 +1,155 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use anyhow::{Context as _, Result};
+use bytes::{Buf, Bytes};
+use h3::error::ConnectionError;
+use h3::server::{RequestResolver, RequestStream};
+use quinn::crypto::rustls::QuicServerConfig;
+use tokio::io::{AsyncRead, AsyncReadExt, BufReader, BufWriter, ReadBuf};
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tracing::{debug, error, info};
+use crate::{log_request, respond, tls, ConnectionReader, ConnectionWriter, HttpMethod, HttpVersion, RequestContext, RequestHead, CONFIG};
+use crate::counting::CountingReader;
+use crate::http::HttpResponse;
+use crate::http2::{request_headers, response_head, MAX_DATA_CHUNK};
+use crate::tls::CertificateSubject;
+
+type Connection = h3_quinn::Connection;
+type SendStream = RequestStream<h3_quinn::SendStream<Bytes>, Bytes>;
+type RecvStream = RequestStream<h3_quinn::RecvStream, Bytes>;
+
+/// Binds the QUIC endpoint for `--http3-port` with the same certificate as the TCP listener.
+pub async fn bind(port: u16) -> Result<quinn::Endpoint> {
+    let mut tls_config = tls::server_config().await?
+        .context("--http3-port requires --tls-cert and --tls-key")?;
+    tls_config.alpn_protocols = vec![b"h3".to_vec()];
+
+    let server_config = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls_config)?));
+    let endpoint = quinn::Endpoint::server(server_config, SocketAddr::from(([127, 0, 0, 1], port)))
+        .with_context(|| format!("Failed to bind UDP port {} for HTTP/3", port))?;
+    Ok(endpoint)
+}
+
+pub async fn serve(endpoint: quinn::Endpoint) {
+    if let Ok(addr) = endpoint.local_addr() {
+        info!("Serving HTTP/3 on {}", addr);
+    }
+
+    while let Some(incoming) = endpoint.accept().await {
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(incoming).await {
+                error!("Error handling HTTP/3 connection: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(incoming: quinn::Incoming) -> Result<()> {
+    let connection = incoming.await?;
+    let addr = connection.remote_address();
+    let client_cert_subject = connection.peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok())
+        .and_then(|certs| tls::certificate_subject(&certs));
+    debug!("Accepted HTTP/3 connection from {}", addr);
+
+    let mut connection = h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(connection)).await?;
+    loop {
+        let resolver = match connection.accept().await {
+            Ok(Some(resolver)) => resolver,
+            Ok(None) => break,
+            // The peer closing the connection normally or going idle isn't worth reporting
+            Err(e) if e.is_h3_no_error() || matches!(e, ConnectionError::Timeout { .. }) => break,
+            Err(e) => return Err(e.into()),
+        };
+
+        let client_cert_subject = client_cert_subject.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_request(addr, resolver, client_cert_subject).await {
+                error!("Error handling HTTP/3 request from {}: {}", addr, e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn handle_request(
+    addr: SocketAddr,
+    resolver: RequestResolver<Connection, Bytes>,
+    client_cert_subject: Option<CertificateSubject>,
+) -> Result<()> {
+    let (request, stream) = resolver.resolve_request().await?;
+    let (mut send, recv) = stream.split();
+    let (parts, _) = request.into_parts();
+    let target = parts.uri.path_and_query().map_or("/", |path| path.as_str()).to_string();
+    let head = HttpMethod::try_from(parts.method.as_str())
+        .and_then(|method| RequestHead::new(0, method, target, HttpVersion::Http3, request_headers(&parts)));
+    let head = match head {
+        Ok(head) => head,
+        Err(e) => {
+            send_response(&mut send, e.into()).await?;
+            return Ok(());
+        }
+    };
+    let reader: ConnectionReader = CountingReader::new(BufReader::new(Box::new(RecvBody { stream: recv, chunk: Bytes::new() })));
+    // Responses go out through `send` as HTTP/3 frames, never as raw bytes
+    let writer: ConnectionWriter = BufWriter::new(Box::new(tokio::io::sink()));
+    let mut ctx = RequestContext::new(reader, writer, head, addr, client_cert_subject);
+    debug!("{} '{}' {:?}", ctx.method, ctx.path, ctx.headers);
+
+    let response = respond(&mut ctx).await?;
+    let status = response.status();
+    let bytes_written = send_response(&mut send, response).await?;
+
+    let config = CONFIG.read().await;
+    log_request(&ctx, status, bytes_written, config.access_log_format.as_ref());
+    Ok(())
+}
+
+async fn send_response(send: &mut SendStream, response: HttpResponse) -> Result<u64> {
+    send.send_response(response_head(&response).await?).await?;
+
+    let mut written = 0;
+    if let Some(content) = response.body().filter(|content| content.content_length() != Some(0)) {
+        let mut reader = content.content()?;
+        let mut buf = vec![0; MAX_DATA_CHUNK];
+        loop {
+            let len = reader.read(&mut buf).await?;
+            if len == 0 {
+                break;
+            }
+
+            send.send_data(Bytes::copy_from_slice(&buf[..len])).await?;
+            written += len as u64;
+        }
+    }
+
+    send.finish().await?;
+    Ok(written)
+}
+
+/// Exposes the DATA frames of a request stream as a plain reader.
+struct RecvBody {
+    stream: RecvStream,
+    chunk: Bytes,
+}
+
+impl AsyncRead for RecvBody {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        while self.chunk.is_empty() {
+            match ready!(self.stream.poll_recv_data(cx)) {
+                Ok(Some(mut data)) => self.chunk = data.copy_to_bytes(data.remaining()),
+                Ok(None) => return Poll::Ready(Ok(())),
+                Err(e) => return Poll::Ready(Err(io::Error::other(e))),
+            }
+        }
+
+        let len = self.chunk.len().min(buf.remaining());
+        let data = self.chunk.split_to(len);
+        buf.put_slice(&data);
+        Poll::Ready(Ok(()))
+    }
+}
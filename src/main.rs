@@ -1,286 +1,3124 @@
+mod accept;
+mod access_log;
+mod acme;
+mod auth;
+mod autoindex;
+mod body;
+mod compression;
+mod conditional;
+mod cookie;
+mod cors;
+mod counting;
+mod error;
+mod extract;
+mod headers;
 mod http;
+mod http2;
+mod http3;
+mod middleware;
+mod mime;
+mod mount;
+mod parser;
+mod redirect;
+mod router;
+mod safe_path;
+mod session;
+mod sse;
+#[cfg(test)]
+mod testing;
+mod timeout;
+mod tls;
+mod vhost;
+mod websocket;
 
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
+use std::future::Future;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use anyhow::{bail, Context, Result};
-use clap::Parser;
+use std::time::{Duration, Instant, SystemTime};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{ArgAction, Parser};
+use itertools::Itertools;
 use once_cell::sync::Lazy;
+use serde::de::DeserializeOwned;
 use tokio::fs::File;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::sync::RwLock;
-use crate::http::{FileContent, HttpResponse, HttpStatus, PlainTextContent};
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn, Level};
+use crate::access_log::{AccessLogEntry, AccessLogFormat};
+use crate::auth::AuthRule;
+use crate::body::{write_chunked, BodyFraming, ChunkedReader, LengthReader, LimitedReader};
+use crate::conditional::{check_write_preconditions, if_range_matches};
+use crate::counting::CountingReader;
+use crate::error::{HttpError, HttpResult};
+use crate::headers::HeaderMap;
+use crate::http::{escape_html, http_date, multipart_boundary, parse_byte_ranges, ByteRange, DigestContent, FileContent, FileError, DEFAULT_CONTENT_TYPE, HtmlContent, HttpContent, HttpResponse, HttpStatus, JsonContent, MessageContent, MultipartRangesContent, MultipartReader, PlainTextContent, RangedFileContent};
+use crate::middleware::{Middleware, Next};
+use crate::mime::MimeType;
+use crate::mount::Mount;
+use crate::parser::is_token_char;
+use crate::redirect::StaticRedirect;
+use crate::router::{Route, Router};
+use crate::safe_path::SymlinkPolicy;
+use crate::session::{Session, SESSION_COOKIE};
+use crate::timeout::TimeoutWriter;
+use crate::tls::{CertificateSubject, ClientAuth};
+use crate::vhost::{host_name, VhostRoutes, VirtualHost};
+use crate::websocket::WebSocketHandler;
 
 #[derive(Parser, Debug)]
 struct Args {
     #[arg(short, long, default_value = None)]
     directory: Option<String>,
+
+    /// Only log errors
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Increase logging verbosity (-v for debug, -vv for trace)
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Number of times to retry binding the listener while the address is in use
+    #[arg(long, default_value_t = 0)]
+    bind_retry: u32,
+
+    /// Initial delay in milliseconds between bind retries, doubled after each attempt
+    #[arg(long, default_value_t = 100)]
+    bind_retry_delay: u64,
+
+    /// Content types eligible for response compression, `type/*` matches a whole family
+    #[arg(long, value_delimiter = ',', default_value = "text/*,application/json,application/javascript,image/svg+xml")]
+    compress_types: Vec<String>,
+
+    /// Minimum body size in bytes before a response is compressed
+    #[arg(long, default_value_t = 0)]
+    compress_min_size: usize,
+
+    /// Product sent in the `Server` header of every response, an empty value leaves it out
+    #[arg(long, default_value = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")))]
+    server_header: String,
+
+    /// Answer TRACE requests by echoing the received request (sensitive headers are stripped)
+    #[arg(long)]
+    allow_trace: bool,
+
+    /// PEM certificate chain to serve over TLS
+    #[arg(long)]
+    tls_cert: Option<String>,
+
+    /// PEM private key matching --tls-cert
+    #[arg(long)]
+    tls_key: Option<String>,
+
+    /// PEM bundle of CAs trusted to issue client certificates, enables mutual TLS
+    #[arg(long)]
+    tls_client_ca: Option<String>,
+
+    /// Whether clients must present a certificate when --tls-client-ca is set
+    #[arg(long, value_enum, default_value_t = ClientAuth::Required)]
+    tls_client_auth: ClientAuth,
+
+    /// Seconds to wait for open connections on shutdown before force-closing them
+    #[arg(long, default_value_t = 30)]
+    graceful_timeout: u64,
+
+    /// File served with 200 in place of a 404 for GETs under --spa-prefix (single-page apps)
+    #[arg(long)]
+    spa_fallback: Option<PathBuf>,
+
+    /// Path prefix that --spa-fallback applies to
+    #[arg(long, default_value = "/files/")]
+    spa_prefix: String,
+
+    /// Also serve --spa-fallback for missing paths with a file extension instead of a 404
+    #[arg(long)]
+    spa_fallback_assets: bool,
+
+    /// Port for a plain HTTP listener that redirects every request to HTTPS (requires TLS)
+    #[arg(long)]
+    redirect_http: Option<u16>,
+
+    /// Access log line per request: `common`, `combined`, or a template such as
+    /// `{remote} {method} {path} {status} {bytes} {duration_ms} "{user_agent}"`
+    #[arg(long, value_parser = AccessLogFormat::parse)]
+    access_log_format: Option<AccessLogFormat>,
+
+    /// Largest request body in bytes accepted for uploads, larger ones get a 413
+    #[arg(long, default_value_t = 100 * 1024 * 1024)]
+    max_body_size: u64,
+
+    /// Longest request target in bytes accepted, longer ones get a 414
+    #[arg(long, default_value_t = 8 * 1024)]
+    max_uri_length: usize,
+
+    /// Most header fields accepted in a request, more get a 431
+    #[arg(long, default_value_t = 100)]
+    max_header_count: usize,
+
+    /// Largest header section in bytes accepted in a request, larger ones get a 431
+    #[arg(long, default_value_t = 16 * 1024)]
+    max_header_size: u64,
+
+    /// Content type for files whose type can't be determined
+    #[arg(long, default_value = DEFAULT_CONTENT_TYPE)]
+    default_type: String,
+
+    /// Guess the type of files without an extension from their first bytes
+    #[arg(long)]
+    sniff: bool,
+
+    /// List the entries of directories requested under /files/ that have no --index file,
+    /// instead of answering with a 404
+    #[arg(long)]
+    autoindex: bool,
+
+    /// Files served in place of a directory requested under /files/, the first one found wins
+    #[arg(long = "index", value_name = "FILE", value_delimiter = ',', default_value = "index.html,index.htm")]
+    index_files: Vec<String>,
+
+    /// Serve directories at paths without a trailing / instead of redirecting to one
+    #[arg(long)]
+    no_slash_redirect: bool,
+
+    /// Which symlinks under the files directories are followed: none, only those leading
+    /// somewhere inside the directory, or all of them
+    #[arg(long, value_enum, default_value_t = SymlinkPolicy::SameRootOnly)]
+    symlinks: SymlinkPolicy,
+
+    /// Serve the files in DIR under the URL path PREFIX for GET and HEAD, next to /files/
+    /// (repeatable)
+    #[arg(long = "mount", value_name = "PREFIX=DIR", value_parser = Mount::parse)]
+    mounts: Vec<Mount>,
+
+    /// Serve files ending in .EXT as TYPE, overriding the built-in table (repeatable)
+    #[arg(long = "mime-type", value_name = "EXT=TYPE", value_parser = MimeType::parse)]
+    mime_types: Vec<MimeType>,
+
+    /// Seconds a client may go without accepting any response bytes before it's disconnected
+    #[arg(long, default_value_t = 30)]
+    write_timeout: u64,
+
+    /// Seconds a persistent connection may sit idle waiting for its next request before it's closed
+    #[arg(long, default_value_t = 5)]
+    keep_alive_timeout: u64,
+
+    /// Most requests served over one persistent connection, 0 for no limit
+    #[arg(long, default_value_t = 100)]
+    keep_alive_max: u32,
+
+    /// UDP port for an HTTP/3 (QUIC) listener next to the TCP one (requires TLS)
+    #[arg(long)]
+    http3_port: Option<u16>,
+
+    /// Virtual host as `NAME=DIR[,CERT,KEY]`: requests for NAME are served from DIR, and over
+    /// TLS clients asking for NAME via SNI get CERT instead of --tls-cert (repeatable)
+    #[arg(long = "vhost", value_name = "NAME=DIR[,CERT,KEY]", value_parser = VirtualHost::parse)]
+    vhosts: Vec<VirtualHost>,
+
+    /// Limit virtual host NAME to the routes registered under the given paths, e.g.
+    /// `static.example=/files/` (repeatable); hosts without an entry get every route
+    #[arg(long = "vhost-routes", value_name = "NAME=ROUTE[,ROUTE...]", value_parser = VhostRoutes::parse)]
+    vhost_routes: Vec<VhostRoutes>,
+
+    /// Obtain and renew the TLS certificate for this domain through ACME (repeatable); http-01
+    /// challenges are answered on the --redirect-http listener, port 80 unless given
+    #[arg(long = "acme-domain", value_name = "DOMAIN", conflicts_with_all = ["tls_cert", "tls_key"])]
+    acme_domains: Vec<String>,
+
+    /// Contact address registered with the ACME account
+    #[arg(long)]
+    acme_email: Option<String>,
+
+    /// ACME directory URL, Let's Encrypt's production one by default
+    #[arg(long, default_value = instant_acme::LetsEncrypt::Production.url())]
+    acme_directory: String,
+
+    /// Directory the ACME account, certificate and key are kept in between runs
+    #[arg(long, default_value = "acme-cache")]
+    acme_cache: PathBuf,
+
+    /// Require a user from the htpasswd FILE (bcrypt or {SHA} hashes) for paths starting with PREFIX
+    #[arg(long = "basic-auth", value_name = "PREFIX=FILE", value_parser = AuthRule::parse)]
+    basic_auth: Vec<AuthRule>,
+
+    /// Require a user from the htdigest FILE through Digest authentication for paths starting with PREFIX
+    #[arg(long = "digest-auth", value_name = "PREFIX=FILE", value_parser = AuthRule::parse)]
+    digest_auth: Vec<AuthRule>,
+
+    /// Realm named in authentication challenges
+    #[arg(long, default_value = "Restricted")]
+    auth_realm: String,
+
+    /// Require a valid `Authorization: Bearer` JWT for paths starting with PREFIX
+    #[arg(long = "jwt-auth", value_name = "PREFIX")]
+    jwt_auth: Vec<String>,
+
+    /// Shared secret HS256 bearer tokens are signed with
+    #[arg(long, conflicts_with = "jwt_public_key")]
+    jwt_secret: Option<String>,
+
+    /// PEM file with the RSA public key RS256 bearer tokens are signed with
+    #[arg(long)]
+    jwt_public_key: Option<String>,
+
+    /// Origin allowed to read responses through CORS, or `*` for any
+    #[arg(long = "cors-origin", value_name = "ORIGIN")]
+    cors_origins: Vec<String>,
+
+    /// Seconds browsers may cache a CORS preflight answer
+    #[arg(long, default_value_t = 600)]
+    cors_max_age: u64,
+
+    /// Let allowed origins make credentialed CORS requests
+    #[arg(long)]
+    cors_allow_credentials: bool,
+
+    /// Redirect requests for the path FROM to TO with a 301, or the given 302, 303, 307 or 308
+    #[arg(long = "redirect", value_name = "FROM=TO[,STATUS]", value_parser = StaticRedirect::parse)]
+    redirects: Vec<StaticRedirect>,
+
+    /// Seconds a session is kept after its last use
+    #[arg(long, default_value_t = 1800)]
+    session_ttl: u64,
+}
+
+impl Args {
+    fn log_level(&self) -> Level {
+        if self.quiet {
+            return Level::ERROR;
+        }
+
+        match self.verbose {
+            0 => Level::INFO,
+            1 => Level::DEBUG,
+            _ => Level::TRACE,
+        }
+    }
 }
 
-static CONFIG: Lazy<Arc<RwLock<Args>>> = Lazy::new(|| Arc::new(RwLock::new(Args::parse())));
+static CONFIG: Lazy<Arc<RwLock<Args>>> = Lazy::new(|| {
+    // The test harness's own arguments aren't meant for the server
+    let args = if cfg!(test) { Args::parse_from([env!("CARGO_PKG_NAME")]) } else { Args::parse() };
+    Arc::new(RwLock::new(args))
+});
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let listener: TcpListener = TcpListener::bind("127.0.0.1:4221").await?;
+    let log_level = CONFIG.read().await.log_level();
+    tracing_subscriber::fmt().with_max_level(log_level).init();
+
+    cors::check_config().await?;
+    let listener = bind_listener("127.0.0.1:4221").await?;
+
+    let (tls_configured, redirect_http) = {
+        let config = CONFIG.read().await;
+        let acme = !config.acme_domains.is_empty();
+        (config.tls_cert.is_some() || acme, config.redirect_http.or(acme.then_some(80)))
+    };
+    if let Some(redirect_port) = redirect_http {
+        if !tls_configured {
+            bail!("--redirect-http requires --tls-cert and --tls-key");
+        }
+
+        let redirect_listener = bind_listener(&format!("127.0.0.1:{}", redirect_port)).await?;
+        tokio::spawn(redirect_to_https(redirect_listener, listener.local_addr()?.port()));
+    }
+
+    // The redirect listener has to be up first to answer the http-01 challenges
+    acme::start().await?;
+    auth::init().await?;
+    // Mounts add routes, so one clashing with another route stops the server here
+    Lazy::force(&ROUTER);
+    let tls_acceptor = tls::acceptor().await?;
+
+    let http3_port = CONFIG.read().await.http3_port;
+    if let Some(http3_port) = http3_port {
+        tokio::spawn(http3::serve(http3::bind(http3_port).await?));
+    }
+
+    serve(listener, tls_acceptor, shutdown_signal()).await
+}
+
+/// Accepts connections until `shutdown` completes, then gives the open ones `--graceful-timeout`
+/// to finish before closing them.
+async fn serve(listener: TcpListener, tls_acceptor: Option<TlsAcceptor>, shutdown: impl Future<Output = ()>) -> Result<()> {
+    tokio::pin!(shutdown);
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, addr) = accepted?;
+                connections.spawn(accept_connection(tls_acceptor.clone(), addr, stream));
+            }
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = &mut shutdown => break,
+        }
+    }
+
+    let graceful_timeout = Duration::from_secs(CONFIG.read().await.graceful_timeout);
+    info!("Shutting down, waiting up to {:?} for {} connection(s)", graceful_timeout, connections.len());
+    let drained = tokio::time::timeout(graceful_timeout, async {
+        while connections.join_next().await.is_some() {}
+    }).await;
+
+    if drained.is_err() {
+        warn!("Force-closing {} connection(s) still open after {:?}", connections.len(), graceful_timeout);
+        connections.shutdown().await;
+    }
+
+    Ok(())
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+async fn redirect_to_https(listener: TcpListener, https_port: u16) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Failed to accept redirect connection: {}", e);
+                continue;
+            }
+        };
+
+        tokio::spawn(async move {
+            if let Err(e) = redirect_connection(addr, stream, https_port).await {
+                error!("Error redirecting connection from {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+async fn redirect_connection(addr: SocketAddr, stream: TcpStream, https_port: u16) -> Result<()> {
+    debug!("Accepted redirect connection from {}", addr);
+    let (mut reader, mut writer) = split_connection(stream).await;
+    let head = match read_request_head(&mut reader).await {
+        Ok(Some(head)) => head,
+        Ok(None) => return Ok(()),
+        Err(e) => return send_error(&mut writer, e).await,
+    };
+
+    let mut ctx = RequestContext::new(reader, writer, head, addr, None);
+    let response = match ctx.headers.get("Host") {
+        _ if ctx.path.starts_with(acme::CHALLENGE_PREFIX) => match acme::challenge_response(&ctx.path) {
+            Some(key_authorization) => HttpResponse::new(HttpStatus::Ok).with_content(PlainTextContent::new(key_authorization)),
+            None => HttpResponse::new(HttpStatus::NotFound),
+        },
+        Some(host) => {
+            let host = host_name(host);
+            let authority = match https_port {
+                443 => host.to_string(),
+                port => format!("{}:{}", host, port),
+            };
+
+            HttpResponse::redirect(HttpStatus::MovedPermanently, &format!("https://{}{}", authority, ctx.origin_target()))
+        }
+        None => HttpResponse::new(HttpStatus::BadRequest),
+    };
+
+    ctx.send(response.with_header("Connection", "close".to_string())).await?;
+    Ok(())
+}
+
+async fn accept_connection(tls_acceptor: Option<TlsAcceptor>, addr: SocketAddr, stream: TcpStream) {
+    // A client that stalls the handshake is treated like one idling before its first request
+    let handshake_timeout = Duration::from_secs(CONFIG.read().await.keep_alive_timeout);
+    match tls_acceptor {
+        Some(tls_acceptor) => match tokio::time::timeout(handshake_timeout, tls_acceptor.accept(stream)).await {
+            Ok(Ok(stream)) => {
+                let client_cert_subject = tls::client_cert_subject(&stream);
+                if tls::negotiated_h2(&stream) {
+                    if let Err(e) = http2::serve(addr, stream, client_cert_subject).await {
+                        error!("Error handling HTTP/2 connection from {}: {}", addr, e);
+                    }
+                    return;
+                }
+
+                handle_connection(addr, stream, client_cert_subject).await
+            }
+            Ok(Err(e)) => warn!("TLS handshake with {} failed: {}", addr, e),
+            Err(_) => warn!("TLS handshake with {} timed out", addr),
+        },
+        None => handle_connection(addr, stream, None).await,
+    }
+}
+
+const MAX_BIND_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+async fn bind_listener(addr: &str) -> Result<TcpListener> {
+    let (retries, mut delay) = {
+        let config = CONFIG.read().await;
+        (config.bind_retry, Duration::from_millis(config.bind_retry_delay))
+    };
+
+    let mut attempt = 0;
     loop {
-        let (stream, addr) = listener.accept().await?;
-        tokio::spawn(handle_connection(addr, stream));
+        match TcpListener::bind(addr).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && attempt < retries => {
+                attempt += 1;
+                warn!("{} is in use, retrying in {:?} (attempt {}/{})", addr, delay, attempt, retries);
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_BIND_RETRY_DELAY);
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to bind {}", addr)),
+        }
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum HttpMethod {
     Get,
+    Head,
     Post,
+    Put,
+    Patch,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    /// Any other syntactically valid method, kept as sent
+    Extension(String),
+}
+
+impl HttpMethod {
+    /// HEAD is answered exactly like GET, so handlers only need to know about GET.
+    fn for_dispatch(&self) -> &Self {
+        match self {
+            Self::Head => &Self::Get,
+            method => method,
+        }
+    }
 }
 
 impl Display for HttpMethod {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Get => write!(f, "GET"),
+            Self::Head => write!(f, "HEAD"),
             Self::Post => write!(f, "POST"),
+            Self::Put => write!(f, "PUT"),
+            Self::Patch => write!(f, "PATCH"),
+            Self::Delete => write!(f, "DELETE"),
+            Self::Connect => write!(f, "CONNECT"),
+            Self::Options => write!(f, "OPTIONS"),
+            Self::Trace => write!(f, "TRACE"),
+            Self::Extension(method) => write!(f, "{}", method),
         }
     }
 }
 
 impl TryFrom<&str> for HttpMethod {
-    type Error = anyhow::Error;
+    type Error = HttpError;
 
     fn try_from(value: &str) -> std::result::Result<Self, Self::Error> {
-        if value.eq_ignore_ascii_case("GET") {
-            Ok(HttpMethod::Get)
-        } else if value.eq_ignore_ascii_case("POST") {
-            Ok(HttpMethod::Post)
-        } else {
-            bail!("HttpMethod {value} is not recognized")
-        }
+        Ok(match value.to_ascii_uppercase().as_str() {
+            "GET" => HttpMethod::Get,
+            "HEAD" => HttpMethod::Head,
+            "POST" => HttpMethod::Post,
+            "PUT" => HttpMethod::Put,
+            "PATCH" => HttpMethod::Patch,
+            "DELETE" => HttpMethod::Delete,
+            "CONNECT" => HttpMethod::Connect,
+            "OPTIONS" => HttpMethod::Options,
+            "TRACE" => HttpMethod::Trace,
+            _ if value.bytes().all(is_token_char) => HttpMethod::Extension(value.to_string()),
+            _ => return Err(HttpError::BadRequest(format!("HttpMethod {value} is not a valid token"))),
+        })
     }
 }
 
-async fn read_headers(reader: &mut BufReader<OwnedReadHalf>) -> Result<HashMap<String, String>> {
-    let mut line_buffer = String::new();
-    let mut headers = HashMap::new();
-    loop {
-        reader.read_line(&mut line_buffer).await?;
-        {
-            let line_buffer = line_buffer.trim_end();
-            if line_buffer.is_empty() {
-                break;
-            }
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HttpVersion {
+    Http10,
+    Http11,
+    Http2,
+    Http3,
+}
 
-            let mut parts = line_buffer.splitn(2, ':');
-            let key = parts.next().unwrap().trim().to_string();
-            let value = parts.next().unwrap().trim().to_string();
-            headers.insert(key, value);
+impl Display for HttpVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Http10 => write!(f, "HTTP/1.0"),
+            Self::Http11 => write!(f, "HTTP/1.1"),
+            Self::Http2 => write!(f, "HTTP/2.0"),
+            Self::Http3 => write!(f, "HTTP/3.0"),
         }
-        line_buffer.clear();
     }
+}
+
+impl TryFrom<(u8, u8)> for HttpVersion {
+    type Error = HttpError;
 
-    Ok(headers)
+    /// Later 1.x minor versions are compatible with 1.1, other major versions can't be spoken
+    /// over this framing.
+    fn try_from((major, minor): (u8, u8)) -> std::result::Result<Self, Self::Error> {
+        match (major, minor) {
+            (1, 0) => Ok(Self::Http10),
+            (1, _) => Ok(Self::Http11),
+            _ => Err(HttpError::HttpVersionNotSupported),
+        }
+    }
 }
 
-async fn read_line(reader: &mut BufReader<OwnedReadHalf>) -> Result<String> {
-    let mut line = String::new();
-    reader.read_line(&mut line).await?;
-    Ok(line.trim().to_string())
+/// Reads header fields up to the empty line ending them, failing with
+/// `RequestHeaderFieldsTooLarge` once there are more than `max_count` of them or they take up
+/// more than `max_size` bytes.
+async fn read_headers(reader: &mut ConnectionReader, max_count: usize, max_size: u64) -> HttpResult<HeaderMap> {
+    let mut line = Vec::new();
+    let mut headers = HeaderMap::new();
+    let mut remaining = max_size;
+    loop {
+        // Running out before the empty line means the section is too large either way
+        if remaining == 0 {
+            return Err(HttpError::RequestHeaderFieldsTooLarge);
+        }
+        line.clear();
+        let read = (&mut *reader).take(remaining).read_until(b'\n', &mut line).await?;
+        if read == 0 {
+            return Err(HttpError::BadRequest("Connection closed before the end of the headers".to_string()));
+        }
+        remaining -= read as u64;
+        if line == b"\r\n" || line == b"\n" {
+            return Ok(headers);
+        }
+        // A line cut short by the limit must not be parsed as if it had ended there
+        if !line.ends_with(b"\n") {
+            return Err(match remaining {
+                0 => HttpError::RequestHeaderFieldsTooLarge,
+                _ => HttpError::BadRequest("Connection closed before the end of the headers".to_string()),
+            });
+        }
+
+        let (name, value) = parser::header_line(&line)?;
+        if headers.len() == max_count {
+            return Err(HttpError::RequestHeaderFieldsTooLarge);
+        }
+        headers.append(name, value.to_string());
+    }
 }
 
-async fn handle_connection(addr: SocketAddr, stream: TcpStream) {
-    match handle_connection_inner(addr, stream).await {
+async fn handle_connection<S>(addr: SocketAddr, stream: S, client_cert_subject: Option<CertificateSubject>)
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    match handle_connection_inner(addr, stream, client_cert_subject).await {
         Ok(_) => {}
-        Err(e) => eprintln!("Error handling connection from {}: {}", addr, e),
+        Err(e) if e.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == std::io::ErrorKind::TimedOut) => {
+            warn!("Disconnecting slow client {}: {}", addr, e);
+        }
+        Err(e) => error!("Error handling connection from {}: {}", addr, e),
     }
 }
 
-async fn handle_connection_inner(addr: SocketAddr, stream: TcpStream) -> Result<()> {
-    println!("Accepted connection from {}", addr);
-    let (reader, writer) = stream.into_split();
-    let mut reader = BufReader::new(reader);
-    let writer = BufWriter::new(writer);
+async fn handle_connection_inner<S>(addr: SocketAddr, stream: S, client_cert_subject: Option<CertificateSubject>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    debug!("Accepted connection from {}", addr);
+    if let Some(subject) = &client_cert_subject {
+        debug!("Client certificate for {}: {:?}", addr, subject);
+    }
 
-    let request_line = read_line(&mut reader).await?;
-    let headers = read_headers(&mut reader).await?;
+    let (idle_timeout, max_requests) = {
+        let config = CONFIG.read().await;
+        (Duration::from_secs(config.keep_alive_timeout), config.keep_alive_max)
+    };
+    let Ok(preface) = tokio::time::timeout(idle_timeout, http2::detect_preface(stream)).await else {
+        debug!("Closing idle connection from {}", addr);
+        return Ok(());
+    };
+    let (is_http2, stream) = preface?;
+    if is_http2 {
+        return http2::serve(addr, stream, client_cert_subject).await;
+    }
 
-    let (method, path, http_version) = {
-        let request_parts: Vec<_> = request_line.split_ascii_whitespace().collect();
-        let method = HttpMethod::try_from(*request_parts.get(0).context("Missing method")?)?;
-        let path = (*request_parts.get(1).context("Missing path")?).to_string();
-        let http_version = match request_parts.get(2) {
-            Some(ver) => (*ver).to_string(),
-            _ => "HTTP/1.1".to_string()
+    let (mut reader, mut writer) = split_connection(stream).await;
+    let mut served = 0;
+    loop {
+        let head = match tokio::time::timeout(idle_timeout, read_request_head(&mut reader)).await {
+            Ok(Ok(Some(head))) => head,
+            Ok(Ok(None)) => return Ok(()),
+            Ok(Err(e)) => return send_error(&mut writer, e).await,
+            Err(_) => {
+                debug!("Closing idle connection from {}", addr);
+                return Ok(());
+            }
         };
 
-        (method, path, (*http_version).to_string())
-    };
-
-    let ctx = RequestContext {
-        reader,
-        writer,
-        method,
-        path,
-        http_version,
-        headers,
-    };
+        let keep_alive = KeepAlive {
+            timeout: idle_timeout,
+            remaining: (max_requests > 0).then(|| max_requests.saturating_sub(served + 1)),
+        };
+        served += 1;
+        let ctx = RequestContext::new(reader, writer, head, addr, client_cert_subject.clone());
+        match process_request(ctx, keep_alive).await? {
+            Some(ctx) => (reader, writer) = (ctx.reader, ctx.writer),
+            None => return Ok(()),
+        }
+    }
+}
 
-    process_request(ctx).await?;
-    Ok(())
+async fn split_connection<S>(stream: S) -> (ConnectionReader, ConnectionWriter)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let write_timeout = Duration::from_secs(CONFIG.read().await.write_timeout);
+    let (reader, writer) = tokio::io::split(stream);
+    let reader: ConnectionReader = CountingReader::new(BufReader::new(Box::new(reader)));
+    let writer: ConnectionWriter = BufWriter::new(Box::new(TimeoutWriter::new(writer, write_timeout)));
+    (reader, writer)
 }
 
-#[allow(unused)]
-pub struct RequestContext {
-    pub reader: BufReader<OwnedReadHalf>,
-    pub writer: BufWriter<OwnedWriteHalf>,
+pub struct RequestHead {
+    /// Position of the request's first byte in the connection's input
+    pub offset: u64,
     pub method: HttpMethod,
+    /// The request target as sent, before decoding
+    pub target: String,
+    /// The scheme of an absolute-form target such as `http://example.com/path`
+    pub scheme: Option<String>,
     pub path: String,
-    pub http_version: String,
-    pub headers: HashMap<String, String>,
+    pub query: Option<String>,
+    pub http_version: HttpVersion,
+    pub headers: HeaderMap,
 }
 
-impl RequestContext {
-    pub async fn send(&mut self, response: HttpResponse) -> Result<()> {
-        self.writer.write(format!("HTTP/1.1 {} ", response.status() as u16).as_bytes()).await?;
-        if let Some(message) = response.status_message() {
-            self.writer.write(message.as_bytes()).await?;
-        } else {
-            self.writer.write(format!("{:?}", response.status()).as_bytes()).await?;
+impl RequestHead {
+    /// Splits the query off `target` and percent-decodes its path. The authority of an
+    /// absolute-form target replaces the Host field, as RFC 9112 asks.
+    pub fn new(offset: u64, method: HttpMethod, target: String, http_version: HttpVersion, mut headers: HeaderMap) -> HttpResult<Self> {
+        let (scheme, origin) = match split_absolute_form(&target) {
+            Some((scheme, _, _)) if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") => {
+                return Err(HttpError::BadRequest(format!("Unsupported scheme in {}", target)));
+            }
+            Some((scheme, authority, origin)) => {
+                headers.insert("Host", authority.to_string());
+                (Some(scheme.to_ascii_lowercase()), origin)
+            }
+            None => (None, target.as_str()),
+        };
+
+        let (path, query) = match origin.split_once('?') {
+            Some((path, query)) => (path, Some(query.to_string())),
+            None => (origin, None),
+        };
+        let path = match path {
+            // An absolute-form `OPTIONS` without a path asks about the server as a whole
+            "" if method == HttpMethod::Options => "*".to_string(),
+            "" => "/".to_string(),
+            path => percent_decode(path)?,
+        };
+        Ok(Self { offset, method, target, scheme, path, query, http_version, headers })
+    }
+}
+
+/// Splits an absolute-form target into its scheme, authority and the rest, which starts with
+/// the path if there is one.
+fn split_absolute_form(target: &str) -> Option<(&str, &str, &str)> {
+    let (scheme, rest) = target.split_once("://")?;
+    if !scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        || !scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c)) {
+        return None;
+    }
+
+    let end = rest.find(['/', '?']).unwrap_or(rest.len());
+    Some((scheme, &rest[..end], &rest[end..]))
+}
+
+/// Decodes the RFC 3986 percent-escapes in a request path, which must leave valid UTF-8.
+fn percent_decode(path: &str) -> HttpResult<String> {
+    let invalid = || HttpError::BadRequest(format!("Invalid percent-encoding in {}", path));
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut bytes = path.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'%' {
+            decoded.push(b);
+            continue;
+        }
+
+        let hex = [bytes.next().ok_or_else(invalid)?, bytes.next().ok_or_else(invalid)?];
+        if !hex.iter().all(u8::is_ascii_hexdigit) {
+            return Err(invalid());
         }
-        self.writer.write(b"\r\n").await?;
+        let hex = std::str::from_utf8(&hex).map_err(|_| invalid())?;
+        decoded.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+    }
+
+    String::from_utf8(decoded).map_err(|_| invalid())
+}
+
+/// Room on the request line for the method, version and separators next to a target of
+/// `--max-uri-length`.
+const MAX_REQUEST_LINE_OVERHEAD: usize = 64;
+
+/// Reads the next request line and headers, or returns `None` if the client closed the
+/// connection before sending another request.
+async fn read_request_head(reader: &mut ConnectionReader) -> HttpResult<Option<RequestHead>> {
+    let (max_uri_length, max_header_count, max_header_size) = {
+        let config = CONFIG.read().await;
+        (config.max_uri_length, config.max_header_count, config.max_header_size)
+    };
 
-        for header in response.headers() {
-            self.writer.write(format!("{}: {}\r\n", header.0, header.1).as_bytes()).await?;
+    let offset = reader.count();
+    let mut request_line = Vec::new();
+    // Empty lines ahead of a request are ignored, as RFC 9112 allows
+    while request_line.trim_ascii().is_empty() {
+        request_line.clear();
+        let max_line = (max_uri_length + MAX_REQUEST_LINE_OVERHEAD) as u64;
+        if (&mut *reader).take(max_line).read_until(b'\n', &mut request_line).await? == 0 {
+            return Ok(None);
         }
-        if let Some(content) = response.content() {
-            self.writer.write(format!("Content-Type: {}\r\n", content.content_type()).as_bytes()).await?;
-            self.writer.write(format!("Content-Length: {}\r\n", content.content_length()).as_bytes()).await?;
+        if !request_line.ends_with(b"\n") && request_line.len() as u64 == max_line {
+            return Err(HttpError::UriTooLong);
         }
-        self.writer.write(b"\r\n").await?;
+    }
+
+    let request_line = parser::request_line(&request_line)?;
+    let method = HttpMethod::try_from(request_line.method)?;
+    if request_line.target.len() > max_uri_length {
+        return Err(HttpError::UriTooLong);
+    }
+    let target = request_line.target.to_string();
+    // A request line without a version is HTTP/0.9, which has neither headers nor a status line
+    let http_version = HttpVersion::try_from(request_line.version.ok_or(HttpError::HttpVersionNotSupported)?)?;
+
+    let mut headers = read_headers(reader, max_header_count, max_header_size).await?;
+    check_framing(&mut headers)?;
 
-        if let Some(content) = response.content().as_mut() {
-            let mut content_reader = content.content()?;
+    Ok(Some(RequestHead::new(offset, method, target, http_version, headers)?))
+}
+
+/// Rejects requests whose body length a proxy in front could read differently: ones with both
+/// `Transfer-Encoding` and `Content-Length`, and ones with disagreeing `Content-Length` values.
+/// Repeats of a single length are collapsed into one field.
+fn check_framing(headers: &mut HeaderMap) -> HttpResult<()> {
+    if !headers.contains_key("Content-Length") {
+        return Ok(());
+    }
+    if headers.contains_key("Transfer-Encoding") {
+        return Err(HttpError::BadRequest("Both Transfer-Encoding and Content-Length".to_string()));
+    }
 
-            _ = tokio::io::copy(&mut content_reader, &mut self.writer).await?;
+    let lengths: Vec<_> = headers.get_all("Content-Length")
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .unique()
+        .collect();
+    match lengths.as_slice() {
+        [length] if !length.is_empty() && length.bytes().all(|b| b.is_ascii_digit()) => {
+            let length = length.to_string();
+            headers.insert("Content-Length", length);
+            Ok(())
         }
+        _ => Err(HttpError::BadRequest(format!("Conflicting Content-Length {}", lengths.join(", ")))),
+    }
+}
 
-        self.writer.flush().await?;
-        Ok(())
+async fn send_error(writer: &mut ConnectionWriter, e: HttpError) -> Result<()> {
+    log_error(&e);
+    let response = HttpResponse::from(e).with_header("Connection", "close".to_string());
+    write_response(writer, response, true).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+fn log_error(e: &HttpError) {
+    match e {
+        HttpError::Internal(_) => error!("{}", e),
+        _ => debug!("{}", e),
     }
 }
 
-async fn process_request(mut ctx: RequestContext) -> Result<()> {
-    println!("{} '{}'", ctx.method, ctx.path);
+/// Largest unread request body that is skipped to keep a connection alive rather than closing it
+const MAX_DISCARDED_BODY: u64 = 64 * 1024;
 
-    let response = match ctx.method {
-        HttpMethod::Get => {
-            match ctx.path.as_str() {
-                "/" => index(&mut ctx).await?,
+pub type ConnectionReader = CountingReader<BufReader<Box<dyn AsyncRead + Send + Unpin>>>;
+pub type ConnectionWriter = BufWriter<Box<dyn AsyncWrite + Send + Unpin>>;
 
-                "/user-agent" => user_agent(&mut ctx).await?,
+#[allow(unused)]
+pub struct RequestContext {
+    pub reader: ConnectionReader,
+    pub writer: ConnectionWriter,
+    pub method: HttpMethod,
+    /// The request target as sent, before decoding
+    pub target: String,
+    /// The scheme of an absolute-form request target
+    pub scheme: Option<String>,
+    /// The decoded path from the request target
+    pub path: String,
+    /// The raw query string from the request target, without its `?`
+    pub query: Option<String>,
+    pub http_version: HttpVersion,
+    pub headers: HeaderMap,
+    pub remote_addr: SocketAddr,
+    pub started: Instant,
+    pub client_cert_subject: Option<CertificateSubject>,
+    /// Trailer fields of a chunked body, filled in once the body has been read to the end
+    pub trailers: HeaderMap,
+    /// The user that authenticated for a `--basic-auth` protected path
+    pub user: Option<String>,
+    /// The claims of the bearer token that authenticated for a `--jwt-auth` protected path
+    pub claims: Option<serde_json::Map<String, serde_json::Value>>,
+    /// Parameters captured from the path by the route's pattern, such as `path` for `/files/{*path}`
+    pub params: Vec<(String, String)>,
+    /// Loaded on first use through `session()`
+    session: Option<Session>,
+    /// Set by a handler accepting a WebSocket upgrade, to take the connection over once the
+    /// 101 has been sent
+    upgrade: Option<WebSocketHandler>,
+    request_start: u64,
+    body_start: u64,
+    /// The client sent `Expect: 100-continue` and is holding the body back until it's asked for
+    awaiting_continue: bool,
+}
 
-                path => {
-                    if path.starts_with("/echo/") {
-                        echo(&mut ctx).await?
-                    } else if path.starts_with("/files/") {
-                        files(&mut ctx).await?
-                    } else {
-                        HttpResponse::new(HttpStatus::NotFound)
-                    }
-                }
-            }
+impl RequestContext {
+    pub fn new(
+        reader: ConnectionReader,
+        writer: ConnectionWriter,
+        head: RequestHead,
+        remote_addr: SocketAddr,
+        client_cert_subject: Option<CertificateSubject>,
+    ) -> Self {
+        let body_start = reader.count();
+        let awaiting_continue = head.http_version == HttpVersion::Http11
+            && head.headers.get("Expect").is_some_and(|expect| expect.trim().eq_ignore_ascii_case("100-continue"));
+        Self {
+            reader,
+            writer,
+            method: head.method,
+            target: head.target,
+            scheme: head.scheme,
+            path: head.path,
+            query: head.query,
+            http_version: head.http_version,
+            headers: head.headers,
+            remote_addr,
+            started: Instant::now(),
+            client_cert_subject,
+            trailers: HeaderMap::new(),
+            user: None,
+            claims: None,
+            params: Vec::new(),
+            session: None,
+            upgrade: None,
+            request_start: head.offset,
+            body_start,
+            awaiting_continue,
         }
+    }
 
-        HttpMethod::Post => {
-            match ctx.path.as_str() {
-                path => {
-                    if path.starts_with("/files/") {
-                        files_post(&mut ctx).await?
-                    } else {
-                        HttpResponse::new(HttpStatus::NotFound)
-                    }
-                }
-            }
+    /// The request target in origin-form, with the scheme and authority of an absolute-form
+    /// target dropped.
+    pub fn origin_target(&self) -> &str {
+        match split_absolute_form(&self.target) {
+            Some((_, _, "")) => "/",
+            Some((_, _, origin)) => origin,
+            None => &self.target,
         }
+    }
 
-        //_ => HttpResponse::new(HttpStatus::BadRequest)
-    };
+    /// The path parameter called `name` captured by the route's pattern.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter().find(|(param, _)| param == name).map(|(_, value)| value.as_str())
+    }
 
-    ctx.send(response).await?;
-    Ok(())
-}
+    /// The decoded query parameters in the order they were given, keeping repeated names.
+    pub fn query(&self) -> Vec<(String, String)> {
+        self.query.as_deref()
+            .and_then(|query| serde_urlencoded::from_str(query).ok())
+            .unwrap_or_default()
+    }
 
-pub async fn index(_ctx: &mut RequestContext) -> Result<HttpResponse> {
-    Ok(HttpResponse::new(HttpStatus::Ok))
-}
+    /// Bytes read from the connection for this request, head and body.
+    pub fn bytes_read(&self) -> u64 {
+        self.reader.count() - self.request_start
+    }
 
-pub async fn echo(ctx: &mut RequestContext) -> Result<HttpResponse> {
-    let remaining = &ctx.path["/echo/".len()..];
-    let content = PlainTextContent::new(remaining.to_string());
-    Ok(HttpResponse::new(HttpStatus::Ok).with_content(content))
-}
+    pub fn body_framing(&self) -> HttpResult<BodyFraming> {
+        if let Some(encoding) = self.headers.get("Transfer-Encoding") {
+            let is_chunked = encoding.rsplit(',').next()
+                .is_some_and(|encoding| encoding.trim().eq_ignore_ascii_case("chunked"));
+            if !is_chunked {
+                return Err(HttpError::BadRequest(format!("Unsupported Transfer-Encoding {}", encoding)));
+            }
 
-pub async fn user_agent(ctx: &mut RequestContext) -> Result<HttpResponse> {
-    let agent = ctx.headers.get("User-Agent").cloned();
-    let response = if let Some(agent) = agent {
-        HttpResponse::new(HttpStatus::Ok)
-            .with_content(PlainTextContent::new(agent))
-    } else {
-        HttpResponse::new(HttpStatus::BadRequest)
-    };
+            return Ok(BodyFraming::Chunked);
+        }
 
-    Ok(response)
-}
+        match self.headers.get("Content-Length") {
+            Some(length) => length.parse::<u64>()
+                .map(BodyFraming::Length)
+                .map_err(|_| HttpError::BadRequest(format!("Invalid Content-Length {}", length))),
+            None if matches!(self.http_version, HttpVersion::Http2 | HttpVersion::Http3) => Ok(BodyFraming::Stream),
+            None => Ok(BodyFraming::None),
+        }
+    }
 
-pub async fn files(ctx: &mut RequestContext) -> Result<HttpResponse> {
-    let file_path = {
-        let config = CONFIG.read().await;
-        if config.directory.is_none() {
-            return Ok(HttpResponse::new(HttpStatus::InternalServerError));
+    /// Returns a reader over the request body that decodes its framing and fails with
+    /// `PayloadTooLarge` once more than `--max-body-size` bytes have been read.
+    pub async fn body_reader(&mut self) -> HttpResult<Box<dyn AsyncRead + Send + Unpin + '_>> {
+        let max_body_size = CONFIG.read().await.max_body_size;
+        let framing = self.body_framing()?;
+        if matches!(framing, BodyFraming::Length(length) if length > max_body_size) {
+            return Err(HttpError::PayloadTooLarge);
         }
 
-        PathBuf::from(config.directory.as_ref().unwrap()).join(&ctx.path["/files/".len()..])
-    };
+        // Only ask for the body once it's certain to be read
+        if self.awaiting_continue {
+            self.writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+            self.writer.flush().await?;
+            self.awaiting_continue = false;
+        }
 
-    let response = if !file_path.exists() {
-        HttpResponse::new(HttpStatus::NotFound)
-    } else {
-        HttpResponse::new(HttpStatus::Ok).with_content(FileContent::new(file_path))
-    };
+        let body: Box<dyn AsyncRead + Send + Unpin + '_> = match framing {
+            BodyFraming::None => Box::new(tokio::io::empty()),
+            BodyFraming::Length(length) => Box::new(LengthReader::new(&mut self.reader, length)),
+            BodyFraming::Chunked => Box::new(ChunkedReader::new(&mut self.reader).with_trailers(&mut self.trailers)),
+            BodyFraming::Stream => Box::new(&mut self.reader),
+        };
 
-    Ok(response)
-}
+        Ok(Box::new(LimitedReader::new(body, max_body_size)))
+    }
 
-pub async fn files_post(ctx: &mut RequestContext) -> Result<HttpResponse> {
-    let dest_path = {
-        let config = CONFIG.read().await;
-        if config.directory.is_none() {
-            return Ok(HttpResponse::new(HttpStatus::InternalServerError));
+    /// Reads an `application/x-www-form-urlencoded` body and decodes it into `T`, such as a
+    /// `HashMap<String, String>` of its fields or a struct deriving `Deserialize`.
+    #[allow(unused)]
+    pub async fn form<T: DeserializeOwned>(&mut self) -> HttpResult<T> {
+        let is_form = self.headers.get("Content-Type")
+            .and_then(|content_type| content_type.split(';').next())
+            .is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case("application/x-www-form-urlencoded"));
+        if !is_form {
+            return Err(HttpError::UnsupportedMediaType);
         }
 
-        PathBuf::from(config.directory.as_ref().unwrap()).join(&ctx.path["/files/".len()..])
-    };
+        let mut body = Vec::new();
+        self.body_reader().await?.read_to_end(&mut body).await?;
+        serde_urlencoded::from_bytes(&body).map_err(|e| HttpError::BadRequest(format!("Invalid form body: {}", e)))
+    }
+
+    /// The cookies the client sent, by name.
+    pub fn cookies(&self) -> HashMap<String, String> {
+        self.headers.get("Cookie").map(|cookie| cookie::parse_cookies(cookie)).unwrap_or_default()
+    }
+
+    /// The client's session, restored from its session cookie the first time it's asked for.
+    #[allow(unused)]
+    pub fn session(&mut self) -> &mut Session {
+        let session = match self.session.take() {
+            Some(session) => session,
+            None => Session::load(self.cookies().get(SESSION_COOKIE)),
+        };
+        self.session.insert(session)
+    }
+
+    /// Picks the media type from `offered` that the request's `Accept` header prefers, or
+    /// `None` when it accepts none of them.
+    pub fn negotiate<'a>(&self, offered: &[&'a str]) -> Option<&'a str> {
+        accept::negotiate(self.headers.get("Accept"), offered)
+    }
 
-    let content_length = {
-        if let Some(content_length) = ctx.headers.get("Content-Length") {
-            content_length.parse::<usize>()?
+    /// Whether the client is willing to send another request on this connection.
+    pub fn keep_alive(&self) -> bool {
+        let has_token = |wanted: &str| self.headers.get("Connection")
+            .is_some_and(|connection| connection.split(',').any(|token| token.trim().eq_ignore_ascii_case(wanted)));
+
+        if self.http_version == HttpVersion::Http10 {
+            has_token("keep-alive")
         } else {
-            return Ok(HttpResponse::new(HttpStatus::BadRequest))
+            !has_token("close")
         }
-    };
+    }
 
-    let mut file = File::create(dest_path).await?;
-    let mut bytes_read: usize = 0;
-    let mut buf = vec![0; 8192];
-    loop {
-        let num_read = ctx.reader.read(&mut buf).await?;
-        bytes_read += num_read;
-        file.write_all(&buf[..num_read]).await?;
-        if bytes_read >= content_length {
-            break;
+    /// Skips whatever the handler left unread of the request body so that the next request
+    /// starts in the right place. Returns false if the connection can't be reused.
+    async fn discard_body(&mut self, status: HttpStatus) -> bool {
+        let consumed = self.reader.count() - self.body_start;
+        // The client may or may not send a body it was never asked for, so the stream can't be trusted
+        if self.awaiting_continue && !matches!(self.body_framing(), Ok(BodyFraming::None | BodyFraming::Length(0))) {
+            return false;
         }
-    }
 
-    Ok(HttpResponse::new(HttpStatus::Created))
-}
\ No newline at end of file
+        match self.body_framing() {
+            Err(_) => false,
+            Ok(BodyFraming::None | BodyFraming::Stream) => true,
+            // Only a handler that succeeded is known to have read up to the last chunk
+            Ok(BodyFraming::Chunked) if consumed > 0 => !(status.is_client_error() || status.is_server_error()),
+            Ok(BodyFraming::Chunked) => {
+                let mut body = LimitedReader::new(ChunkedReader::new(&mut self.reader), MAX_DISCARDED_BODY);
+                tokio::io::copy(&mut body, &mut tokio::io::sink()).await.is_ok()
+            }
+            Ok(BodyFraming::Length(length)) => match length.checked_sub(consumed) {
+                Some(0) => true,
+                Some(remaining) if remaining <= MAX_DISCARDED_BODY => {
+                    let mut body = LengthReader::new(&mut self.reader, remaining);
+                    tokio::io::copy(&mut body, &mut tokio::io::sink()).await.is_ok()
+                }
+                _ => false,
+            },
+        }
+    }
+
+    /// Writes the response and returns the number of bytes put on the wire, leaving out the body
+    /// of responses to HEAD and of statuses that can't have one.
+    pub async fn send(&mut self, response: HttpResponse) -> Result<u64> {
+        let chunked = self.supports_chunked();
+        let bytes_written = write_head(&mut self.writer, &response, chunked).await?
+            + write_body(&mut self.writer, &response, chunked).await?;
+
+        self.writer.flush().await?;
+        Ok(bytes_written)
+    }
+
+    /// HTTP/1.0 clients don't understand chunked responses.
+    pub fn supports_chunked(&self) -> bool {
+        self.http_version != HttpVersion::Http10
+    }
+}
+
+/// Writes the response head and body. A body of unknown length is sent chunked, or if `chunked`
+/// is false delimited by closing the connection, so the response must carry `Connection: close`.
+async fn write_response(writer: &mut ConnectionWriter, response: HttpResponse, chunked: bool) -> Result<u64> {
+    let bytes_written = write_head(writer, &response, chunked).await? + write_body(writer, &response, chunked).await?;
+    writer.flush().await?;
+    Ok(bytes_written)
+}
+
+async fn write_head(writer: &mut ConnectionWriter, response: &HttpResponse, chunked: bool) -> Result<u64> {
+    let mut head = format!("HTTP/1.1 {} ", response.status() as u16);
+    if let Some(message) = response.status_message() {
+        head.push_str(message);
+    } else {
+        head.push_str(response.status().reason_phrase());
+    }
+    head.push_str("\r\n");
+
+    if !response.headers().contains_key("Date") {
+        head.push_str(&format!("Date: {}\r\n", http_date()));
+    }
+    if let Some(server) = server_header(response).await {
+        head.push_str(&format!("Server: {}\r\n", server));
+    }
+    for (name, value) in response.sent_headers() {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if let Some(content_type) = response.content_type() {
+        head.push_str(&format!("Content-Type: {}\r\n", content_type));
+    }
+    if let Some(content) = response.content().filter(|_| response.status().permits_body()) {
+        match content.content_length() {
+            Some(content_length) => head.push_str(&format!("Content-Length: {}\r\n", content_length)),
+            None if chunked => {
+                head.push_str("Transfer-Encoding: chunked\r\n");
+                let trailer_names = content.trailer_names();
+                if !trailer_names.is_empty() {
+                    head.push_str(&format!("Trailer: {}\r\n", trailer_names.join(", ")));
+                }
+            }
+            None => {}
+        }
+    } else if response.status().permits_body() {
+        // Without this a keep-alive client can't tell that the (empty) body is already over
+        head.push_str("Content-Length: 0\r\n");
+    }
+    head.push_str("\r\n");
+
+    writer.write_all(head.as_bytes()).await?;
+    Ok(head.len() as u64)
+}
+
+/// The configured `Server` product, unless it's turned off or the response names its own.
+pub async fn server_header(response: &HttpResponse) -> Option<String> {
+    if response.headers().contains_key("Server") {
+        return None;
+    }
+
+    Some(CONFIG.read().await.server_header.clone()).filter(|server| !server.is_empty())
+}
+
+async fn write_body(writer: &mut ConnectionWriter, response: &HttpResponse, chunked: bool) -> Result<u64> {
+    let mut bytes_written = 0;
+    match response.body().map(|content| (content, content.content_length())) {
+        Some((_, Some(0))) | None => {}
+        Some((content, Some(content_length))) => {
+            let content_length = content_length as u64;
+            let mut content_reader = content.content()?.take(content_length);
+
+            let written = tokio::io::copy(&mut content_reader, writer).await?;
+            if written != content_length {
+                // The headers are already out, so the only honest thing left is to drop the connection
+                bail!("Response body ended after {} of {} declared bytes, aborting connection", written, content_length);
+            }
+            bytes_written += written;
+        }
+        Some((content, None)) if chunked => {
+            bytes_written += write_chunked(&mut content.content()?, writer, || content.trailers()).await?;
+        }
+        Some((content, None)) => {
+            bytes_written += tokio::io::copy(&mut content.content()?, writer).await?;
+        }
+    }
+
+    Ok(bytes_written)
+}
+
+static ROUTER: Lazy<Router> = Lazy::new(|| {
+    let router = Router::new()
+        .get("/", index)
+        .get("/user-agent", user_agent)
+        .get("/echo/{*message}", echo)
+        .get("/files/{*path}", files)
+        .post("/files/{*path}", files_post)
+        .put("/files/{*path}", files_put)
+        .patch("/files/{*path}", files_patch)
+        .delete("/files/{*path}", files_delete)
+        .post("/upload/{*path}", upload)
+        .get("/ws/echo", websocket::echo_route);
+
+    // Nothing writes the config after startup, so it's always free to read
+    let mut mounts = CONFIG.try_read().map(|config| config.mounts.clone()).unwrap_or_default();
+    // Routes match in order, so nested mounts have to come before the ones containing them
+    mounts.sort_by_key(|mount| std::cmp::Reverse(mount.prefix.len()));
+    mounts.iter().fold(router, |router, mount| router.get(&format!("{}{{*path}}", mount.prefix), mounted_files))
+});
+
+/// What's left of a persistent connection's allowance, advertised in the `Keep-Alive` header.
+struct KeepAlive {
+    timeout: Duration,
+    /// Requests the connection may still carry after the current one, `None` if unlimited
+    remaining: Option<u32>,
+}
+
+impl KeepAlive {
+    fn header(&self) -> String {
+        match self.remaining {
+            Some(remaining) => format!("timeout={}, max={}", self.timeout.as_secs(), remaining),
+            None => format!("timeout={}", self.timeout.as_secs()),
+        }
+    }
+}
+
+/// Handles one request, handing the context back if the connection should be kept open for
+/// the next one.
+async fn process_request(mut ctx: RequestContext, keep_alive: KeepAlive) -> Result<Option<RequestContext>> {
+    debug!("{} '{}' {:?}", ctx.method, ctx.path, ctx.headers);
+
+    let response = respond(&mut ctx).await?;
+    if let Some(handler) = ctx.upgrade.take().filter(|_| matches!(response.status(), HttpStatus::SwitchingProtocols)) {
+        let status = response.status();
+        let bytes_written = ctx.send(response).await?;
+        {
+            let config = CONFIG.read().await;
+            log_request(&ctx, status, bytes_written, config.access_log_format.as_ref());
+        }
+        websocket::take_over(ctx, handler).await?;
+        return Ok(None);
+    }
+
+    let delimited = ctx.supports_chunked() || response.body().is_none_or(|content| content.content_length().is_some());
+
+    let status = response.status();
+    let advertised = keep_alive.header();
+    let keep_alive = delimited && keep_alive.remaining != Some(0) && ctx.keep_alive() && ctx.discard_body(status).await;
+    let response = match keep_alive {
+        true => response.with_header("Connection", "keep-alive".to_string()).with_header("Keep-Alive", advertised),
+        false => response.with_header("Connection", "close".to_string()),
+    };
+
+    let bytes_written = ctx.send(response).await?;
+    {
+        let config = CONFIG.read().await;
+        log_request(&ctx, status, bytes_written, config.access_log_format.as_ref());
+    }
+
+    if !keep_alive {
+        // Over TLS this sends close_notify, so the client can tell the end from a truncation
+        ctx.writer.shutdown().await?;
+        return Ok(None);
+    }
+    Ok(Some(ctx))
+}
+
+/// Routes the request and prepares its response, whichever protocol it arrived over. Responses
+/// to HEAD keep the headers GET would get, but lose their body.
+pub async fn respond(ctx: &mut RequestContext) -> Result<HttpResponse> {
+    let response = Next::new(&MIDDLEWARE, &route_request).run(ctx).await?;
+    Ok(match ctx.method {
+        HttpMethod::Head => response.without_body(),
+        _ => response,
+    })
+}
+
+/// The steps every request passes through on its way to `route_request`, outermost first. The
+/// ones after `recover` can fail with an `HttpError` and still have the ones before it finish
+/// the error response.
+static MIDDLEWARE: Lazy<Vec<Box<dyn Middleware>>> = Lazy::new(|| vec![
+    Box::new(compress),
+    Box::new(alt_svc),
+    Box::new(apply_cors),
+    Box::new(not_modified),
+    Box::new(commit_session),
+    Box::new(spa_fallback),
+    Box::new(recover),
+    Box::new(check_host),
+    Box::new(static_redirect),
+    Box::new(authenticate),
+]);
+
+async fn compress(ctx: &mut RequestContext, next: Next<'_>) -> HttpResult<HttpResponse> {
+    let response = next.run(ctx).await?;
+    Ok(compression::compress(response, ctx.headers.get("Accept-Encoding")).await?)
+}
+
+/// Lets clients know they can switch to HTTP/3 for later requests.
+async fn alt_svc(ctx: &mut RequestContext, next: Next<'_>) -> HttpResult<HttpResponse> {
+    let response = next.run(ctx).await?;
+    Ok(match CONFIG.read().await.http3_port {
+        Some(port) => response.with_header("Alt-Svc", format!("h3=\":{}\"; ma=86400", port)),
+        None => response,
+    })
+}
+
+async fn apply_cors(ctx: &mut RequestContext, next: Next<'_>) -> HttpResult<HttpResponse> {
+    let response = next.run(ctx).await?;
+    Ok(cors::apply(&ctx.headers, response).await)
+}
+
+async fn not_modified(ctx: &mut RequestContext, next: Next<'_>) -> HttpResult<HttpResponse> {
+    let response = next.run(ctx).await?;
+    Ok(conditional::not_modified(&ctx.method, &ctx.headers, response))
+}
+
+async fn commit_session(ctx: &mut RequestContext, next: Next<'_>) -> HttpResult<HttpResponse> {
+    let response = next.run(ctx).await?;
+    Ok(match ctx.session.take() {
+        Some(session) => {
+            let config = CONFIG.read().await;
+            let secure = config.tls_cert.is_some() || !config.acme_domains.is_empty();
+            session.commit(response, Duration::from_secs(config.session_ttl), secure)
+        }
+        None => response,
+    })
+}
+
+/// Turns errors from the steps after it into their error responses.
+async fn recover(ctx: &mut RequestContext, next: Next<'_>) -> HttpResult<HttpResponse> {
+    Ok(next.run(ctx).await.unwrap_or_else(|e| {
+        log_error(&e);
+        e.into()
+    }))
+}
+
+async fn check_host(ctx: &mut RequestContext, next: Next<'_>) -> HttpResult<HttpResponse> {
+    vhost::validate_host(&ctx.headers, ctx.http_version == HttpVersion::Http11)?;
+    next.run(ctx).await
+}
+
+async fn static_redirect(ctx: &mut RequestContext, next: Next<'_>) -> HttpResult<HttpResponse> {
+    let redirect = CONFIG.read().await.redirects.iter()
+        .find(|redirect| redirect.from == ctx.path)
+        .map(|redirect| redirect.response(ctx.query.as_deref()));
+    match redirect {
+        Some(redirect) => Ok(redirect),
+        None => next.run(ctx).await,
+    }
+}
+
+/// Challenges requests for protected paths that lack valid credentials. CORS preflights are let
+/// through since browsers never send credentials with them.
+async fn authenticate(ctx: &mut RequestContext, next: Next<'_>) -> HttpResult<HttpResponse> {
+    if !cors::is_preflight(&ctx.method, &ctx.headers) {
+        if let Some(challenge) = auth::authenticate(ctx).await? {
+            return Ok(challenge);
+        }
+    }
+    next.run(ctx).await
+}
+
+pub fn log_request(ctx: &RequestContext, status: HttpStatus, bytes_written: u64, access_log_format: Option<&AccessLogFormat>) {
+    info!("{} '{}' {} ({} bytes in, {} bytes out)", ctx.method, ctx.target, status as u16, ctx.bytes_read(), bytes_written);
+
+    if let Some(format) = access_log_format {
+        let entry = AccessLogEntry {
+            remote: ctx.remote_addr,
+            user: ctx.user.as_deref(),
+            time: SystemTime::now(),
+            method: &ctx.method,
+            path: &ctx.target,
+            version: ctx.http_version,
+            status: status as u16,
+            bytes: bytes_written,
+            bytes_in: ctx.bytes_read(),
+            duration: ctx.started.elapsed(),
+            headers: &ctx.headers,
+        };
+        info!(target: "access", "{}", format.render(&entry));
+    }
+}
+
+/// The routes the request's virtual host serves, all of them unless `--vhost-routes` limits it.
+async fn host_routes<'a>(router: &'a Router, headers: &HeaderMap) -> Vec<&'a Route> {
+    let config = CONFIG.read().await;
+    let host = headers.get("Host").map(|host| host_name(host));
+    let routes = router.routes().iter();
+    match host.and_then(|host| config.vhost_routes.iter().find(|vhost| vhost.name.eq_ignore_ascii_case(host))) {
+        Some(vhost) => routes
+            .filter(|route| vhost.routes.iter().any(|path| path == route.pattern().as_str() || path == route.pattern().prefix()))
+            .collect(),
+        None => routes.collect(),
+    }
+}
+
+async fn route_request(ctx: &mut RequestContext) -> HttpResult<HttpResponse> {
+    dispatch(&ROUTER, ctx).await
+}
+
+/// Answers the request from the first of `router`'s routes matching its path, or its fallback.
+async fn dispatch(router: &Router, ctx: &mut RequestContext) -> HttpResult<HttpResponse> {
+    let allow_trace = CONFIG.read().await.allow_trace;
+    let routes = host_routes(router, &ctx.headers).await;
+    let found = routes.iter().find_map(|route| route.pattern().matches(&ctx.path).map(|params| (route, params)));
+    let response = match found {
+        _ if ctx.headers.get("Expect").is_some_and(|expect| !expect.trim().eq_ignore_ascii_case("100-continue")) => {
+            HttpResponse::new(HttpStatus::ExpectationFailed)
+        }
+
+        _ if ctx.method == HttpMethod::Trace && allow_trace => trace(ctx).await?,
+
+        // TRACE is known even while it's turned off, so it gets a 405 with `Allow` like any other
+        // method the resource doesn't take
+        _ if ctx.method != HttpMethod::Trace && !routes.iter().any(|route| route.allows(&ctx.method)) => {
+            HttpResponse::new(HttpStatus::NotImplemented)
+        }
+
+        // `OPTIONS *` asks about the server as a whole
+        None if ctx.method == HttpMethod::Options && ctx.path == "*" => {
+            let allow = routes.iter().flat_map(|route| route.implied_methods()).unique().map(|method| method.to_string()).join(", ");
+            HttpResponse::new(HttpStatus::NoContent).with_header("Allow", allow)
+        }
+
+        None => match router.fallback_handler() {
+            Some(handler) => handler.call(ctx).await?,
+            None => HttpResponse::new(HttpStatus::NotFound),
+        },
+
+        Some((route, _)) if ctx.method == HttpMethod::Options => {
+            let response = HttpResponse::new(HttpStatus::NoContent).with_header("Allow", route.allow());
+            cors::preflight(&ctx.headers, response, route.allow()).await
+        }
+
+        Some((route, params)) => match route.handler(&ctx.method) {
+            Some(handler) => {
+                ctx.params = params;
+                handler.call(ctx).await?
+            }
+            None => HttpResponse::new(HttpStatus::MethodNotAllowed).with_header("Allow", route.allow()),
+        },
+    };
+
+    Ok(response)
+}
+
+/// Serves `--spa-fallback` in place of 404s for GETs under `--spa-prefix`.
+async fn spa_fallback(ctx: &mut RequestContext, next: Next<'_>) -> HttpResult<HttpResponse> {
+    let response = next.run(ctx).await?;
+    Ok(match response.status() {
+        HttpStatus::NotFound if matches!(ctx.method, HttpMethod::Get | HttpMethod::Head) => {
+            spa_fallback_response(&ctx.path).await.unwrap_or(response)
+        }
+        _ => response,
+    })
+}
+
+async fn spa_fallback_response(path: &str) -> Option<HttpResponse> {
+    let config = CONFIG.read().await;
+    let fallback = config.spa_fallback.as_ref()?;
+    if !path.starts_with(&config.spa_prefix) {
+        return None;
+    }
+
+    if Path::new(path).extension().is_some() && !config.spa_fallback_assets {
+        return None;
+    }
+
+    let content_type = mime::content_type(fallback, &config.mime_types).unwrap_or(&config.default_type).to_string();
+    match FileContent::open(fallback.clone()) {
+        Ok(content) => Some(HttpResponse::new(HttpStatus::Ok).with_content(content.with_content_type(content_type))),
+        Err(e) => {
+            error!("Failed to open SPA fallback {}: {}", fallback.display(), e);
+            None
+        }
+    }
+}
+
+pub async fn index() -> HttpResult<HttpResponse> {
+    Ok(HttpResponse::new(HttpStatus::Ok))
+}
+
+pub async fn echo(ctx: &mut RequestContext) -> HttpResult<HttpResponse> {
+    let remaining = ctx.param("message").unwrap_or_default().to_string();
+    text_response(ctx, remaining)
+}
+
+pub async fn user_agent(ctx: &mut RequestContext) -> HttpResult<HttpResponse> {
+    let agent = ctx.headers.get("User-Agent").cloned()
+        .ok_or_else(|| HttpError::BadRequest("Missing User-Agent".to_string()))?;
+
+    text_response(ctx, agent)
+}
+
+/// Sends `text` as plain text, a JSON string or an HTML snippet, whichever the client accepts.
+fn text_response(ctx: &RequestContext, text: String) -> HttpResult<HttpResponse> {
+    let content: Box<dyn HttpContent + Send + Sync> = match ctx.negotiate(&["text/plain", "application/json", "text/html"]) {
+        Some("application/json") => JsonContent::new(&text)?,
+        Some("text/html") => HtmlContent::new(format!("<pre>{}</pre>\n", escape_html(&text))),
+        Some(_) => PlainTextContent::new(text),
+        None => return Err(HttpError::NotAcceptable),
+    };
+
+    Ok(HttpResponse::new(HttpStatus::Ok)
+        .with_vary("Accept")
+        .with_content(content))
+}
+
+const TRACE_HIDDEN_HEADERS: &[&str] = &["Authorization", "Proxy-Authorization", "Cookie"];
+
+pub async fn trace(ctx: &mut RequestContext) -> HttpResult<HttpResponse> {
+    let mut message = format!("{} {} {}\r\n", ctx.method, ctx.target, ctx.http_version);
+    for (name, value) in &ctx.headers {
+        if TRACE_HIDDEN_HEADERS.iter().any(|hidden| hidden.eq_ignore_ascii_case(name)) {
+            continue;
+        }
+        message.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    message.push_str("\r\n");
+
+    Ok(HttpResponse::new(HttpStatus::Ok).with_content(MessageContent::new(message)))
+}
+
+/// The document root of the request's virtual host, or `--directory`.
+async fn document_root(headers: &HeaderMap) -> HttpResult<PathBuf> {
+    let config = CONFIG.read().await;
+    let host = headers.get("Host").map(|host| host_name(host));
+    let vhost = host.and_then(|host| config.vhosts.iter().find(|vhost| vhost.name.eq_ignore_ascii_case(host)));
+    let directory = match vhost {
+        Some(vhost) => &vhost.directory,
+        None => config.directory.as_ref().ok_or_else(|| anyhow!("No --directory configured"))?,
+    };
+    Ok(PathBuf::from(directory))
+}
+
+/// Maps the `{*path}` of a `/files/` route onto the document root.
+async fn files_path(headers: &HeaderMap, path: Option<&str>) -> HttpResult<PathBuf> {
+    safe_path::resolve(&document_root(headers).await?, path.unwrap_or_default()).await
+}
+
+pub async fn files(ctx: &mut RequestContext) -> HttpResult<HttpResponse> {
+    let root = document_root(&ctx.headers).await?;
+    let path = ctx.param("path").unwrap_or_default().to_string();
+    serve_file(ctx, &root, &path).await
+}
+
+/// Serves the files of the `--mount` whose prefix the path starts with.
+pub async fn mounted_files(ctx: &mut RequestContext) -> HttpResult<HttpResponse> {
+    let (root, path) = {
+        let config = CONFIG.read().await;
+        let mount = mount::find(&config.mounts, &ctx.path).ok_or(HttpError::NotFound)?;
+        (PathBuf::from(&mount.directory), ctx.path[mount.prefix.len()..].to_string())
+    };
+    serve_file(ctx, &root, &path).await
+}
+
+/// Serves `path` relative to the directory `root`, or the index or listing of a directory there.
+async fn serve_file(ctx: &mut RequestContext, root: &Path, path: &str) -> HttpResult<HttpResponse> {
+    let mut file_path = safe_path::resolve(root, path).await?;
+    if tokio::fs::metadata(&file_path).await.is_ok_and(|metadata| metadata.is_dir()) {
+        match directory_response(root, path, &file_path, &ctx.path, ctx.origin_target()).await? {
+            DirectoryResponse::Index(index) => file_path = index,
+            DirectoryResponse::Response(response) => return Ok(response),
+        }
+    }
+
+    let content = FileContent::open(file_path)?;
+    let content = {
+        let config = CONFIG.read().await;
+        let content_type = match content.path().extension() {
+            Some(_) => mime::content_type(content.path(), &config.mime_types),
+            None if config.sniff => content.sniff_content_type()?,
+            None => None,
+        };
+        let content_type = content_type.unwrap_or(&config.default_type).to_string();
+        content.with_content_type(content_type)
+    };
+
+    let file_len = content.file_len();
+    let etag = content.etag();
+    let content_modified = content.modified();
+    // `?download` saves the file under its own name instead of showing it
+    let download_name = content.path().file_name()
+        .filter(|_| ctx.query().iter().any(|(param, _)| param == "download"))
+        .map(|name| name.to_string_lossy().into_owned());
+    let ranges = match ctx.headers.get("Range") {
+        // A stale If-Range means the client's partial copy is outdated, so it gets the whole file
+        Some(_) if ctx.headers.get("If-Range")
+            .is_some_and(|if_range| !if_range_matches(if_range, &etag, content_modified)) => ByteRange::Ignored,
+        Some(range) => parse_byte_ranges(range, file_len),
+        None => ByteRange::Ignored,
+    };
+    let response = match ranges {
+        ByteRange::Satisfiable(mut ranges) if ranges.len() == 1 => {
+            let range = ranges.remove(0);
+            HttpResponse::new(HttpStatus::PartialContent)
+                .with_header("Content-Range", format!("bytes {}-{}/{}", range.start, range.end - 1, file_len))
+                .with_content(RangedFileContent::new(content.path().to_path_buf(), content.content_type().to_string(), range))
+        }
+        ByteRange::Satisfiable(ranges) => HttpResponse::new(HttpStatus::PartialContent)
+            .with_content(MultipartRangesContent::new(content.path().to_path_buf(), content.content_type().to_string(), file_len, ranges)),
+        ByteRange::Unsatisfiable => HttpResponse::new(HttpStatus::RangeNotSatisfiable)
+            .with_header("Content-Range", format!("bytes */{}", file_len)),
+        ByteRange::Ignored if ctx.supports_chunked() && wants_repr_digest(&ctx.headers) => {
+            HttpResponse::new(HttpStatus::Ok).with_content(DigestContent::new(content))
+        }
+        ByteRange::Ignored => HttpResponse::new(HttpStatus::Ok).with_content(content),
+    };
+
+    let response = response
+        .with_header("Accept-Ranges", "bytes".to_string())
+        .with_header("ETag", etag);
+    let response = match download_name {
+        Some(name) => response.with_attachment(&name),
+        None => response,
+    };
+    Ok(match content_modified {
+        Some(modified) => response.with_header("Last-Modified", httpdate::fmt_http_date(modified)),
+        None => response,
+    })
+}
+
+/// How a directory requested under `/files/` is answered.
+enum DirectoryResponse {
+    /// Serve this index file from the directory as if it had been asked for
+    Index(PathBuf),
+    Response(HttpResponse),
+}
+
+/// Picks the directory's first `--index` file, or else lists it when `--autoindex` is on. Either
+/// way the client is first sent to the path with a trailing `/`, unless `--no-slash-redirect`
+/// is given, so relative links resolve inside the directory. `directory` is `relative` resolved
+/// under `root`, and index files are resolved the same way so `--symlinks` applies to them too.
+async fn directory_response(root: &Path, relative: &str, directory: &Path, path: &str, target: &str) -> HttpResult<DirectoryResponse> {
+    let (index_files, autoindex, slash_redirect) = {
+        let config = CONFIG.read().await;
+        (config.index_files.clone(), config.autoindex, !config.no_slash_redirect)
+    };
+
+    let mut index = None;
+    for name in index_files.iter().filter(|name| !name.is_empty()) {
+        // An index the policy refuses is skipped like a missing one
+        let Ok(candidate) = safe_path::resolve(root, &Path::new(relative).join(name).to_string_lossy()).await else {
+            continue;
+        };
+        if tokio::fs::metadata(&candidate).await.is_ok_and(|metadata| metadata.is_file()) {
+            index = Some(candidate);
+            break;
+        }
+    }
+    if index.is_none() && !autoindex {
+        return Err(HttpError::NotFound);
+    }
+
+    if slash_redirect && !path.ends_with('/') {
+        let location = match target.split_once('?') {
+            Some((target_path, query)) => format!("{}/?{}", target_path, query),
+            None => format!("{}/", target),
+        };
+        return Ok(DirectoryResponse::Response(HttpResponse::redirect(HttpStatus::MovedPermanently, &location)));
+    }
+
+    Ok(match index {
+        Some(index) => DirectoryResponse::Index(index),
+        None => DirectoryResponse::Response(autoindex::listing(directory, path, !relative.is_empty()).await?),
+    })
+}
+
+/// Whether the client asked for a SHA-256 `Repr-Digest` and can receive it as a trailer.
+fn wants_repr_digest(headers: &HeaderMap) -> bool {
+    let accepts_trailers = headers.get("TE")
+        .is_some_and(|te| te.split(',').any(|coding| coding.trim().eq_ignore_ascii_case("trailers")));
+    let wants_sha256 = headers.get("Want-Repr-Digest").is_some_and(|want| want.split(',').any(|entry| {
+        let (algorithm, preference) = entry.split_once('=').unwrap_or((entry, "1"));
+        algorithm.trim().eq_ignore_ascii_case("sha-256") && preference.trim() != "0"
+    }));
+    accepts_trailers && wants_sha256
+}
+
+pub async fn files_post(ctx: &mut RequestContext) -> HttpResult<HttpResponse> {
+    let dest_path = files_path(&ctx.headers, ctx.param("path")).await?;
+    check_write_preconditions(&ctx.headers, &dest_path)?;
+    save_body(ctx, &dest_path).await?;
+    Ok(HttpResponse::new(HttpStatus::Created))
+}
+
+pub async fn files_put(ctx: &mut RequestContext) -> HttpResult<HttpResponse> {
+    let dest_path = files_path(&ctx.headers, ctx.param("path")).await?;
+    check_write_preconditions(&ctx.headers, &dest_path)?;
+    let replaced = tokio::fs::metadata(&dest_path).await.is_ok_and(|metadata| metadata.is_file());
+    save_body(ctx, &dest_path).await?;
+
+    Ok(HttpResponse::new(if replaced { HttpStatus::NoContent } else { HttpStatus::Created }))
+}
+
+/// Appends the request body to an existing file, cutting it back to its old length if the
+/// upload fails.
+pub async fn files_patch(ctx: &mut RequestContext) -> HttpResult<HttpResponse> {
+    let path = files_path(&ctx.headers, ctx.param("path")).await?;
+    check_write_preconditions(&ctx.headers, &path)?;
+    let metadata = tokio::fs::metadata(&path).await.map_err(FileError::from)?;
+    if !metadata.is_file() {
+        return Err(HttpError::NotFound);
+    }
+    if ctx.body_framing()? == BodyFraming::None {
+        return Err(HttpError::BadRequest("Missing Content-Length".to_string()));
+    }
+
+    let mut body = ctx.body_reader().await?;
+    let mut file = tokio::fs::OpenOptions::new().append(true).open(&path).await.map_err(FileError::from)?;
+    if let Err(e) = tokio::io::copy(&mut body, &mut file).await {
+        file.set_len(metadata.len()).await?;
+        return Err(e.into());
+    }
+
+    Ok(HttpResponse::new(HttpStatus::NoContent))
+}
+
+pub async fn files_delete(ctx: &mut RequestContext) -> HttpResult<HttpResponse> {
+    let path = files_path(&ctx.headers, ctx.param("path")).await?;
+    check_write_preconditions(&ctx.headers, &path)?;
+    let metadata = tokio::fs::metadata(&path).await.map_err(FileError::from)?;
+    if !metadata.is_file() {
+        return Err(HttpError::NotFound);
+    }
+
+    tokio::fs::remove_file(&path).await.map_err(FileError::from)?;
+    Ok(HttpResponse::new(HttpStatus::NoContent))
+}
+
+/// Saves the files of a `multipart/form-data` form post into the directory named by the rest of
+/// the `/upload/` path, answering with the names they were saved under. Other form fields are
+/// ignored.
+pub async fn upload(ctx: &mut RequestContext) -> HttpResult<HttpResponse> {
+    let directory = files_path(&ctx.headers, ctx.param("path")).await?;
+    if !tokio::fs::metadata(&directory).await.map_err(FileError::from)?.is_dir() {
+        return Err(HttpError::NotFound);
+    }
+    let boundary = ctx.headers.get("Content-Type")
+        .and_then(|content_type| multipart_boundary(content_type))
+        .ok_or(HttpError::UnsupportedMediaType)?;
+
+    let mut saved = Vec::new();
+    let mut form = MultipartReader::new(ctx.body_reader().await?, &boundary);
+    while let Some(mut part) = form.next_part().await? {
+        // Browsers send the bare file name, but some older ones include the client's path
+        let Some(filename) = part.filename.as_deref()
+            .and_then(|filename| filename.rsplit(['/', '\\']).next())
+            .filter(|filename| !filename.is_empty() && *filename != "." && *filename != "..")
+            .map(str::to_string) else {
+            continue;
+        };
+
+        let dest_path = safe_path::resolve(&directory, &filename).await?;
+        debug!("Saving upload field '{}' ({}) to {}", part.name, part.content_type.as_deref().unwrap_or(DEFAULT_CONTENT_TYPE), dest_path.display());
+        let mut file = File::create(&dest_path).await?;
+        if let Err(e) = tokio::io::copy(&mut part, &mut file).await {
+            drop(file);
+            tokio::fs::remove_file(&dest_path).await?;
+            return Err(e.into());
+        }
+        saved.push(filename);
+    }
+
+    if saved.is_empty() {
+        return Err(HttpError::BadRequest("No files in upload".to_string()));
+    }
+    let message = saved.iter().map(|filename| format!("{}\n", filename)).collect();
+    Ok(HttpResponse::new(HttpStatus::Created).with_content(PlainTextContent::new(message)))
+}
+
+/// Writes the request body to `dest_path`, removing the file again if the upload fails.
+async fn save_body(ctx: &mut RequestContext, dest_path: &Path) -> HttpResult<()> {
+    if ctx.body_framing()? == BodyFraming::None {
+        return Err(HttpError::BadRequest("Missing Content-Length".to_string()));
+    }
+
+    let mut body = ctx.body_reader().await?;
+    let mut file = File::create(dest_path).await?;
+    if let Err(e) = tokio::io::copy(&mut body, &mut file).await {
+        drop(file);
+        tokio::fs::remove_file(dest_path).await?;
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use anyhow::anyhow;
+    use clap::Parser;
+    use pretty_assertions::assert_eq;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tracing::Level;
+    use crate::error::{HttpError, HttpResult};
+    use crate::extract::{Body, Headers, Path, Query};
+    use crate::http::{FileContent, HttpResponse, HttpStatus, PlainTextContent};
+    use crate::middleware::{Middleware, Next};
+    use crate::router::{Handler, Router};
+    use crate::testing::{body, configure, context, exchange, header, remote_addr, temp_dir, tls_handshake, TestPki};
+    use crate::{accept_connection, bind_listener, commit_session, dispatch, handle_connection, log_error, mounted_files, percent_decode, read_request_head, redirect_to_https, serve, split_connection, tls, write_response, Args, ConnectionWriter, RequestContext, CONFIG};
+
+    /// Log output kept for a test to look at.
+    #[derive(Clone, Default)]
+    struct LogCapture(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogCapture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Everything logged at `level` or above while `f` runs.
+    async fn capture_logs(level: Level, f: impl Future<Output = ()>) -> String {
+        let capture = LogCapture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(level)
+            .with_writer(move || writer.clone())
+            .finish();
+        // Tests run on a single thread, so this also covers the connection task
+        let _subscriber = tracing::subscriber::set_default(subscriber);
+
+        f.await;
+        let logs = capture.0.lock().unwrap();
+        String::from_utf8_lossy(&logs).into_owned()
+    }
+
+    /// What gets logged for one request and one internal error when started with `args`.
+    async fn logs_with(args: &[&str]) -> String {
+        let _config = configure(args).await;
+        let level = CONFIG.read().await.log_level();
+        capture_logs(level, async {
+            exchange(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+            log_error(&HttpError::Internal(anyhow!("disk on fire")));
+        }).await
+    }
+
+    #[test]
+    fn verbosity_flags_map_to_levels() {
+        let level = |args: &[&str]| Args::parse_from([&["test"], args].concat()).log_level();
+        assert_eq!(level(&[]), Level::INFO);
+        assert_eq!(level(&["-q"]), Level::ERROR);
+        assert_eq!(level(&["-v"]), Level::DEBUG);
+        assert_eq!(level(&["-vv"]), Level::TRACE);
+        assert!(Args::try_parse_from(["test", "-q", "-v"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn quiet_logs_errors_but_not_requests() {
+        let logs = logs_with(&["--quiet"]).await;
+        assert!(!logs.contains("GET '/' 200"), "{}", logs);
+        assert!(logs.contains("disk on fire"), "{}", logs);
+
+        let logs = logs_with(&[]).await;
+        assert!(logs.contains("GET '/' 200"), "{}", logs);
+    }
+
+    #[tokio::test]
+    async fn wrong_method_gets_405_with_the_route_methods() {
+        let _config = configure(&[]).await;
+
+        let response = exchange(b"POST /echo/x HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "{}", response);
+        assert_eq!(header(&response, "Allow"), Some("GET, HEAD, OPTIONS"));
+
+        let response = exchange(b"GET /upload/x HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "{}", response);
+        assert_eq!(header(&response, "Allow"), Some("POST, OPTIONS"));
+    }
+
+    #[tokio::test]
+    async fn allow_differs_between_echo_and_files() {
+        let _config = configure(&[]).await;
+
+        let echo = exchange(b"OPTIONS /echo/x HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        let files = exchange(b"OPTIONS /files/x HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(header(&echo, "Allow"), Some("GET, HEAD, OPTIONS"));
+        assert_eq!(header(&files, "Allow"), Some("GET, HEAD, POST, PUT, PATCH, DELETE, OPTIONS"));
+    }
+
+    #[tokio::test]
+    async fn unknown_method_gets_501() {
+        let _config = configure(&[]).await;
+
+        let response = exchange(b"BREW /echo/x HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 501 Not Implemented\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn chunked_upload_leaves_the_pipelined_request_alone() {
+        let dir = temp_dir("chunked-pipelined");
+        let _config = configure(&["-d", dir.to_str().unwrap()]).await;
+
+        let response = exchange(concat!(
+            "POST /files/note.txt HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n",
+            "5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n",
+            "GET /files/note.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        ).as_bytes()).await;
+
+        let (created, fetched) = response.split_once("HTTP/1.1 200 OK\r\n").expect(&response);
+        assert!(created.starts_with("HTTP/1.1 201 Created\r\n"), "{}", response);
+        assert_eq!(body(fetched), "hello world");
+    }
+
+    #[tokio::test]
+    async fn malformed_chunk_size_gets_400() {
+        let dir = temp_dir("chunked-malformed");
+        let _config = configure(&["-d", dir.to_str().unwrap()]).await;
+
+        let response = exchange(b"POST /files/note.txt HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\nxyz\r\nhello\r\n0\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+        assert!(!dir.join("note.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn empty_echo_has_content_length_0() {
+        let _config = configure(&[]).await;
+
+        for request in [
+            &b"GET /echo/ HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"[..],
+            b"GET /echo/ HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n",
+        ] {
+            let response = exchange(request).await;
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+            assert_eq!(header(&response, "Content-Length"), Some("0"));
+            assert_eq!(header(&response, "Content-Encoding"), None);
+            assert_eq!(header(&response, "Transfer-Encoding"), None);
+            assert_eq!(body(&response), "");
+        }
+    }
+
+    #[tokio::test]
+    async fn bind_retries_until_the_port_is_released() {
+        let _config = configure(&["--bind-retry", "10", "--bind-retry-delay", "10"]).await;
+        let holder = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = holder.local_addr().unwrap().to_string();
+
+        let binding = tokio::spawn({
+            let addr = addr.clone();
+            async move { bind_listener(&addr).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!binding.is_finished());
+        drop(holder);
+
+        let listener = binding.await.unwrap().unwrap();
+        assert_eq!(listener.local_addr().unwrap().to_string(), addr);
+    }
+
+    #[tokio::test]
+    async fn bind_fails_at_once_without_retries() {
+        let _config = configure(&[]).await;
+        let holder = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let bound = bind_listener(&holder.local_addr().unwrap().to_string()).await;
+        assert!(bound.is_err());
+    }
+
+    #[tokio::test]
+    async fn missing_file_gets_404() {
+        let dir = temp_dir("missing-file");
+        let _config = configure(&["-d", dir.to_str().unwrap()]).await;
+        let response = exchange(b"GET /files/missing.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn trace_is_405_unless_allowed() {
+        let _config = configure(&[]).await;
+
+        let response = exchange(b"TRACE /echo/x HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "{}", response);
+        assert_eq!(header(&response, "Allow"), Some("GET, HEAD, OPTIONS"));
+    }
+
+    #[tokio::test]
+    async fn trace_echoes_the_request_without_credentials() {
+        let _config = configure(&["--allow-trace"]).await;
+
+        let response = exchange(concat!(
+            "TRACE /echo/x?y=1 HTTP/1.1\r\nHost: localhost\r\nX-Custom: kept\r\n",
+            "Authorization: Basic dTpw\r\nCookie: session=secret\r\nConnection: close\r\n\r\n",
+        ).as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert_eq!(header(&response, "Content-Type"), Some("message/http"));
+        let echoed = body(&response);
+        assert!(echoed.starts_with("TRACE /echo/x?y=1 HTTP/1.1\r\n"), "{}", echoed);
+        assert!(echoed.contains("X-Custom: kept\r\n"), "{}", echoed);
+        assert!(!echoed.contains("Authorization") && !echoed.contains("secret"), "{}", echoed);
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_stragglers_after_the_graceful_timeout() {
+        let _config = configure(&["--graceful-timeout", "1", "--keep-alive-timeout", "60"]).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, None, async {
+            let _ = stopped.await;
+        }));
+
+        // A request head that never ends
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let stopping = Instant::now();
+        stop.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server).await.expect("Server kept running").unwrap().unwrap();
+        assert!(stopping.elapsed() >= Duration::from_secs(1));
+        let mut rest = Vec::new();
+        assert!(client.read_to_end(&mut rest).await.map_or(true, |read| read == 0));
+    }
+
+    #[tokio::test]
+    async fn body_shorter_than_declared_fails_the_connection() {
+        let _config = configure(&[]).await;
+        let path = temp_dir("short-body").join("file.txt");
+        std::fs::write(&path, "x".repeat(1000)).unwrap();
+        let response = HttpResponse::new(HttpStatus::Ok).with_content(FileContent::open(path.clone()).unwrap());
+        // Swapped for a shorter file after its length went into the response
+        std::fs::write(&path, "short").unwrap();
+
+        let mut writer: ConnectionWriter = BufWriter::new(Box::new(Vec::new()));
+        let written = tokio::time::timeout(Duration::from_secs(5), write_response(&mut writer, response, false)).await
+            .expect("Writing the response hung");
+        assert!(written.unwrap_err().to_string().contains("5 of 1000"));
+    }
+
+    async fn get(path: &str) -> String {
+        exchange(format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path).as_bytes()).await
+    }
+
+    async fn get_with(path: &str, header: String) -> String {
+        exchange(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n{}\r\nConnection: close\r\n\r\n", path, header).as_bytes()).await
+    }
+
+    #[tokio::test]
+    async fn spa_fallback_serves_app_routes_but_not_missing_assets() {
+        let dir = temp_dir("spa");
+        std::fs::write(dir.join("index.html"), "<html>app</html>").unwrap();
+        std::fs::write(dir.join("app.js"), "run()").unwrap();
+        let fallback = dir.join("index.html");
+        let _config = configure(&["-d", dir.to_str().unwrap(), "--spa-fallback", fallback.to_str().unwrap()]).await;
+
+        let response = get("/files/settings/profile").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert_eq!(body(&response), "<html>app</html>");
+
+        let response = get("/files/app.js").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert_eq!(body(&response), "run()");
+
+        let response = get("/files/missing.js").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn spa_fallback_can_cover_missing_assets() {
+        let dir = temp_dir("spa-assets");
+        std::fs::write(dir.join("index.html"), "<html>app</html>").unwrap();
+        let fallback = dir.join("index.html");
+        let _config = configure(&["-d", dir.to_str().unwrap(), "--spa-fallback", fallback.to_str().unwrap(), "--spa-fallback-assets"]).await;
+
+        let response = get("/files/missing.js").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert_eq!(body(&response), "<html>app</html>");
+    }
+
+    #[tokio::test]
+    async fn logged_byte_counts_match_the_wire() {
+        let _config = configure(&[]).await;
+        let request = b"GET /echo/counted HTTP/1.1\r\nHost: localhost\r\nUser-Agent: test\r\nConnection: close\r\n\r\n";
+        let mut response = String::new();
+
+        let logs = capture_logs(Level::INFO, async {
+            response = exchange(request).await;
+        }).await;
+        let expected = format!("({} bytes in, {} bytes out)", request.len(), response.len());
+        assert!(logs.contains(&expected), "{} doesn't contain {}", logs, expected);
+    }
+
+    /// Sends `request` to a redirect listener for an HTTPS port of `https_port`.
+    async fn redirected(request: &[u8], https_port: u16) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let redirector = tokio::spawn(redirect_to_https(listener, https_port));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(request).await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        redirector.abort();
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    #[tokio::test]
+    async fn plain_http_is_redirected_to_https() {
+        let _config = configure(&[]).await;
+
+        let response = redirected(b"GET /files/a.txt?download HTTP/1.1\r\nHost: example.com:8080\r\n\r\n", 8443).await;
+        assert!(response.starts_with("HTTP/1.1 301 Moved Permanently\r\n"), "{}", response);
+        assert_eq!(header(&response, "Location"), Some("https://example.com:8443/files/a.txt?download"));
+        assert_eq!(header(&response, "Connection"), Some("close"));
+    }
+
+    #[tokio::test]
+    async fn redirect_leaves_out_the_default_https_port() {
+        let _config = configure(&[]).await;
+
+        let response = redirected(b"GET / HTTP/1.1\r\nHost: example.com:80\r\n\r\n", 443).await;
+        assert_eq!(header(&response, "Location"), Some("https://example.com/"));
+
+        let response = redirected(b"GET /a HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n", 8443).await;
+        assert_eq!(header(&response, "Location"), Some("https://[::1]:8443/a"));
+    }
+
+    #[tokio::test]
+    async fn redirect_without_a_host_gets_400() {
+        let _config = configure(&[]).await;
+
+        let response = redirected(b"GET / HTTP/1.0\r\n\r\n", 8443).await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+        assert_eq!(header(&response, "Location"), None);
+    }
+
+    #[tokio::test]
+    async fn chunked_upload_over_the_limit_gets_413_and_is_removed() {
+        let dir = temp_dir("chunked-too-large");
+        let _config = configure(&["-d", dir.to_str().unwrap(), "--max-body-size", "8"]).await;
+
+        let response = exchange(b"POST /files/note.txt HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 413 Content Too Large\r\n"), "{}", response);
+        assert!(!dir.join("note.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn bytes_past_the_declared_length_are_not_saved() {
+        let dir = temp_dir("lying-content-length");
+        let _config = configure(&["-d", dir.to_str().unwrap(), "--max-body-size", "8"]).await;
+
+        // Whatever follows the declared body is the next request, not more of the upload
+        let response = exchange(b"POST /files/note.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello, and a good deal more than that\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"), "{}", response);
+        assert!(response.contains("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+        assert_eq!(std::fs::read(dir.join("note.txt")).unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn default_type_covers_unknown_files() {
+        let dir = temp_dir("default-type");
+        std::fs::write(dir.join("notes.xyz"), "plain enough").unwrap();
+        let _config = configure(&["-d", dir.to_str().unwrap(), "--default-type", "text/plain"]).await;
+
+        let response = get("/files/notes.xyz").await;
+        assert_eq!(header(&response, "Content-Type"), Some("text/plain"));
+    }
+
+    #[tokio::test]
+    async fn sniffed_html_is_still_sent_whole() {
+        let dir = temp_dir("sniff");
+        let page = "<!DOCTYPE html>\n<title>Hi</title>\n";
+        std::fs::write(dir.join("page"), page).unwrap();
+
+        let _config = configure(&["-d", dir.to_str().unwrap()]).await;
+        assert_eq!(header(&get("/files/page").await, "Content-Type"), Some("application/octet-stream"));
+        drop(_config);
+
+        let _config = configure(&["-d", dir.to_str().unwrap(), "--sniff"]).await;
+        let response = get("/files/page").await;
+        assert_eq!(header(&response, "Content-Type"), Some("text/html"));
+        assert_eq!(header(&response, "Content-Length"), Some(page.len().to_string().as_str()));
+        assert_eq!(body(&response), page);
+    }
+
+    #[tokio::test]
+    async fn client_that_stops_reading_is_dropped() {
+        let dir = temp_dir("write-timeout");
+        std::fs::write(dir.join("large.bin"), vec![0; 1 << 20]).unwrap();
+        let _config = configure(&["-d", dir.to_str().unwrap(), "--write-timeout", "1"]).await;
+
+        // The client sends its request and then never reads, holding the connection open
+        let (mut client, server) = tokio::io::duplex(4096);
+        client.write_all(b"GET /files/large.bin HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let started = Instant::now();
+        tokio::time::timeout(Duration::from_secs(5), handle_connection(remote_addr(), server, None)).await
+            .expect("connection outlived its write timeout");
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        drop(client);
+    }
+
+    #[tokio::test]
+    async fn malformed_request_heads_get_400_and_unknown_versions_505() {
+        let _config = configure(&[]).await;
+
+        for request in [&b"G@T / HTTP/1.1\r\n\r\n"[..], b"GET / HTTP/x\r\n\r\n", b"GET / HTTP/1.1\r\nHost : localhost\r\n\r\n"] {
+            let response = exchange(request).await;
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+        }
+        let response = exchange(b"GET / HTTP/3.7\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 505 HTTP Version Not Supported\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn encoded_dot_segments_cant_leave_the_directory() {
+        let dir = temp_dir("traversal");
+        std::fs::create_dir_all(dir.join("public")).unwrap();
+        std::fs::write(dir.join("secret.txt"), "secret").unwrap();
+        let _config = configure(&["-d", dir.join("public").to_str().unwrap()]).await;
+
+        for path in ["/files/../secret.txt", "/files/%2e%2e/secret.txt", "/files/%2E%2E%2fsecret.txt", "/files/..%2fsecret.txt", "/files/a/%2e%2e/%2e%2e/secret.txt"] {
+            let response = get(path).await;
+            assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{}: {}", path, response);
+        }
+    }
+
+    #[tokio::test]
+    async fn ranges_get_206_and_unsatisfiable_ones_416() {
+        let dir = temp_dir("ranges");
+        std::fs::write(dir.join("digits.txt"), "0123456789").unwrap();
+        let _config = configure(&["-d", dir.to_str().unwrap()]).await;
+
+        let range = |value: &str| get_with("/files/digits.txt", format!("Range: {}", value));
+        let response = range("bytes=2-4").await;
+        assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"), "{}", response);
+        assert_eq!(header(&response, "Content-Range"), Some("bytes 2-4/10"));
+        assert_eq!(header(&response, "Accept-Ranges"), Some("bytes"));
+        assert_eq!(body(&response), "234");
+
+        let response = range("bytes=-3").await;
+        assert_eq!(header(&response, "Content-Range"), Some("bytes 7-9/10"));
+        assert_eq!(body(&response), "789");
+
+        let response = range("bytes=10-").await;
+        assert!(response.starts_with("HTTP/1.1 416 Range Not Satisfiable\r\n"), "{}", response);
+        assert_eq!(header(&response, "Content-Range"), Some("bytes */10"));
+
+        let response = range("lines=1-2").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert_eq!(body(&response), "0123456789");
+    }
+
+    #[tokio::test]
+    async fn several_ranges_are_sent_as_multipart_byteranges() {
+        let dir = temp_dir("multipart-ranges");
+        std::fs::write(dir.join("digits.txt"), "0123456789").unwrap();
+        let _config = configure(&["-d", dir.to_str().unwrap()]).await;
+
+        let response = get_with("/files/digits.txt", "Range: bytes=0-1,-2".to_string()).await;
+        assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"), "{}", response);
+        assert_eq!(header(&response, "Content-Range"), None);
+        let boundary = header(&response, "Content-Type")
+            .and_then(|content_type| content_type.strip_prefix("multipart/byteranges; boundary="))
+            .expect(&response);
+
+        let body = body(&response);
+        assert_eq!(header(&response, "Content-Length"), Some(body.len().to_string().as_str()));
+        assert_eq!(body, format!(concat!(
+            "\r\n--{0}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: bytes 0-1/10\r\n\r\n01",
+            "\r\n--{0}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: bytes 8-9/10\r\n\r\n89",
+            "\r\n--{0}--\r\n",
+        ), boundary));
+    }
+
+    #[tokio::test]
+    async fn echo_follows_accept_or_gets_406() {
+        let _config = configure(&[]).await;
+
+        let response = get_with("/echo/a<b", "Accept: text/html".to_string()).await;
+        assert!(header(&response, "Content-Type").is_some_and(|content_type| content_type.starts_with("text/html")), "{}", response);
+        assert!(header(&response, "Vary").is_some_and(|vary| vary.split(", ").any(|name| name == "Accept")), "{}", response);
+        assert_eq!(body(&response), "<pre>a&lt;b</pre>\n");
+
+        let response = get_with("/echo/abc", "Accept: application/json".to_string()).await;
+        assert_eq!(body(&response), "\"abc\"");
+
+        let response = get_with("/echo/abc", "Accept: image/*".to_string()).await;
+        assert!(response.starts_with("HTTP/1.1 406 Not Acceptable\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn form_upload_saves_the_files_under_their_names() {
+        let dir = temp_dir("form-upload");
+        std::fs::create_dir(dir.join("photos")).unwrap();
+        let _config = configure(&["-d", dir.to_str().unwrap()]).await;
+
+        let form = concat!(
+            "--b0undary\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nHoliday\r\n",
+            "--b0undary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"C:\\\\Users\\\\me\\\\beach.txt\"\r\n",
+            "Content-Type: text/plain\r\n\r\nsand\r\n",
+            "--b0undary--\r\n",
+        );
+        let response = exchange(format!(
+            "POST /upload/photos HTTP/1.1\r\nHost: localhost\r\nContent-Type: multipart/form-data; boundary=b0undary\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            form.len(), form,
+        ).as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"), "{}", response);
+        assert_eq!(body(&response), "beach.txt\n");
+        assert_eq!(std::fs::read_to_string(dir.join("photos/beach.txt")).unwrap(), "sand");
+        assert!(!dir.join("photos/title").exists());
+
+        let response = exchange(b"POST /upload/photos HTTP/1.1\r\nHost: localhost\r\nContent-Type: text/plain\r\nContent-Length: 1\r\nConnection: close\r\n\r\nx").await;
+        assert!(response.starts_with("HTTP/1.1 415 Unsupported Media Type\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn static_redirects_answer_before_routing() {
+        let _config = configure(&["--redirect", "/echo/old=/echo/new,307"]).await;
+
+        let response = get("/echo/old?x=1").await;
+        assert!(response.starts_with("HTTP/1.1 307 Temporary Redirect\r\n"), "{}", response);
+        assert_eq!(header(&response, "Location"), Some("/echo/new?x=1"));
+    }
+
+    #[tokio::test]
+    async fn tls_connections_are_terminated_and_served() {
+        let pki = TestPki::new("tls-termination");
+        let args = pki.args(false);
+        let _config = configure(&args.iter().map(String::as_str).collect::<Vec<_>>()).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, tls::acceptor().await.unwrap(), std::future::pending()));
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut stream = pki.connector(false).connect(ServerName::try_from("localhost").unwrap(), stream).await.unwrap();
+        stream.write_all(b"GET /echo/secure HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert_eq!(body(&response), "secure");
+
+        // Plain HTTP on the TLS port gets no HTTP answer
+        let mut plain = TcpStream::connect(addr).await.unwrap();
+        plain.write_all(b"GET /echo/plain HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let mut response = Vec::new();
+        let _ = plain.read_to_end(&mut response).await;
+        assert!(!response.starts_with(b"HTTP/"), "{}", String::from_utf8_lossy(&response));
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_are_answered_in_order() {
+        let _config = configure(&[]).await;
+
+        // All three arrive in a single read, so the later ones wait in the buffer
+        let response = exchange(concat!(
+            "GET /echo/first HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            "GET /user-agent HTTP/1.1\r\nHost: localhost\r\nUser-Agent: second\r\n\r\n",
+            "GET /echo/third HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        ).as_bytes()).await;
+
+        let bodies: Vec<_> = response.split("HTTP/1.1 200 OK\r\n").skip(1).map(body).collect();
+        assert_eq!(bodies, ["first", "second", "third"], "{}", response);
+    }
+
+    #[tokio::test]
+    async fn declared_body_over_the_limit_gets_413_before_it_is_sent() {
+        let dir = temp_dir("declared-too-large");
+        let _config = configure(&["-d", dir.to_str().unwrap(), "--max-body-size", "10"]).await;
+
+        let response = exchange(b"POST /files/big.bin HTTP/1.1\r\nHost: localhost\r\nContent-Length: 11\r\nExpect: 100-continue\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 413 Content Too Large\r\n"), "{}", response);
+        assert_eq!(header(&response, "Connection"), Some("close"));
+        assert!(!dir.join("big.bin").exists());
+
+        let response = exchange(b"POST /files/small.bin HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\nConnection: close\r\n\r\n0123456789").await;
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn upload_takes_exactly_its_content_length() {
+        let dir = temp_dir("exact-length");
+        let _config = configure(&["-d", dir.to_str().unwrap()]).await;
+
+        let response = exchange(concat!(
+            "POST /files/note.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello",
+            "GET /files/note.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        ).as_bytes()).await;
+
+        let (created, fetched) = response.split_once("HTTP/1.1 200 OK\r\n").expect(&response);
+        assert!(created.starts_with("HTTP/1.1 201 Created\r\n"), "{}", response);
+        assert_eq!(body(fetched), "hello");
+    }
+
+    #[tokio::test]
+    async fn repr_digest_is_of_the_unencoded_file() {
+        use base64::prelude::{Engine, BASE64_STANDARD};
+        use sha2::{Digest, Sha256};
+
+        let dir = temp_dir("digest-vs-gzip");
+        let page = "<p>hello</p>".repeat(1000);
+        std::fs::write(dir.join("page.html"), &page).unwrap();
+        let _config = configure(&["-d", dir.to_str().unwrap()]).await;
+
+        let response = exchange(b"GET /files/page.html HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip\r\nTE: trailers\r\nWant-Repr-Digest: sha-256=1\r\nConnection: close\r\n\r\n").await;
+        assert_eq!(header(&response, "Content-Encoding"), None);
+        assert_eq!(header(&response, "Transfer-Encoding"), Some("chunked"));
+        let trailer = format!("\r\nRepr-Digest: sha-256=:{}:\r\n", BASE64_STANDARD.encode(Sha256::digest(&page)));
+        assert!(response.ends_with(&format!("{}\r\n", trailer)), "{}", &response[response.len() - 100..]);
+    }
+
+    #[tokio::test]
+    async fn index_files_follow_the_symlink_policy() {
+        let dir = temp_dir("index-symlinks");
+        std::fs::create_dir_all(dir.join("root/docs")).unwrap();
+        std::fs::write(dir.join("secret.html"), "secret").unwrap();
+        std::fs::write(dir.join("root/docs/index.htm"), "fallback").unwrap();
+        std::os::unix::fs::symlink(dir.join("secret.html"), dir.join("root/docs/index.html")).unwrap();
+        let root = dir.join("root");
+
+        // The escaping index.html is passed over for the next candidate
+        let _config = configure(&["-d", root.to_str().unwrap()]).await;
+        let response = get("/files/docs/").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert_eq!(body(&response), "fallback");
+        drop(_config);
+
+        let _config = configure(&["-d", root.to_str().unwrap(), "--symlinks", "allow"]).await;
+        assert_eq!(body(&get("/files/docs/").await), "secret");
+    }
+
+    #[tokio::test]
+    async fn silent_connection_is_closed_before_its_first_request() {
+        let _config = configure(&["--keep-alive-timeout", "1"]).await;
+
+        // Too little to tell HTTP/1.1 from an HTTP/2 preface
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(b"PR").await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), handle_connection(remote_addr(), server, None)).await
+            .expect("connection waited on its preface forever");
+    }
+
+    #[tokio::test]
+    async fn stalled_tls_handshake_is_abandoned() {
+        let pki = TestPki::new("tls-handshake-timeout");
+        let mut args = pki.args(false);
+        args.extend(["--keep-alive-timeout".to_string(), "1".to_string()]);
+        let _config = configure(&args.iter().map(String::as_str).collect::<Vec<_>>()).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, addr) = listener.accept().await.unwrap();
+
+        let accepting = accept_connection(tls::acceptor().await.unwrap(), addr, stream);
+        tokio::time::timeout(Duration::from_secs(5), accepting).await.expect("handshake waited forever");
+    }
+
+    async fn known_page(_: &mut RequestContext) -> HttpResult<HttpResponse> {
+        Ok(HttpResponse::new(HttpStatus::NoContent))
+    }
+
+    async fn custom_not_found(ctx: &mut RequestContext) -> HttpResult<HttpResponse> {
+        Ok(HttpResponse::new(HttpStatus::NotFound).with_content(PlainTextContent::new(format!("nothing at {}", ctx.path))))
+    }
+
+    #[tokio::test]
+    async fn unmatched_paths_go_to_the_fallback() {
+        let _config = configure(&[]).await;
+        let router = Router::new().get("/known", known_page).fallback(custom_not_found);
+
+        let mut ctx = context(b"GET /known HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(dispatch(&router, &mut ctx).await.unwrap().status(), HttpStatus::NoContent);
+
+        let mut ctx = context(b"GET /unknown/page HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        let response = dispatch(&router, &mut ctx).await.unwrap();
+        assert_eq!(response.status(), HttpStatus::NotFound);
+        assert_eq!(response.content().unwrap().content_length(), Some("nothing at /unknown/page".len()));
+
+        // Without one, they get a plain 404
+        let mut ctx = context(b"GET /unknown/page HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        let response = dispatch(&Router::new().get("/known", known_page), &mut ctx).await.unwrap();
+        assert_eq!(response.status(), HttpStatus::NotFound);
+        assert!(response.content().is_none());
+    }
+
+    #[tokio::test]
+    async fn patch_appends_to_an_existing_file() {
+        let dir = temp_dir("files-patch");
+        std::fs::write(dir.join("log.txt"), "one\n").unwrap();
+        let _config = configure(&["-d", dir.to_str().unwrap(), "--max-body-size", "8"]).await;
+
+        let response = exchange(b"PATCH /files/log.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\nConnection: close\r\n\r\ntwo\n").await;
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"), "{}", response);
+        assert_eq!(std::fs::read_to_string(dir.join("log.txt")).unwrap(), "one\ntwo\n");
+
+        // A failed append leaves the file as it was
+        let response = exchange(b"PATCH /files/log.txt HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nthree\r\n6\r\n four\n\r\n0\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 413 Content Too Large\r\n"), "{}", response);
+        assert_eq!(std::fs::read_to_string(dir.join("log.txt")).unwrap(), "one\ntwo\n");
+
+        let response = exchange(b"PATCH /files/missing.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1\r\nConnection: close\r\n\r\nx").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn ambiguous_body_framing_gets_400_and_closes() {
+        let dir = temp_dir("ambiguous-framing");
+        let _config = configure(&["-d", dir.to_str().unwrap()]).await;
+
+        // What follows a request that can't be framed is never read as another request
+        for request in [
+            "POST /files/a.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+            "POST /files/a.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nhello!",
+            "POST /files/a.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5, 6\r\n\r\nhello!",
+        ] {
+            let response = exchange(format!("{}GET /echo/smuggled HTTP/1.1\r\nHost: localhost\r\n\r\n", request).as_bytes()).await;
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+            assert_eq!(header(&response, "Connection"), Some("close"));
+            assert!(!response.contains("smuggled"), "{}", response);
+            assert!(!dir.join("a.txt").exists());
+        }
+
+        // Repeating the same length is harmless
+        let response = exchange(b"POST /files/b.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello").await;
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"), "{}", response);
+        assert_eq!(std::fs::read_to_string(dir.join("b.txt")).unwrap(), "hello");
+    }
+
+    #[tokio::test]
+    async fn if_range_only_allows_a_partial_response_for_the_current_file() {
+        let dir = temp_dir("if-range");
+        std::fs::write(dir.join("data.txt"), "0123456789").unwrap();
+        let _config = configure(&["-d", dir.to_str().unwrap()]).await;
+
+        let response = get("/files/data.txt").await;
+        let etag = header(&response, "ETag").unwrap().to_string();
+        let last_modified = header(&response, "Last-Modified").unwrap().to_string();
+        assert!(etag.starts_with('"'), "{}", etag);
+
+        for if_range in [etag.clone(), last_modified] {
+            let response = get_with("/files/data.txt", format!("Range: bytes=2-4\r\nIf-Range: {}", if_range)).await;
+            assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"), "{}", response);
+            assert_eq!(body(&response), "234");
+        }
+        for if_range in ["\"stale\"".to_string(), format!("W/{}", etag), "Thu, 01 Jan 1970 00:00:00 GMT".to_string()] {
+            let response = get_with("/files/data.txt", format!("Range: bytes=2-4\r\nIf-Range: {}", if_range)).await;
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+            assert_eq!(body(&response), "0123456789");
+        }
+    }
+
+    async fn count_visit(ctx: &mut RequestContext) -> HttpResult<HttpResponse> {
+        let session = ctx.session();
+        let visits = session.get("visits").map_or(0, |visits| visits.parse::<u32>().unwrap()) + 1;
+        session.insert("visits", visits.to_string());
+        Ok(HttpResponse::new(HttpStatus::Ok).with_content(PlainTextContent::new(visits.to_string())))
+    }
+
+    async fn peek_visits(ctx: &mut RequestContext) -> HttpResult<HttpResponse> {
+        let visits = ctx.session().get("visits").unwrap_or("0").to_string();
+        Ok(HttpResponse::new(HttpStatus::Ok).with_content(PlainTextContent::new(visits)))
+    }
+
+    /// Runs `handler` behind `commit_session` for a request carrying `cookie`.
+    async fn with_session(handler: &dyn Handler, cookie: Option<&str>) -> HttpResponse {
+        let cookie = cookie.map_or(String::new(), |cookie| format!("Cookie: {}\r\n", cookie));
+        let mut ctx = context(format!("GET / HTTP/1.1\r\nHost: localhost\r\n{}\r\n", cookie).as_bytes()).await;
+        let layers: [Box<dyn Middleware>; 1] = [Box::new(commit_session)];
+        Next::new(&layers, handler).run(&mut ctx).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn session_cookie_is_only_set_when_the_session_changes() {
+        let _config = configure(&["--session-ttl", "120"]).await;
+
+        let response = with_session(&peek_visits, None).await;
+        assert_eq!(response.headers().get("Set-Cookie"), None);
+
+        let response = with_session(&count_visit, None).await;
+        let set_cookie = response.headers().get("Set-Cookie").unwrap().clone();
+        assert!(set_cookie.contains("; Max-Age=120;"), "{}", set_cookie);
+        let cookie = set_cookie.split(';').next().unwrap();
+
+        // Reading an existing session leaves the cookie the client has alone
+        let response = with_session(&peek_visits, Some(cookie)).await;
+        assert_eq!(response.headers().get("Set-Cookie"), None);
+
+        let response = with_session(&count_visit, Some(cookie)).await;
+        assert_eq!(response.headers().get("Set-Cookie").and_then(|value| value.split(';').next()), Some(cookie));
+        let response = with_session(&peek_visits, Some(cookie)).await;
+        assert_eq!(body_text(&response).await, "2");
+    }
+
+    #[tokio::test]
+    async fn expired_sessions_are_not_restored() {
+        let _config = configure(&["--session-ttl", "0"]).await;
+        let response = with_session(&count_visit, None).await;
+        let set_cookie = response.headers().get("Set-Cookie").unwrap().clone();
+        assert!(set_cookie.contains("; Max-Age=0;"), "{}", set_cookie);
+
+        let cookie = set_cookie.split(';').next().unwrap();
+        let response = with_session(&count_visit, Some(cookie)).await;
+        let renewed = response.headers().get("Set-Cookie").unwrap();
+        assert_ne!(renewed.split(';').next(), Some(cookie));
+    }
+
+    #[tokio::test]
+    async fn host_field_is_checked_for_http_1_1() {
+        let _config = configure(&[]).await;
+
+        for request in [
+            "GET /echo/hi HTTP/1.1\r\nConnection: close\r\n\r\n",
+            "GET /echo/hi HTTP/1.1\r\nHost: localhost\r\nHost: other.example\r\nConnection: close\r\n\r\n",
+            "GET /echo/hi HTTP/1.0\r\nHost: a.example\r\nHost: b.example\r\n\r\n",
+            "GET /echo/hi HTTP/1.1\r\nHost: evil.example/path\r\nConnection: close\r\n\r\n",
+        ] {
+            let response = exchange(request.as_bytes()).await;
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{:?}: {}", request, response);
+        }
+
+        let response = exchange(b"GET /echo/hi HTTP/1.0\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert_eq!(body(&response), "hi");
+    }
+
+    #[tokio::test]
+    async fn absolute_form_targets_route_by_path_and_host_by_authority() {
+        let default_dir = temp_dir("absolute-form-default");
+        let vhost_dir = temp_dir("absolute-form-vhost");
+        std::fs::write(default_dir.join("who.txt"), "default").unwrap();
+        std::fs::write(vhost_dir.join("who.txt"), "vhost").unwrap();
+        let vhost = format!("a.example={}", vhost_dir.display());
+        let _config = configure(&["-d", default_dir.to_str().unwrap(), "--vhost", &vhost]).await;
+
+        let response = exchange(b"GET http://localhost:4221/echo/absolute?ignored=1 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert_eq!(body(&response), "absolute");
+
+        let response = exchange(b"GET http://a.example/files/who.txt HTTP/1.1\r\nHost: b.example\r\nConnection: close\r\n\r\n").await;
+        assert_eq!(body(&response), "vhost");
+        let response = exchange(b"GET http://b.example/files/who.txt HTTP/1.1\r\nHost: a.example\r\nConnection: close\r\n\r\n").await;
+        assert_eq!(body(&response), "default");
+
+        let response = exchange(b"GET ftp://a.example/files/who.txt HTTP/1.1\r\nHost: a.example\r\nConnection: close\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn oversized_header_sections_get_431() {
+        let _config = configure(&["--max-header-count", "5", "--max-header-size", "256"]).await;
+
+        let many = (0..6).map(|i| format!("X-Field-{}: {}\r\n", i, i)).collect::<String>();
+        let large = format!("X-Large: {}\r\n", "a".repeat(300));
+        for fields in [many, large] {
+            let response = exchange(format!("GET /echo/hi HTTP/1.1\r\nHost: localhost\r\n{}\r\n", fields).as_bytes()).await;
+            assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"), "{}", response);
+            assert_eq!(header(&response, "Connection"), Some("close"));
+        }
+
+        let fields = (0..3).map(|i| format!("X-Field-{}: {}\r\n", i, i)).collect::<String>();
+        let response = exchange(format!("GET /echo/hi HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n", fields).as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn overlong_targets_get_414_and_other_major_versions_505() {
+        let _config = configure(&["--max-uri-length", "100"]).await;
+
+        // Whether the line ends within the limit or runs far past it
+        for length in [101, 10_000] {
+            let response = exchange(format!("GET /echo/{} HTTP/1.1\r\nHost: localhost\r\n\r\n", "a".repeat(length - 6)).as_bytes()).await;
+            assert!(response.starts_with("HTTP/1.1 414 URI Too Long\r\n"), "{}", response);
+            assert_eq!(header(&response, "Connection"), Some("close"));
+        }
+        let response = exchange(format!("GET /echo/{} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", "a".repeat(94)).as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+        for version in ["HTTP/2.0", "HTTP/0.9", "HTTP/3.0"] {
+            let response = exchange(format!("GET /echo/hi {}\r\nHost: localhost\r\n\r\n", version).as_bytes()).await;
+            assert!(response.starts_with("HTTP/1.1 505 HTTP Version Not Supported\r\n"), "{}: {}", version, response);
+        }
+        let response = exchange(b"GET /echo/hi HTTP/1.9\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn date_is_an_imf_fixdate_of_now() {
+        let _config = configure(&[]).await;
+        let response = get("/echo/hi").await;
+        let date = header(&response, "Date").unwrap();
+
+        // Such as `Sun, 06 Nov 1994 08:49:37 GMT`, always 29 characters in GMT
+        assert_eq!(date.len(), 29, "{}", date);
+        assert!(date.ends_with(" GMT"), "{}", date);
+        let parsed = httpdate::parse_http_date(date).unwrap();
+        assert_eq!(httpdate::fmt_http_date(parsed), date);
+        let age = std::time::SystemTime::now().duration_since(parsed).unwrap_or_default();
+        assert!(age < Duration::from_secs(5), "{}", date);
+    }
+
+    /// The head `write_response` sends for `response`.
+    async fn sent_head(response: HttpResponse) -> String {
+        let (client, mut server) = tokio::io::duplex(64 * 1024);
+        let mut writer: ConnectionWriter = BufWriter::new(Box::new(client));
+        write_response(&mut writer, response, false).await.unwrap();
+        writer.flush().await.unwrap();
+        drop(writer);
+        let mut sent = String::new();
+        server.read_to_string(&mut sent).await.unwrap();
+        sent
+    }
+
+    #[tokio::test]
+    async fn server_header_names_this_server_by_default() {
+        let _config = configure(&[]).await;
+        let response = get("/echo/hi").await;
+        assert_eq!(header(&response, "Server"), Some(concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"))));
+    }
+
+    #[tokio::test]
+    async fn server_header_can_be_replaced() {
+        let _config = configure(&["--server-header", "edge/2.1"]).await;
+        let response = get("/echo/hi").await;
+        assert_eq!(header(&response, "Server"), Some("edge/2.1"));
+    }
+
+    #[tokio::test]
+    async fn empty_server_header_leaves_it_out() {
+        let _config = configure(&["--server-header", ""]).await;
+        let response = get("/echo/hi").await;
+        assert_eq!(header(&response, "Server"), None);
+        assert!(header(&response, "Date").is_some());
+    }
+
+    #[tokio::test]
+    async fn responses_may_name_their_own_server() {
+        let _config = configure(&["--server-header", "edge/2.1"]).await;
+        let head = sent_head(HttpResponse::new(HttpStatus::NoContent).with_header("Server", "upstream/1.0".to_string())).await;
+        let servers: Vec<_> = head.lines().filter(|line| line.to_ascii_lowercase().starts_with("server:")).collect();
+        assert_eq!(servers, ["Server: upstream/1.0"]);
+    }
+
+    #[tokio::test]
+    async fn keep_alive_counts_down_and_closes_after_the_last_request() {
+        let _config = configure(&["--keep-alive-timeout", "7", "--keep-alive-max", "3"]).await;
+        let request = "GET /echo/hi HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let response = exchange(request.repeat(4).as_bytes()).await;
+
+        let responses: Vec<_> = response.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(responses.len(), 3, "{}", response);
+        assert_eq!(header(responses[0], "Keep-Alive"), Some("timeout=7, max=2"));
+        assert_eq!(header(responses[1], "Keep-Alive"), Some("timeout=7, max=1"));
+        assert_eq!(header(responses[0], "Connection"), Some("keep-alive"));
+        assert_eq!(header(responses[2], "Connection"), Some("close"));
+        assert_eq!(header(responses[2], "Keep-Alive"), None);
+    }
+
+    #[tokio::test]
+    async fn unlimited_keep_alive_only_advertises_the_timeout() {
+        let _config = configure(&["--keep-alive-timeout", "7", "--keep-alive-max", "0"]).await;
+        let response = exchange(b"GET /echo/hi HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(header(&response, "Keep-Alive"), Some("timeout=7"));
+    }
+
+    #[tokio::test]
+    async fn head_keeps_the_get_headers_but_sends_no_body() {
+        let dir = temp_dir("head");
+        std::fs::write(dir.join("page.txt"), "twelve bytes").unwrap();
+        let _config = configure(&["-d", dir.to_str().unwrap()]).await;
+
+        let get = get("/files/page.txt").await;
+        let head = exchange(b"HEAD /files/page.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        for name in ["Content-Length", "Content-Type", "ETag", "Last-Modified"] {
+            assert_eq!(header(&head, name), header(&get, name), "{}", name);
+        }
+        assert_eq!(header(&head, "Content-Length"), Some("12"));
+        assert!(head.ends_with("\r\n\r\n"), "{}", head);
+        assert_eq!(body(&head), "");
+
+        // A body the client was told to expect doesn't arrive ahead of the next response
+        let both = exchange(b"HEAD /echo/hello HTTP/1.1\r\nHost: localhost\r\n\r\nGET /echo/next HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+        let responses: Vec<_> = both.split("HTTP/1.1 200 OK\r\n").skip(1).collect();
+        assert_eq!(responses.len(), 2, "{}", both);
+        assert_eq!(header(responses[0], "Content-Length"), Some("5"));
+        assert_eq!(body(responses[0]), "");
+        assert_eq!(body(responses[1]), "next");
+    }
+
+    #[tokio::test]
+    async fn put_creates_with_201_and_replaces_with_204() {
+        let dir = temp_dir("put");
+        let _config = configure(&["-d", dir.to_str().unwrap()]).await;
+
+        let response = exchange(b"PUT /files/note.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nConnection: close\r\n\r\nfirst").await;
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"), "{}", response);
+        assert_eq!(std::fs::read_to_string(dir.join("note.txt")).unwrap(), "first");
+
+        let response = exchange(b"PUT /files/note.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\nConnection: close\r\n\r\n2nd").await;
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"), "{}", response);
+        assert_eq!(std::fs::read_to_string(dir.join("note.txt")).unwrap(), "2nd");
+    }
+
+    #[tokio::test]
+    async fn delete_removes_a_file_with_204_and_misses_with_404() {
+        let dir = temp_dir("delete");
+        std::fs::write(dir.join("old.txt"), "old").unwrap();
+        std::fs::create_dir(dir.join("folder")).unwrap();
+        let _config = configure(&["-d", dir.to_str().unwrap()]).await;
+
+        let response = exchange(b"DELETE /files/old.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"), "{}", response);
+        assert!(!dir.join("old.txt").exists());
+
+        for path in ["/files/old.txt", "/files/folder"] {
+            let response = exchange(format!("DELETE {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path).as_bytes()).await;
+            assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+        }
+        assert!(dir.join("folder").is_dir());
+    }
+
+    #[tokio::test]
+    async fn http_1_0_closes_unless_asked_to_keep_alive() {
+        let _config = configure(&[]).await;
+        let second = "GET /echo/second HTTP/1.0\r\n\r\n";
+
+        let response = exchange(format!("GET /echo/first HTTP/1.0\r\n\r\n{}", second).as_bytes()).await;
+        assert_eq!(response.matches("HTTP/1.1 200 OK\r\n").count(), 1, "{}", response);
+        assert_eq!(header(&response, "Connection"), Some("close"));
+        assert_eq!(body(&response), "first");
+
+        let response = exchange(format!("GET /echo/first HTTP/1.0\r\nConnection: keep-alive\r\n\r\n{}", second).as_bytes()).await;
+        let responses: Vec<_> = response.split("HTTP/1.1 200 OK\r\n").skip(1).collect();
+        assert_eq!(responses.len(), 2, "{}", response);
+        assert_eq!(header(responses[0], "Connection"), Some("keep-alive"));
+        assert_eq!(body(responses[0]), "first");
+        assert_eq!(header(responses[1], "Connection"), Some("close"));
+        assert_eq!(body(responses[1]), "second");
+    }
+
+    #[tokio::test]
+    async fn expect_continue_is_answered_before_the_body_is_sent() {
+        let dir = temp_dir("expect-continue");
+        let _config = configure(&["-d", dir.to_str().unwrap()]).await;
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let server = tokio::spawn(handle_connection(remote_addr(), server, None));
+        let (mut reader, mut writer) = tokio::io::split(client);
+        writer.write_all(b"POST /files/held.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\nExpect: 100-continue\r\nConnection: close\r\n\r\n").await.unwrap();
+
+        // Nothing more is sent until the server asks for it
+        let interim = b"HTTP/1.1 100 Continue\r\n\r\n";
+        let mut received = vec![0; interim.len()];
+        tokio::time::timeout(Duration::from_secs(5), reader.read_exact(&mut received)).await
+            .expect("no interim response")
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&received), String::from_utf8_lossy(interim));
+
+        writer.write_all(b"body").await.unwrap();
+        let mut response = String::new();
+        reader.read_to_string(&mut response).await.unwrap();
+        server.await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"), "{}", response);
+        assert_eq!(std::fs::read_to_string(dir.join("held.txt")).unwrap(), "body");
+    }
+
+    #[tokio::test]
+    async fn unknown_expectations_get_417() {
+        let dir = temp_dir("expect-unknown");
+        let _config = configure(&["-d", dir.to_str().unwrap()]).await;
+
+        let response = exchange(b"POST /files/a.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\nExpect: 200-ok\r\nConnection: close\r\n\r\nbody").await;
+        assert!(response.starts_with("HTTP/1.1 417 Expectation Failed\r\n"), "{}", response);
+        assert!(!response.contains("100 Continue"), "{}", response);
+        assert!(!dir.join("a.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn form_bodies_decode_plus_and_escapes() {
+        let _config = configure(&[]).await;
+        let form = "name=Ada+Lovelace&note=1%2B1%3D2&tag=a&tag=b";
+        let request = |content_type: &str| format!(
+            "POST /submit HTTP/1.1\r\nHost: localhost\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+            content_type, form.len(), form,
+        );
+
+        let mut ctx = context(request("application/x-www-form-urlencoded; charset=utf-8").as_bytes()).await;
+        let fields: Vec<(String, String)> = ctx.form().await.unwrap();
+        let fields: Vec<_> = fields.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect();
+        assert_eq!(fields, [("name", "Ada Lovelace"), ("note", "1+1=2"), ("tag", "a"), ("tag", "b")]);
+
+        // Into a map a repeated field keeps its last value
+        let mut ctx = context(request("application/x-www-form-urlencoded").as_bytes()).await;
+        let fields: HashMap<String, String> = ctx.form().await.unwrap();
+        assert_eq!(fields["name"], "Ada Lovelace");
+        assert_eq!(fields["tag"], "b");
+
+        let mut ctx = context(request("text/plain").as_bytes()).await;
+        assert!(matches!(ctx.form::<HashMap<String, String>>().await, Err(HttpError::UnsupportedMediaType)));
+    }
+
+    #[test]
+    fn percent_escapes_must_be_complete_hex_and_leave_utf8() {
+        assert_eq!(percent_decode("/echo/hello%20world").unwrap(), "/echo/hello world");
+        assert_eq!(percent_decode("/caf%C3%A9/%2e%2E").unwrap(), "/café/..");
+        for path in ["/bad%zz", "/bad%4", "/bad%", "/bad%%41", "/bad%ff", "/bad%C3"] {
+            assert!(matches!(percent_decode(path), Err(HttpError::BadRequest(_))), "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_escapes_in_the_target_get_400() {
+        let _config = configure(&[]).await;
+        assert_eq!(body(&get("/echo/hello%20world").await), "hello world");
+        for path in ["/echo/%zz", "/echo/%4", "/echo/%ff"] {
+            let response = get(path).await;
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}: {}", path, response);
+        }
+    }
+
+    /// The text of a response's body.
+    async fn body_text(response: &HttpResponse) -> String {
+        let mut text = String::new();
+        response.content().unwrap().content().unwrap().read_to_string(&mut text).await.unwrap();
+        text
+    }
+
+    async fn describe_post(
+        Path(params): Path<HashMap<String, String>>,
+        Query(query): Query<Vec<(String, String)>>,
+        Headers(headers): Headers,
+        Body(body): Body,
+    ) -> HttpResult<HttpResponse> {
+        let description = format!(
+            "{} #{} {:?} by {} saying {}",
+            params["user"], params["post"], query, headers.get("User-Agent").unwrap(), String::from_utf8(body).unwrap(),
+        );
+        Ok(HttpResponse::new(HttpStatus::Ok).with_content(PlainTextContent::new(description)))
+    }
+
+    async fn numbered(Path(number): Path<u32>) -> HttpResult<HttpResponse> {
+        Ok(HttpResponse::new(HttpStatus::Ok).with_content(PlainTextContent::new((number * 2).to_string())))
+    }
+
+    #[tokio::test]
+    async fn extractor_handlers_get_each_argument_filled_in() {
+        let _config = configure(&[]).await;
+        let router = Router::new()
+            .post("/users/{user}/posts/{post}", describe_post)
+            .get("/double/{number}", numbered)
+            .get("/known", known_page);
+
+        let mut ctx = context(b"POST /users/ada/posts/7?sort=new&tag=a HTTP/1.1\r\nHost: localhost\r\nUser-Agent: probe\r\nContent-Length: 2\r\n\r\nhi").await;
+        let response = dispatch(&router, &mut ctx).await.unwrap();
+        assert_eq!(body_text(&response).await, "ada #7 [(\"sort\", \"new\"), (\"tag\", \"a\")] by probe saying hi");
+
+        let mut ctx = context(b"GET /double/21 HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(body_text(&dispatch(&router, &mut ctx).await.unwrap()).await, "42");
+        let mut ctx = context(b"GET /known HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(dispatch(&router, &mut ctx).await.unwrap().status(), HttpStatus::NoContent);
+
+        // An argument that can't be extracted answers for the handler
+        let mut ctx = context(b"GET /double/many HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(matches!(dispatch(&router, &mut ctx).await, Err(HttpError::BadRequest(_))));
+    }
+
+    #[tokio::test]
+    async fn directories_redirect_to_a_trailing_slash_and_serve_their_index() {
+        let dir = temp_dir("directory-index");
+        std::fs::create_dir_all(dir.join("site/empty")).unwrap();
+        std::fs::write(dir.join("site/index.html"), "<p>home</p>").unwrap();
+        let _config = configure(&["-d", dir.to_str().unwrap()]).await;
+
+        let response = get("/files/site?lang=en").await;
+        assert!(response.starts_with("HTTP/1.1 301 Moved Permanently\r\n"), "{}", response);
+        assert_eq!(header(&response, "Location"), Some("/files/site/?lang=en"));
+
+        let response = get("/files/site/").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert_eq!(header(&response, "Content-Type"), Some("text/html; charset=utf-8"));
+        assert_eq!(body(&response), "<p>home</p>");
+
+        // Without an index or --autoindex there's nothing to redirect to
+        let response = get("/files/site/empty").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+        drop(_config);
+
+        let _config = configure(&["-d", dir.to_str().unwrap(), "--no-slash-redirect"]).await;
+        assert_eq!(body(&get("/files/site").await), "<p>home</p>");
+    }
+
+    #[tokio::test]
+    async fn mounts_serve_paths_with_their_prefix_stripped() {
+        let outer = temp_dir("mount-outer");
+        let inner = temp_dir("mount-inner");
+        std::fs::write(outer.join("app.js"), "outer app").unwrap();
+        std::fs::create_dir(outer.join("images")).unwrap();
+        std::fs::write(outer.join("images/logo.svg"), "shadowed").unwrap();
+        std::fs::write(inner.join("logo.svg"), "inner logo").unwrap();
+        let outer_mount = format!("/assets={}", outer.display());
+        let inner_mount = format!("/assets/images={}", inner.display());
+        let _config = configure(&["--mount", &outer_mount, "--mount", &inner_mount]).await;
+
+        for (path, expected) in [("/assets/app.js", "outer app"), ("/assets/images/logo.svg", "inner logo")] {
+            let mut ctx = context(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes()).await;
+            let response = mounted_files(&mut ctx).await.unwrap();
+            assert_eq!(body_text(&response).await, expected, "{}", path);
+        }
+        let mut ctx = context(b"GET /assets/images/app.js HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(matches!(mounted_files(&mut ctx).await, Err(HttpError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn tls_connection_is_kept_alive_across_requests() {
+        let pki = TestPki::new("tls-keep-alive");
+        let args = pki.args(false);
+        let _config = configure(&args.iter().map(String::as_str).collect::<Vec<_>>()).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, tls::acceptor().await.unwrap(), std::future::pending()));
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut stream = pki.connector(false).connect(ServerName::try_from("localhost").unwrap(), stream).await.unwrap();
+        stream.write_all(b"GET /echo/first HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let mut first = Vec::new();
+        while !first.ends_with(b"\r\n\r\nfirst") {
+            assert_ne!(stream.read_buf(&mut first).await.unwrap(), 0, "{}", String::from_utf8_lossy(&first));
+        }
+
+        // The same TLS session carries the next request
+        stream.write_all(b"GET /echo/second HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut second = String::new();
+        stream.read_to_string(&mut second).await.unwrap();
+        assert!(second.starts_with("HTTP/1.1 200 OK\r\n"), "{}", second);
+        assert_eq!(body(&second), "second");
+    }
+
+    /// Answers with the common name of the client certificate the request came with.
+    async fn whoami(ctx: &mut RequestContext) -> HttpResult<HttpResponse> {
+        let name = ctx.client_cert_subject.as_ref().and_then(|subject| subject.common_name.clone());
+        Ok(HttpResponse::new(HttpStatus::Ok).with_content(PlainTextContent::new(name.unwrap_or_default())))
+    }
+
+    #[tokio::test]
+    async fn handlers_see_the_verified_client_certificate() {
+        let pki = TestPki::new("tls-client-context");
+        let args = pki.args(true);
+        let _config = configure(&args.iter().map(String::as_str).collect::<Vec<_>>()).await;
+
+        let (server, client) = tls_handshake(tls::acceptor().await.unwrap().unwrap(), pki.connector(true)).await;
+        let (server, mut client) = (server.unwrap(), client.unwrap());
+        client.write_all(b"GET /whoami HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+
+        // Built the way a TLS connection's requests are
+        let subject = tls::client_cert_subject(&server);
+        let (mut reader, writer) = split_connection(server).await;
+        let head = read_request_head(&mut reader).await.unwrap().unwrap();
+        let mut ctx = RequestContext::new(reader, writer, head, remote_addr(), subject);
+        assert_eq!(body_text(&whoami(&mut ctx).await.unwrap()).await, "alice");
+    }
+
+    #[tokio::test]
+    async fn redirect_keeps_the_target_as_sent() {
+        let _config = configure(&[]).await;
+
+        let response = redirected(b"POST /files/a%20b.txt?q=%2F&x HTTP/1.1\r\nHost: example.com\r\nContent-Length: 0\r\n\r\n", 443).await;
+        assert!(response.starts_with("HTTP/1.1 301 Moved Permanently\r\n"), "{}", response);
+        assert_eq!(header(&response, "Location"), Some("https://example.com/files/a%20b.txt?q=%2F&x"));
+
+        // An absolute-form target keeps its path and query but not its scheme
+        let response = redirected(b"GET http://example.com/echo/a?b=c HTTP/1.1\r\nHost: example.com\r\n\r\n", 443).await;
+        assert_eq!(header(&response, "Location"), Some("https://example.com/echo/a?b=c"));
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_after_a_close_are_not_answered() {
+        let dir = temp_dir("pipelined-close");
+        let _config = configure(&["-d", dir.to_str().unwrap()]).await;
+
+        let response = exchange(concat!(
+            "GET /echo/first HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            "GET /files/missing.txt HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            "GET /echo/last HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            "GET /echo/unanswered HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        ).as_bytes()).await;
+
+        // A failed request in the middle doesn't stop the ones after it
+        let statuses: Vec<_> = response.match_indices("HTTP/1.1 ").map(|(at, _)| &response[at + 9..at + 12]).collect();
+        assert_eq!(statuses, ["200", "404", "200"], "{}", response);
+        assert!(response.ends_with("\r\n\r\nlast"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn chunked_upload_at_the_limit_is_saved_and_one_over_closes() {
+        let dir = temp_dir("chunked-at-limit");
+        let _config = configure(&["-d", dir.to_str().unwrap(), "--max-body-size", "10"]).await;
+
+        let response = exchange(b"POST /files/exact.txt HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n4\r\nhell\r\n6\r\no worl\r\n0\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"), "{}", response);
+        assert_eq!(std::fs::read(dir.join("exact.txt")).unwrap(), b"hello worl");
+
+        // The rest of the oversized body isn't read, so nothing after it is taken for a request
+        let response = exchange(concat!(
+            "POST /files/over.txt HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nhell\r\n7\r\no world\r\n0\r\n\r\n",
+            "GET /echo/after HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        ).as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 413 Content Too Large\r\n"), "{}", response);
+        assert_eq!(header(&response, "Connection"), Some("close"));
+        assert!(!response.contains("after"), "{}", response);
+        assert!(!dir.join("over.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn unread_body_is_skipped_before_the_next_request() {
+        let _config = configure(&[]).await;
+
+        // The echo handler never asks for its body
+        let response = exchange(concat!(
+            "GET /echo/first HTTP/1.1\r\nHost: localhost\r\nContent-Length: 27\r\n\r\nGET /echo/smuggled HTTP/1.1",
+            "GET /echo/second HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        ).as_bytes()).await;
+
+        let bodies: Vec<_> = response.split("HTTP/1.1 200 OK\r\n").skip(1).map(body).collect();
+        assert_eq!(bodies, ["first", "second"], "{}", response);
+    }
+}
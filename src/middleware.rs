@@ -0,0 +1,125 @@
+use std::future::Future;
+use crate::error::HttpResult;
+use crate::http::HttpResponse;
+use crate::router::{Handler, HandlerFuture};
+use crate::RequestContext;
+
+/// A step wrapped around request handling, such as adding headers to every response. It's
+/// implemented for every `async fn` and closure taking `&mut RequestContext` and the `Next`
+/// step, which it may run or skip to answer on its own.
+pub trait Middleware: Send + Sync + 'static {
+    fn call<'a>(&'a self, ctx: &'a mut RequestContext, next: Next<'a>) -> HandlerFuture<'a>;
+}
+
+/// Names the future a middleware function returns, as `HandlerFn` does for handlers.
+pub trait MiddlewareFn<'a>: Fn(&'a mut RequestContext, Next<'a>) -> Self::Fut {
+    type Fut: Future<Output = HttpResult<HttpResponse>> + Send + 'a;
+}
+
+impl<'a, F, Fut> MiddlewareFn<'a> for F
+where
+    F: Fn(&'a mut RequestContext, Next<'a>) -> Fut,
+    Fut: Future<Output = HttpResult<HttpResponse>> + Send + 'a,
+{
+    type Fut = Fut;
+}
+
+impl<F> Middleware for F
+where
+    F: for<'a> MiddlewareFn<'a> + Send + Sync + 'static,
+{
+    fn call<'a>(&'a self, ctx: &'a mut RequestContext, next: Next<'a>) -> HandlerFuture<'a> {
+        Box::pin(self(ctx, next))
+    }
+}
+
+/// The rest of the chain: the remaining middleware, then the handler at its end.
+pub struct Next<'a> {
+    layers: &'a [Box<dyn Middleware>],
+    endpoint: &'a dyn Handler,
+}
+
+impl<'a> Next<'a> {
+    /// Starts a chain that runs `layers` in order, outermost first, before `endpoint`.
+    pub fn new(layers: &'a [Box<dyn Middleware>], endpoint: &'a dyn Handler) -> Self {
+        Self { layers, endpoint }
+    }
+
+    pub fn run<'b>(self, ctx: &'b mut RequestContext) -> HandlerFuture<'b>
+    where
+        'a: 'b,
+    {
+        match self.layers.split_first() {
+            Some((layer, layers)) => layer.call(ctx, Next { layers, endpoint: self.endpoint }),
+            None => self.endpoint.call(ctx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use crate::http::HttpStatus;
+    use crate::testing::context;
+    use super::*;
+
+    /// Each step notes itself in a request header on the way in and a response header on the
+    /// way out.
+    async fn outer(ctx: &mut RequestContext, next: Next<'_>) -> HttpResult<HttpResponse> {
+        ctx.headers.append("X-Order", "outer".to_string());
+        let response = next.run(ctx).await?;
+        Ok(unwound(response, "outer"))
+    }
+
+    async fn inner(ctx: &mut RequestContext, next: Next<'_>) -> HttpResult<HttpResponse> {
+        ctx.headers.append("X-Order", "inner".to_string());
+        let response = next.run(ctx).await?;
+        Ok(unwound(response, "inner"))
+    }
+
+    fn unwound(response: HttpResponse, step: &str) -> HttpResponse {
+        let unwound = match response.headers().get("X-Unwound") {
+            Some(steps) => format!("{}, {}", steps, step),
+            None => step.to_string(),
+        };
+        response.with_header("X-Unwound", unwound)
+    }
+
+    async fn gate(ctx: &mut RequestContext, next: Next<'_>) -> HttpResult<HttpResponse> {
+        if ctx.headers.contains_key("X-Blocked") {
+            return Ok(HttpResponse::new(HttpStatus::Forbidden));
+        }
+        next.run(ctx).await
+    }
+
+    async fn endpoint(ctx: &mut RequestContext) -> HttpResult<HttpResponse> {
+        ctx.headers.append("X-Order", "handler".to_string());
+        Ok(HttpResponse::new(HttpStatus::NoContent))
+    }
+
+    fn order(ctx: &RequestContext) -> Vec<&String> {
+        ctx.headers.get_all("X-Order").collect()
+    }
+
+    #[tokio::test]
+    async fn layers_run_outermost_first_and_unwind_in_reverse() {
+        let layers: Vec<Box<dyn Middleware>> = vec![Box::new(outer), Box::new(gate), Box::new(inner)];
+        let mut ctx = context(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+
+        let response = Next::new(&layers, &endpoint).run(&mut ctx).await.unwrap();
+        assert_eq!(response.status(), HttpStatus::NoContent);
+        assert_eq!(order(&ctx), ["outer", "inner", "handler"]);
+        assert_eq!(response.headers().get("X-Unwound").map(String::as_str), Some("inner, outer"));
+    }
+
+    #[tokio::test]
+    async fn a_layer_can_answer_without_the_rest() {
+        let layers: Vec<Box<dyn Middleware>> = vec![Box::new(outer), Box::new(gate), Box::new(inner)];
+        let mut ctx = context(b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Blocked: yes\r\n\r\n").await;
+
+        let response = Next::new(&layers, &endpoint).run(&mut ctx).await.unwrap();
+        assert_eq!(response.status(), HttpStatus::Forbidden);
+        assert_eq!(order(&ctx), ["outer"]);
+        assert_eq!(response.headers().get("X-Unwound").map(String::as_str), Some("outer"));
+    }
+}
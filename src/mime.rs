@@ -0,0 +1,110 @@
+use std::path::Path;
+use anyhow::{bail, Result};
+
+/// Content types of common static files by extension. Text types name their charset, which
+/// browsers would otherwise have to guess.
+const TYPES: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("md", "text/markdown; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    ("xml", "application/xml"),
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("ico", "image/x-icon"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+];
+
+/// A `--mime-type EXT=TYPE` entry, serving files ending in `.EXT` as `content_type`.
+#[derive(Clone, Debug)]
+pub struct MimeType {
+    pub extension: String,
+    pub content_type: String,
+}
+
+impl MimeType {
+    pub fn parse(value: &str) -> Result<Self> {
+        let Some((extension, content_type)) = value.split_once('=') else {
+            bail!("Expected EXT=TYPE but got {}", value);
+        };
+        let extension = extension.strip_prefix('.').unwrap_or(extension);
+        if extension.is_empty() || content_type.is_empty() {
+            bail!("Expected EXT=TYPE but got {}", value);
+        }
+
+        Ok(Self { extension: extension.to_ascii_lowercase(), content_type: content_type.to_string() })
+    }
+}
+
+/// The content type for `path` going by its extension, looking at `overrides` before the
+/// built-in table.
+pub fn content_type<'a>(path: &Path, overrides: &'a [MimeType]) -> Option<&'a str> {
+    let extension = path.extension()?.to_str()?;
+    overrides.iter()
+        .find(|mime_type| mime_type.extension.eq_ignore_ascii_case(extension))
+        .map(|mime_type| mime_type.content_type.as_str())
+        .or_else(|| TYPES.iter().find(|(known, _)| known.eq_ignore_ascii_case(extension)).map(|(_, content_type)| *content_type))
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use crate::testing::{body, configure, exchange, header, temp_dir};
+    use super::*;
+
+    #[test]
+    fn overrides_win_over_the_built_in_types() {
+        let overrides = [MimeType::parse(".JS=application/x-custom").unwrap(), MimeType::parse("log=text/plain").unwrap()];
+        assert_eq!(content_type(Path::new("app.js"), &overrides), Some("application/x-custom"));
+        assert_eq!(content_type(Path::new("server.LOG"), &overrides), Some("text/plain"));
+        assert_eq!(content_type(Path::new("style.css"), &overrides), Some("text/css; charset=utf-8"));
+        assert_eq!(content_type(Path::new("archive.unknown"), &overrides), None);
+        assert_eq!(content_type(Path::new("README"), &overrides), None);
+    }
+
+    #[test]
+    fn malformed_entries_are_rejected() {
+        for value in ["js", "=text/plain", "js=", "."] {
+            assert!(MimeType::parse(value).is_err(), "{}", value);
+        }
+    }
+
+    #[tokio::test]
+    async fn configured_types_are_served() {
+        let dir = temp_dir("mime-overrides");
+        std::fs::write(dir.join("data.geojson"), "{}").unwrap();
+        std::fs::write(dir.join("page.html"), "<p>hi</p>").unwrap();
+        let _config = configure(&["-d", dir.to_str().unwrap(), "--mime-type", "geojson=application/geo+json", "--mime-type", "html=text/plain"]).await;
+
+        let response = exchange(b"GET /files/data.geojson HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+        assert_eq!(header(&response, "Content-Type"), Some("application/geo+json"));
+        assert_eq!(body(&response), "{}");
+        let response = exchange(b"GET /files/page.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+        assert_eq!(header(&response, "Content-Type"), Some("text/plain"));
+    }
+}
@@ -0,0 +1,70 @@
+use anyhow::{bail, Result};
+
+/// A `--mount PREFIX=DIR` entry serving the files in `directory` under the URL path `prefix`,
+/// which always starts and ends with `/`.
+#[derive(Clone, Debug)]
+pub struct Mount {
+    pub prefix: String,
+    pub directory: String,
+}
+
+impl Mount {
+    pub fn parse(value: &str) -> Result<Self> {
+        let Some((prefix, directory)) = value.split_once('=') else {
+            bail!("Expected PREFIX=DIR but got {}", value);
+        };
+        if !prefix.starts_with('/') {
+            bail!("Mount prefix {} doesn't start with /", prefix);
+        }
+        if prefix.contains(['{', '}']) {
+            bail!("Mount prefix {} can't hold route parameters", prefix);
+        }
+        if directory.is_empty() {
+            bail!("Missing directory in {}", value);
+        }
+
+        let prefix = match prefix.ends_with('/') {
+            true => prefix.to_string(),
+            false => format!("{}/", prefix),
+        };
+        Ok(Self { prefix, directory: directory.to_string() })
+    }
+}
+
+/// The mount serving `path`, the one with the longest prefix if several do.
+pub fn find<'a>(mounts: &'a [Mount], path: &str) -> Option<&'a Mount> {
+    mounts.iter()
+        .filter(|mount| path.starts_with(&mount.prefix))
+        .max_by_key(|mount| mount.prefix.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use super::*;
+
+    fn mounts(entries: &[&str]) -> Vec<Mount> {
+        entries.iter().map(|entry| Mount::parse(entry).unwrap()).collect()
+    }
+
+    #[test]
+    fn prefixes_always_end_in_a_slash() {
+        assert_eq!(Mount::parse("/static=public").unwrap().prefix, "/static/");
+        assert_eq!(Mount::parse("/static/=public").unwrap().prefix, "/static/");
+        for value in ["static=public", "/static", "/static=", "/{dir}=public"] {
+            assert!(Mount::parse(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn longest_matching_prefix_wins() {
+        let mounts = mounts(&["/assets=a", "/assets/images=b", "/=c"]);
+        let directory = |path: &str| find(&mounts, path).map(|mount| mount.directory.as_str());
+        assert_eq!(directory("/assets/images/logo.png"), Some("b"));
+        assert_eq!(directory("/assets/app.js"), Some("a"));
+        // Prefixes match whole segments only
+        assert_eq!(directory("/assets-old/app.js"), Some("c"));
+        assert_eq!(directory("/assets/imagesx/logo.png"), Some("a"));
+        assert!(find(&mounts[..2], "/other").is_none());
+    }
+}
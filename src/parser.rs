@@ -0,0 +1,149 @@
+use std::fmt::{Display, Formatter};
+use nom::bytes::complete::{tag, take_while, take_while1, take_while_m_n};
+use nom::character::complete::line_ending;
+use nom::combinator::map;
+use nom::sequence::{delimited, preceded, separated_pair, terminated, tuple};
+use nom::IResult;
+
+type ParseResult<'a, T> = IResult<&'a [u8], T>;
+
+/// Which part of a request head didn't follow the RFC 9112 grammar.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    Method,
+    Target,
+    Version,
+    Header,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Method => write!(f, "Malformed request method"),
+            Self::Target => write!(f, "Malformed request target"),
+            Self::Version => write!(f, "Malformed HTTP version"),
+            Self::Header => write!(f, "Malformed header line"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub struct RequestLine<'a> {
+    pub method: &'a str,
+    pub target: &'a str,
+    /// The major and minor version, `None` for an HTTP/0.9 request line that has none
+    pub version: Option<(u8, u8)>,
+}
+
+/// Whether `b` may appear in an RFC 9110 token such as a method or header name.
+pub fn is_token_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// Whether `b` may appear in a header value: visible characters, blanks and obs-text.
+fn is_field_char(b: u8) -> bool {
+    b.is_ascii_graphic() || b == b' ' || b == b'\t' || b >= 0x80
+}
+
+fn token(input: &[u8]) -> ParseResult<'_, &[u8]> {
+    take_while1(is_token_char)(input)
+}
+
+/// The separator between request line parts. RFC 9112 asks for a single space, but allows
+/// being lenient about runs of them.
+fn spaces(input: &[u8]) -> ParseResult<'_, &[u8]> {
+    take_while1(|b| b == b' ')(input)
+}
+
+/// The line's end, tolerating trailing spaces and a bare LF.
+fn line_end(input: &[u8]) -> ParseResult<'_, &[u8]> {
+    preceded(take_while(|b| b == b' '), line_ending)(input)
+}
+
+fn digit(input: &[u8]) -> ParseResult<'_, u8> {
+    map(take_while_m_n(1, 1, |b: u8| b.is_ascii_digit()), |digit: &[u8]| digit[0] - b'0')(input)
+}
+
+fn version(input: &[u8]) -> ParseResult<'_, (u8, u8)> {
+    preceded(tag("HTTP/"), separated_pair(digit, tag("."), digit))(input)
+}
+
+/// Parses `METHOD TARGET HTTP/x.y` followed by its line ending.
+pub fn request_line(line: &[u8]) -> Result<RequestLine<'_>, ParseError> {
+    let (rest, method) = terminated(token, spaces)(line).map_err(|_| ParseError::Method)?;
+    let (rest, target) = take_while1(|b: u8| b.is_ascii_graphic())(rest)
+        .map_err(|_: nom::Err<nom::error::Error<&[u8]>>| ParseError::Target)?;
+    let version = match line_end(rest) {
+        Ok(_) => None,
+        Err(_) => {
+            let (_, version) = delimited(spaces, version, line_end)(rest).map_err(|_| ParseError::Version)?;
+            Some(version)
+        }
+    };
+
+    // Both only hold ASCII at this point
+    Ok(RequestLine {
+        method: std::str::from_utf8(method).map_err(|_| ParseError::Method)?,
+        target: std::str::from_utf8(target).map_err(|_| ParseError::Target)?,
+        version,
+    })
+}
+
+/// Parses a `Name: value` header line followed by its line ending, returning the name and the
+/// value without surrounding whitespace. Folded continuation lines and whitespace before the
+/// colon are rejected, since proxies disagree on how to read them.
+pub fn header_line(line: &[u8]) -> Result<(&str, &str), ParseError> {
+    let (_, (name, _, value, _)) = tuple((token, tag(":"), take_while(is_field_char), line_ending))(line)
+        .map_err(|_: nom::Err<nom::error::Error<&[u8]>>| ParseError::Header)?;
+
+    let name = std::str::from_utf8(name).map_err(|_| ParseError::Header)?;
+    let value = std::str::from_utf8(value).map_err(|_| ParseError::Header)?;
+    Ok((name, value.trim_matches([' ', '\t'])))
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use super::*;
+
+    type Parts<'a> = (&'a str, &'a str, Option<(u8, u8)>);
+
+    fn parts(line: &[u8]) -> Result<Parts<'_>, ParseError> {
+        request_line(line).map(|line| (line.method, line.target, line.version))
+    }
+
+    #[test]
+    fn request_line_parts() {
+        assert_eq!(parts(b"GET /index.html HTTP/1.1\r\n"), Ok(("GET", "/index.html", Some((1, 1)))));
+        assert_eq!(parts(b"M-SEARCH * HTTP/1.0\n"), Ok(("M-SEARCH", "*", Some((1, 0)))));
+        assert_eq!(parts(b"GET  /a?b=c  HTTP/1.1 \r\n"), Ok(("GET", "/a?b=c", Some((1, 1)))));
+        assert_eq!(parts(b"GET /old\r\n"), Ok(("GET", "/old", None)));
+    }
+
+    #[test]
+    fn request_line_errors_name_the_bad_part() {
+        assert_eq!(parts(b"G@T / HTTP/1.1\r\n").err(), Some(ParseError::Method));
+        assert_eq!(parts(b" / HTTP/1.1\r\n").err(), Some(ParseError::Method));
+        assert_eq!(parts(b"GET \r\n").err(), Some(ParseError::Target));
+        assert_eq!(parts(b"GET /caf\xc3\xa9 HTTP/1.1\r\n").err(), Some(ParseError::Version));
+        assert_eq!(parts(b"GET / HTTP/11\r\n").err(), Some(ParseError::Version));
+        assert_eq!(parts(b"GET / http/1.1\r\n").err(), Some(ParseError::Version));
+        assert_eq!(parts(b"GET / HTTP/1.1").err(), Some(ParseError::Version));
+    }
+
+    #[test]
+    fn header_line_trims_the_value() {
+        assert_eq!(header_line(b"Host: example.com\r\n"), Ok(("Host", "example.com")));
+        assert_eq!(header_line(b"X-Empty:\r\n"), Ok(("X-Empty", "")));
+        assert_eq!(header_line(b"Accept:\t text/html, */* \n"), Ok(("Accept", "text/html, */*")));
+        assert_eq!(header_line(b"Name: caf\xc3\xa9\r\n"), Ok(("Name", "caf\u{e9}")));
+    }
+
+    #[test]
+    fn header_line_rejects_ambiguous_forms() {
+        for line in [&b"Host : example.com\r\n"[..], b" folded\r\n", b"No colon\r\n", b"Bad\0: x\r\n", b"X: a\0b\r\n", b"X: unterminated"] {
+            assert_eq!(header_line(line), Err(ParseError::Header), "{:?}", String::from_utf8_lossy(line));
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use anyhow::{bail, Result};
+use crate::http::{HttpResponse, HttpStatus};
+
+/// A `--redirect FROM=TO[,STATUS]` entry sending requests for the path `from` to `to`.
+#[derive(Clone, Debug)]
+pub struct StaticRedirect {
+    pub from: String,
+    pub to: String,
+    pub status: HttpStatus,
+}
+
+impl StaticRedirect {
+    pub fn parse(value: &str) -> Result<Self> {
+        let Some((from, rest)) = value.split_once('=') else {
+            bail!("Expected FROM=TO[,STATUS] but got {}", value);
+        };
+        if !from.starts_with('/') {
+            bail!("Redirected path must start with / in {}", value);
+        }
+
+        let (to, status) = match rest.rsplit_once(',') {
+            Some((to, status)) => (to, status.trim()),
+            None => (rest, "301"),
+        };
+        let status = match status {
+            "301" => HttpStatus::MovedPermanently,
+            "302" => HttpStatus::Found,
+            "303" => HttpStatus::SeeOther,
+            "307" => HttpStatus::TemporaryRedirect,
+            "308" => HttpStatus::PermanentRedirect,
+            _ => bail!("Redirect status must be 301, 302, 303, 307 or 308 in {}", value),
+        };
+        if to.is_empty() {
+            bail!("Missing redirect target in {}", value);
+        }
+
+        Ok(Self { from: from.to_string(), to: to.to_string(), status })
+    }
+
+    /// Redirects to the target, carrying the request's query over unless the target has its own.
+    pub fn response(&self, query: Option<&str>) -> HttpResponse {
+        match query {
+            Some(query) if !self.to.contains('?') => HttpResponse::redirect(self.status, &format!("{}?{}", self.to, query)),
+            _ => HttpResponse::redirect(self.status, &self.to),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use crate::http::HttpStatus;
+    use super::StaticRedirect;
+
+    #[test]
+    fn parses_the_target_and_status() {
+        let redirect = StaticRedirect::parse("/old=/new").unwrap();
+        assert_eq!((redirect.from.as_str(), redirect.to.as_str(), redirect.status), ("/old", "/new", HttpStatus::MovedPermanently));
+
+        let redirect = StaticRedirect::parse("/docs=https://example.com/docs, 308").unwrap();
+        assert_eq!((redirect.to.as_str(), redirect.status), ("https://example.com/docs", HttpStatus::PermanentRedirect));
+
+        for (status, expected) in [("302", HttpStatus::Found), ("303", HttpStatus::SeeOther), ("307", HttpStatus::TemporaryRedirect)] {
+            assert_eq!(StaticRedirect::parse(&format!("/a=/b,{}", status)).unwrap().status, expected);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_entries() {
+        for value in ["/old", "old=/new", "/old=", "/old=,302", "/old=/new,200", "/old=/new,abc"] {
+            assert!(StaticRedirect::parse(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn response_carries_the_query_over() {
+        let location = |redirect: &str, query| StaticRedirect::parse(redirect).unwrap().response(query).headers().get("Location").cloned();
+
+        assert_eq!(location("/old=/new", Some("page=2")), Some("/new?page=2".to_string()));
+        assert_eq!(location("/old=/new?tab=a", Some("page=2")), Some("/new?tab=a".to_string()));
+        assert_eq!(location("/old=/new page,307", None), Some("/new%20page".to_string()));
+    }
+}
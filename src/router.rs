@@ -0,0 +1,371 @@
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use anyhow::{bail, Result};
+use itertools::Itertools;
+use crate::error::HttpResult;
+use crate::extract::FromRequest;
+use crate::http::HttpResponse;
+use crate::{HttpMethod, RequestContext};
+
+pub type HandlerFuture<'a> = Pin<Box<dyn Future<Output = HttpResult<HttpResponse>> + Send + 'a>>;
+
+/// Something that answers a request. It's implemented for every `async fn` and closure that
+/// takes `&mut RequestContext` and returns `HttpResult<HttpResponse>`, so handlers can live in
+/// any module and be registered as they are.
+pub trait Handler: Send + Sync + 'static {
+    fn call<'a>(&'a self, ctx: &'a mut RequestContext) -> HandlerFuture<'a>;
+}
+
+/// Names the future a handler function returns for a context borrowed for `'a`, which a plain
+/// `Fn` bound can't do since the future's type depends on the borrow.
+pub trait HandlerFn<'a>: Fn(&'a mut RequestContext) -> Self::Fut {
+    type Fut: Future<Output = HttpResult<HttpResponse>> + Send + 'a;
+}
+
+impl<'a, F, Fut> HandlerFn<'a> for F
+where
+    F: Fn(&'a mut RequestContext) -> Fut,
+    Fut: Future<Output = HttpResult<HttpResponse>> + Send + 'a,
+{
+    type Fut = Fut;
+}
+
+impl<F> Handler for F
+where
+    F: for<'a> HandlerFn<'a> + Send + Sync + 'static,
+{
+    fn call<'a>(&'a self, ctx: &'a mut RequestContext) -> HandlerFuture<'a> {
+        Box::pin(self(ctx))
+    }
+}
+
+/// Anything the router can turn into a `Handler`: handlers themselves, and `async fn`s taking
+/// up to four extractors such as `Path<T>` or `Json<T>`. `Args` only tells the two apart.
+pub trait IntoHandler<Args> {
+    fn into_handler(self) -> Box<dyn Handler>;
+}
+
+/// Marks handlers that take the whole `RequestContext`.
+pub struct WithContext;
+
+impl<H: Handler> IntoHandler<WithContext> for H {
+    fn into_handler(self) -> Box<dyn Handler> {
+        Box::new(self)
+    }
+}
+
+/// Fills in each extractor from the request in parameter order, then calls `f` with them.
+struct ExtractorHandler<F, Args> {
+    f: F,
+    args: PhantomData<fn() -> Args>,
+}
+
+macro_rules! extractor_handler {
+    ($($arg:ident),*) => {
+        impl<F, Fut, $($arg),*> IntoHandler<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> Fut + Send + Sync + 'static,
+            Fut: Future<Output = HttpResult<HttpResponse>> + Send + 'static,
+            $($arg: FromRequest,)*
+        {
+            fn into_handler(self) -> Box<dyn Handler> {
+                Box::new(ExtractorHandler { f: self, args: PhantomData::<fn() -> ($($arg,)*)> })
+            }
+        }
+
+        impl<F, Fut, $($arg),*> Handler for ExtractorHandler<F, ($($arg,)*)>
+        where
+            F: Fn($($arg),*) -> Fut + Send + Sync + 'static,
+            Fut: Future<Output = HttpResult<HttpResponse>> + Send + 'static,
+            $($arg: FromRequest,)*
+        {
+            #[allow(non_snake_case, unused_variables)]
+            fn call<'a>(&'a self, ctx: &'a mut RequestContext) -> HandlerFuture<'a> {
+                Box::pin(async move {
+                    $(let $arg = $arg::from_request(ctx).await?;)*
+                    (self.f)($($arg),*).await
+                })
+            }
+        }
+    };
+}
+
+extractor_handler!();
+extractor_handler!(T1);
+extractor_handler!(T1, T2);
+extractor_handler!(T1, T2, T3);
+extractor_handler!(T1, T2, T3, T4);
+
+/// One piece of a route pattern such as `/files/{*path}`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    /// `{name}`, matching up to the next `/`
+    Param(String),
+    /// `{*name}`, matching the rest of the path, which may be empty
+    CatchAll(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    pub fn parse(source: &str) -> Result<Self> {
+        if !source.starts_with('/') {
+            bail!("Route pattern {} doesn't start with /", source);
+        }
+
+        let mut segments = Vec::new();
+        let mut rest = source;
+        while !rest.is_empty() {
+            let Some(start) = rest.find('{') else {
+                segments.push(Segment::Literal(rest.to_string()));
+                break;
+            };
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_string()));
+            }
+
+            let Some(end) = rest[start..].find('}').map(|end| start + end) else {
+                bail!("Unclosed parameter in route pattern {}", source);
+            };
+            let segment = match &rest[start + 1..end] {
+                name if name.is_empty() || name == "*" => bail!("Unnamed parameter in route pattern {}", source),
+                name => match name.strip_prefix('*') {
+                    Some(name) => Segment::CatchAll(name.to_string()),
+                    None => Segment::Param(name.to_string()),
+                },
+            };
+            if matches!(segments.last(), Some(Segment::Param(_) | Segment::CatchAll(_))) {
+                bail!("Adjacent parameters in route pattern {}", source);
+            }
+            segments.push(segment);
+            rest = &rest[end + 1..];
+        }
+
+        if segments.iter().rev().skip(1).any(|segment| matches!(segment, Segment::CatchAll(_))) {
+            bail!("Catch-all parameter isn't last in route pattern {}", source);
+        }
+
+        Ok(Self { source: source.to_string(), segments })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// The literal part ahead of the first parameter, such as `/files/` for `/files/{*path}`.
+    pub fn prefix(&self) -> &str {
+        match self.segments.first() {
+            Some(Segment::Literal(literal)) => literal,
+            _ => "",
+        }
+    }
+
+    /// Matches a decoded path, returning the captured parameters in pattern order.
+    pub fn matches(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let mut params = Vec::new();
+        let mut rest = path;
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => rest = rest.strip_prefix(literal.as_str())?,
+                Segment::Param(name) => {
+                    let end = rest.find('/').unwrap_or(rest.len());
+                    if end == 0 {
+                        return None;
+                    }
+                    params.push((name.clone(), rest[..end].to_string()));
+                    rest = &rest[end..];
+                }
+                Segment::CatchAll(name) => {
+                    params.push((name.clone(), rest.to_string()));
+                    rest = "";
+                }
+            }
+        }
+
+        rest.is_empty().then_some(params)
+    }
+}
+
+pub struct Route {
+    pattern: Pattern,
+    handlers: Vec<(HttpMethod, Box<dyn Handler>)>,
+}
+
+impl Route {
+    pub fn pattern(&self) -> &Pattern {
+        &self.pattern
+    }
+
+    /// The handler for `method`, with HEAD answered by the GET handler.
+    pub fn handler(&self, method: &HttpMethod) -> Option<&dyn Handler> {
+        self.handlers.iter()
+            .find(|(registered, _)| registered == method.for_dispatch())
+            .map(|(_, handler)| handler.as_ref())
+    }
+
+    /// HEAD is implied wherever GET is allowed, and OPTIONS everywhere.
+    pub fn allows(&self, method: &HttpMethod) -> bool {
+        self.implied_methods().any(|allowed| allowed == *method)
+    }
+
+    pub fn allow(&self) -> String {
+        self.implied_methods().map(|method| method.to_string()).join(", ")
+    }
+
+    pub fn implied_methods(&self) -> impl Iterator<Item = HttpMethod> + '_ {
+        self.handlers.iter()
+            .flat_map(|(method, _)| match method {
+                HttpMethod::Get => vec![HttpMethod::Get, HttpMethod::Head],
+                method => vec![method.clone()],
+            })
+            .chain([HttpMethod::Options])
+    }
+}
+
+/// Routes in the order they were registered, the first one whose pattern matches the path wins.
+/// Paths no route matches go to the fallback, or get a 404 without one.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: Option<Box<dyn Handler>>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `handler` for `method` requests to paths matching `pattern`. Methods registered
+    /// under the same pattern share one route, which answers the others with a 405. Panics if
+    /// the pattern doesn't parse or the method already has a handler there, since routes are
+    /// fixed when the server is built.
+    pub fn on<Args>(mut self, method: HttpMethod, pattern: &str, handler: impl IntoHandler<Args>) -> Self {
+        let index = match self.routes.iter().position(|route| route.pattern.as_str() == pattern) {
+            Some(index) => index,
+            None => {
+                let pattern = Pattern::parse(pattern).unwrap_or_else(|e| panic!("{}", e));
+                self.routes.push(Route { pattern, handlers: Vec::new() });
+                self.routes.len() - 1
+            }
+        };
+
+        let route = &mut self.routes[index];
+        if route.handlers.iter().any(|(registered, _)| *registered == method) {
+            panic!("{} {} is registered twice", method, pattern);
+        }
+        route.handlers.push((method, handler.into_handler()));
+        self
+    }
+
+    pub fn get<Args>(self, pattern: &str, handler: impl IntoHandler<Args>) -> Self {
+        self.on(HttpMethod::Get, pattern, handler)
+    }
+
+    pub fn post<Args>(self, pattern: &str, handler: impl IntoHandler<Args>) -> Self {
+        self.on(HttpMethod::Post, pattern, handler)
+    }
+
+    pub fn put<Args>(self, pattern: &str, handler: impl IntoHandler<Args>) -> Self {
+        self.on(HttpMethod::Put, pattern, handler)
+    }
+
+    pub fn patch<Args>(self, pattern: &str, handler: impl IntoHandler<Args>) -> Self {
+        self.on(HttpMethod::Patch, pattern, handler)
+    }
+
+    pub fn delete<Args>(self, pattern: &str, handler: impl IntoHandler<Args>) -> Self {
+        self.on(HttpMethod::Delete, pattern, handler)
+    }
+
+    /// Handles requests for paths no route matches, such as a single-page app's entry point
+    /// or a custom 404 page.
+    #[allow(unused)]
+    pub fn fallback<Args>(mut self, handler: impl IntoHandler<Args>) -> Self {
+        self.fallback = Some(handler.into_handler());
+        self
+    }
+
+    pub fn fallback_handler(&self) -> Option<&dyn Handler> {
+        self.fallback.as_deref()
+    }
+
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use super::*;
+
+    fn params(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn literal_pattern_matches_only_itself() {
+        let pattern = Pattern::parse("/user-agent").unwrap();
+        assert_eq!(pattern.matches("/user-agent"), Some(Vec::new()));
+        assert_eq!(pattern.matches("/user-agent/"), None);
+        assert_eq!(pattern.matches("/user"), None);
+    }
+
+    #[test]
+    fn param_matches_one_segment() {
+        let pattern = Pattern::parse("/users/{id}/posts/{post}").unwrap();
+        assert_eq!(pattern.matches("/users/7/posts/42"), Some(params(&[("id", "7"), ("post", "42")])));
+        assert_eq!(pattern.matches("/users//posts/42"), None);
+        assert_eq!(pattern.matches("/users/7/8/posts/42"), None);
+    }
+
+    #[test]
+    fn catch_all_matches_the_rest() {
+        let pattern = Pattern::parse("/files/{*path}").unwrap();
+        assert_eq!(pattern.matches("/files/a/b.txt"), Some(params(&[("path", "a/b.txt")])));
+        assert_eq!(pattern.matches("/files/"), Some(params(&[("path", "")])));
+        assert_eq!(pattern.matches("/files"), None);
+        assert_eq!(pattern.prefix(), "/files/");
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        for source in ["files", "/files/{path", "/files/{}", "/files/{*}", "/{a}{b}", "/{*rest}/more"] {
+            assert!(Pattern::parse(source).is_err(), "{} parsed", source);
+        }
+    }
+
+    async fn ok() -> HttpResult<HttpResponse> {
+        Ok(HttpResponse::new(crate::http::HttpStatus::Ok))
+    }
+
+    #[test]
+    fn allow_lists_the_route_methods() {
+        let router = Router::new()
+            .get("/echo/{*message}", ok)
+            .get("/files/{*path}", ok)
+            .post("/files/{*path}", ok)
+            .delete("/files/{*path}", ok);
+        let [echo, files] = router.routes() else {
+            panic!("Expected two routes");
+        };
+
+        assert_eq!(echo.allow(), "GET, HEAD, OPTIONS");
+        assert_eq!(files.allow(), "GET, HEAD, POST, DELETE, OPTIONS");
+        assert!(files.allows(&HttpMethod::Head));
+        assert!(!echo.allows(&HttpMethod::Post));
+        assert!(echo.handler(&HttpMethod::Head).is_some());
+    }
+
+    #[test]
+    #[should_panic(expected = "registered twice")]
+    fn registering_a_method_twice_panics() {
+        let _ = Router::new().get("/", ok).get("/", ok);
+    }
+}
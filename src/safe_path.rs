@@ -0,0 +1,121 @@
+use std::path::{Component, Path, PathBuf};
+use clap::ValueEnum;
+use crate::error::{HttpError, HttpResult};
+use crate::CONFIG;
+
+/// Which symlinks under a files directory requests may go through.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SymlinkPolicy {
+    /// None at all
+    Deny,
+    /// Only those leading somewhere inside the directory
+    SameRootOnly,
+    /// Any, wherever they lead
+    Allow,
+}
+
+/// Joins the decoded rest of a request path onto the directory `root`, refusing with a 403
+/// anything that would end up outside it. That covers `..` segments and absolute paths,
+/// however they were encoded, and symlinks as `--symlinks` says. The path doesn't have to
+/// exist yet, so files can be created through it.
+pub async fn resolve(root: &Path, path: &str) -> HttpResult<PathBuf> {
+    if path.contains('\0') {
+        return Err(HttpError::BadRequest("NUL in path".to_string()));
+    }
+
+    let mut resolved = root.to_path_buf();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(name) => resolved.push(name),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return Err(HttpError::Forbidden),
+        }
+    }
+
+    let policy = CONFIG.read().await.symlinks;
+    match policy {
+        SymlinkPolicy::Deny => check_no_symlinks(root, &resolved).await?,
+        SymlinkPolicy::SameRootOnly => check_inside(root, &resolved).await?,
+        SymlinkPolicy::Allow => {}
+    }
+
+    Ok(resolved)
+}
+
+/// Fails if anything between `root` and `path`, or `path` itself, is a symlink. The root may be
+/// one, since it's configured rather than asked for.
+async fn check_no_symlinks(root: &Path, path: &Path) -> HttpResult<()> {
+    let mut current = root.to_path_buf();
+    for component in path.strip_prefix(root).unwrap_or(path).components() {
+        current.push(component);
+        match tokio::fs::symlink_metadata(&current).await {
+            Ok(metadata) if metadata.is_symlink() => return Err(HttpError::Forbidden),
+            Ok(_) => {}
+            // Nothing further down exists yet
+            Err(_) => break,
+        }
+    }
+
+    Ok(())
+}
+
+/// Fails unless `path`, as far as it exists on disk, leads somewhere inside `root`.
+async fn check_inside(root: &Path, path: &Path) -> HttpResult<()> {
+    let root = tokio::fs::canonicalize(root).await?;
+    for existing in path.ancestors() {
+        if tokio::fs::symlink_metadata(existing).await.is_err() {
+            continue;
+        }
+
+        // A dangling symlink can't be resolved, and writing through it could create a file anywhere
+        let canonical = tokio::fs::canonicalize(existing).await.map_err(|_| HttpError::Forbidden)?;
+        if !canonical.starts_with(&root) {
+            return Err(HttpError::Forbidden);
+        }
+        break;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+    use crate::error::HttpError;
+    use crate::testing::{configure, temp_dir};
+    use super::resolve;
+
+    #[tokio::test]
+    async fn paths_stay_under_the_root() {
+        let root = temp_dir("resolve");
+        let _config = configure(&[]).await;
+
+        assert_eq!(resolve(&root, "a/./b.txt").await.unwrap(), root.join("a/b.txt"));
+        for path in ["../secret", "a/../../secret", "/etc/passwd", "a/.."] {
+            assert!(matches!(resolve(&root, path).await, Err(HttpError::Forbidden)), "{}", path);
+        }
+        assert!(matches!(resolve(&root, "a\0b").await, Err(HttpError::BadRequest(_))));
+    }
+
+    #[tokio::test]
+    async fn symlinks_follow_the_policy() {
+        let dir = temp_dir("resolve-symlinks");
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(dir.join("secret.txt"), "secret").unwrap();
+        symlink(root.join("docs"), root.join("inside")).unwrap();
+        symlink(dir.join("secret.txt"), root.join("outside")).unwrap();
+
+        let _config = configure(&["--symlinks", "deny"]).await;
+        assert!(matches!(resolve(&root, "inside/a.txt").await, Err(HttpError::Forbidden)));
+        drop(_config);
+
+        let _config = configure(&["--symlinks", "same-root-only"]).await;
+        assert!(resolve(&root, "inside/a.txt").await.is_ok());
+        assert!(matches!(resolve(&root, "outside").await, Err(HttpError::Forbidden)));
+        drop(_config);
+
+        let _config = configure(&["--symlinks", "allow"]).await;
+        assert!(resolve(&root, "outside").await.is_ok());
+    }
+}
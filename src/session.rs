@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use once_cell::sync::Lazy;
+use crate::cookie::{Cookie, SameSite};
+use crate::http::HttpResponse;
+
+pub const SESSION_COOKIE: &str = "session_id";
+const SESSION_ID_BYTES: usize = 16;
+
+pub type SessionData = HashMap<String, String>;
+
+/// Where session data lives between requests. Entries should be forgotten once `ttl` has
+/// passed without them being saved again.
+pub trait SessionStore: Send + Sync {
+    fn load(&self, id: &str) -> Option<SessionData>;
+    fn save(&self, id: &str, data: SessionData, ttl: Duration);
+    fn remove(&self, id: &str);
+}
+
+/// Keeps sessions in process memory, so they're lost on restart.
+#[derive(Default)]
+pub struct MemorySessionStore {
+    sessions: Mutex<HashMap<String, (SessionData, Instant)>>,
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load(&self, id: &str) -> Option<SessionData> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.get(id)
+            .filter(|(_, expires)| *expires > Instant::now())
+            .map(|(data, _)| data.clone())
+    }
+
+    fn save(&self, id: &str, data: SessionData, ttl: Duration) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if !sessions.contains_key(id) {
+            let now = Instant::now();
+            sessions.retain(|_, (_, expires)| *expires > now);
+        }
+        sessions.insert(id.to_string(), (data, Instant::now() + ttl));
+    }
+
+    fn remove(&self, id: &str) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+}
+
+static STORE: Lazy<RwLock<Arc<dyn SessionStore>>> = Lazy::new(|| RwLock::new(Arc::new(MemorySessionStore::default())));
+
+/// Replaces the in-memory store, for deployments that keep sessions elsewhere.
+#[allow(unused)]
+pub fn set_store(store: Arc<dyn SessionStore>) {
+    *STORE.write().unwrap() = store;
+}
+
+fn store() -> Arc<dyn SessionStore> {
+    STORE.read().unwrap().clone()
+}
+
+/// The data kept for one client across requests, identified by its session cookie.
+#[derive(Default)]
+pub struct Session {
+    id: Option<String>,
+    data: SessionData,
+    changed: bool,
+    destroyed: bool,
+}
+
+#[allow(unused)]
+impl Session {
+    /// Restores the session the cookie refers to, or starts an empty one if it's unknown or
+    /// has expired.
+    pub fn load(cookie: Option<&String>) -> Self {
+        let loaded = cookie
+            .filter(|id| is_session_id(id))
+            .and_then(|id| store().load(id).map(|data| (id.clone(), data)));
+        match loaded {
+            Some((id, data)) => Self { id: Some(id), data, ..Default::default() },
+            None => Self::default(),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.data.get(key).map(String::as_str)
+    }
+
+    pub fn insert(&mut self, key: &str, value: String) {
+        self.data.insert(key.to_string(), value);
+        self.changed = true;
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.changed = true;
+        self.data.remove(key)
+    }
+
+    /// Forgets the session and tells the client to drop its cookie.
+    pub fn destroy(&mut self) {
+        self.data.clear();
+        self.destroyed = true;
+    }
+
+    /// Saves the session for another `ttl` and sets its cookie on `response` when it was written
+    /// to. Sessions that were only read keep the cookie and expiry they already have.
+    pub fn commit(self, response: HttpResponse, ttl: Duration, secure: bool) -> HttpResponse {
+        if self.destroyed {
+            if let Some(id) = &self.id {
+                store().remove(id);
+            }
+            return response.with_cookie(Cookie::removal(SESSION_COOKIE).with_path("/"));
+        }
+        if !self.changed {
+            return response;
+        }
+
+        let id = self.id.unwrap_or_else(new_session_id);
+        store().save(&id, self.data, ttl);
+        let cookie = Cookie::new(SESSION_COOKIE, &id)
+            .with_path("/")
+            .with_max_age(ttl)
+            .with_http_only()
+            .with_same_site(SameSite::Lax);
+        response.with_cookie(if secure { cookie.with_secure() } else { cookie })
+    }
+}
+
+fn new_session_id() -> String {
+    let mut bytes = [0; SESSION_ID_BYTES];
+    getrandom::getrandom(&mut bytes).expect("No system randomness available");
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn is_session_id(id: &str) -> bool {
+    id.len() == SESSION_ID_BYTES * 2 && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use crate::http::HttpStatus;
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    /// Commits `session` and returns the `Set-Cookie` values the response gets.
+    fn commit(session: Session, ttl: Duration) -> Vec<String> {
+        let response = session.commit(HttpResponse::new(HttpStatus::Ok), ttl, false);
+        response.headers().get_all("Set-Cookie").cloned().collect()
+    }
+
+    fn session_id(set_cookie: &str) -> String {
+        let id = set_cookie.split(';').next().unwrap().strip_prefix("session_id=").unwrap();
+        assert!(is_session_id(id), "{}", set_cookie);
+        id.to_string()
+    }
+
+    #[test]
+    fn first_write_issues_an_id_that_brings_the_data_back() {
+        let mut session = Session::load(None);
+        assert_eq!(session.get("user"), None);
+        session.insert("user", "alice".to_string());
+        let cookies = commit(session, TTL);
+        assert_eq!(cookies.len(), 1);
+        let id = session_id(&cookies[0]);
+        assert_eq!(cookies[0], format!("session_id={}; Path=/; Max-Age=60; HttpOnly; SameSite=Lax", id));
+
+        let mut session = Session::load(Some(&id));
+        assert_eq!(session.get("user"), Some("alice"));
+        session.insert("theme", "dark".to_string());
+        assert_eq!(session_id(&commit(session, TTL)[0]), id);
+
+        let session = Session::load(Some(&id));
+        assert_eq!((session.get("user"), session.get("theme")), (Some("alice"), Some("dark")));
+    }
+
+    #[test]
+    fn unknown_or_expired_ids_start_over() {
+        for id in ["0123456789abcdef0123456789abcdef", "not-a-session-id", ""] {
+            let mut session = Session::load(Some(&id.to_string()));
+            assert_eq!(session.get("user"), None);
+            session.insert("user", "bob".to_string());
+            assert_ne!(session_id(&commit(session, TTL)[0]), id);
+        }
+
+        let mut session = Session::load(None);
+        session.insert("user", "carol".to_string());
+        let id = session_id(&commit(session, Duration::ZERO)[0]);
+        assert_eq!(Session::load(Some(&id)).get("user"), None);
+    }
+
+    #[test]
+    fn destroying_removes_the_data_and_the_cookie() {
+        let mut session = Session::load(None);
+        session.insert("user", "dave".to_string());
+        let id = session_id(&commit(session, TTL)[0]);
+
+        let mut session = Session::load(Some(&id));
+        session.destroy();
+        assert_eq!(commit(session, TTL), ["session_id=; Path=/; Max-Age=0"]);
+        assert_eq!(Session::load(Some(&id)).get("user"), None);
+    }
+}
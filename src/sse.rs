@@ -0,0 +1,181 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use anyhow::anyhow;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::mpsc::Receiver;
+use tokio::time::{interval, Interval, MissedTickBehavior};
+use crate::http::HttpContent;
+
+pub const EVENT_STREAM_TYPE: &str = "text/event-stream";
+
+/// Idle streams get a comment this often so proxies and browsers don't give up on them.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// One Server-Sent Event.
+#[allow(unused)]
+#[derive(Clone, Debug, Default)]
+pub struct Event {
+    event: Option<String>,
+    id: Option<String>,
+    /// How long the browser should wait before reconnecting after the stream drops
+    retry: Option<Duration>,
+    data: String,
+}
+
+#[allow(unused)]
+impl Event {
+    pub fn new(data: &str) -> Self {
+        Self { data: data.to_string(), ..Default::default() }
+    }
+
+    pub fn with_event(mut self, event: &str) -> Self {
+        self.event = Some(event.to_string());
+        self
+    }
+
+    pub fn with_id(mut self, id: &str) -> Self {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    pub fn with_retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Frames the event for the wire, with every line of the data in a `data:` field of its own.
+    fn encode(&self) -> Vec<u8> {
+        // Line breaks would end a field early and let values forge fields of their own
+        let single_line = |value: &str| value.replace(['\r', '\n'], " ");
+
+        let mut encoded = String::new();
+        if let Some(event) = &self.event {
+            encoded.push_str(&format!("event: {}\n", single_line(event)));
+        }
+        if let Some(id) = &self.id {
+            encoded.push_str(&format!("id: {}\n", single_line(id)));
+        }
+        if let Some(retry) = self.retry {
+            encoded.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        for line in self.data.split('\n') {
+            encoded.push_str(&format!("data: {}\n", line.strip_suffix('\r').unwrap_or(line)));
+        }
+        encoded.push('\n');
+        encoded.into_bytes()
+    }
+}
+
+/// A `text/event-stream` body that sends the events arriving on a channel as they come,
+/// ending once every sender has been dropped.
+#[allow(unused)]
+pub struct SseContent {
+    events: Mutex<Option<Receiver<Event>>>,
+}
+
+#[allow(unused)]
+impl SseContent {
+    pub fn new(events: Receiver<Event>) -> Box<Self> {
+        Box::new(Self { events: Mutex::new(Some(events)) })
+    }
+}
+
+impl HttpContent for SseContent {
+    fn content_type(&self) -> &str {
+        EVENT_STREAM_TYPE
+    }
+
+    fn content_length(&self) -> Option<usize> {
+        None
+    }
+
+    fn content(&self) -> Result<Box<dyn AsyncRead + Send + Sync + Unpin + '_>, anyhow::Error> {
+        let events = self.events.lock().unwrap().take()
+            .ok_or_else(|| anyhow!("Event stream can only be sent once"))?;
+
+        let mut keep_alive = interval(KEEP_ALIVE_INTERVAL);
+        keep_alive.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Ok(Box::new(EventStreamReader { events, keep_alive, pending: Vec::new(), first_tick: true }))
+    }
+}
+
+struct EventStreamReader {
+    events: Receiver<Event>,
+    keep_alive: Interval,
+    /// Encoded bytes that didn't fit into the last read
+    pending: Vec<u8>,
+    /// The interval ticks right away, which isn't worth a keep-alive
+    first_tick: bool,
+}
+
+impl AsyncRead for EventStreamReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        while self.pending.is_empty() {
+            if let Poll::Ready(event) = self.events.poll_recv(cx) {
+                match event {
+                    Some(event) => self.pending = event.encode(),
+                    None => return Poll::Ready(Ok(())),
+                }
+                self.keep_alive.reset();
+                continue;
+            }
+
+            ready!(self.keep_alive.poll_tick(cx));
+            if !std::mem::take(&mut self.first_tick) {
+                self.pending = b": keep-alive\n\n".to_vec();
+            }
+        }
+
+        let len = self.pending.len().min(buf.remaining());
+        buf.put_slice(&self.pending[..len]);
+        self.pending.drain(..len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use tokio::io::AsyncReadExt;
+    use super::*;
+
+    fn encoded(event: Event) -> String {
+        String::from_utf8(event.encode()).unwrap()
+    }
+
+    #[test]
+    fn fields_come_before_the_data_lines() {
+        assert_eq!(encoded(Event::new("hello")), "data: hello\n\n");
+        let event = Event::new("42").with_event("update").with_id("7").with_retry(Duration::from_secs(3));
+        assert_eq!(encoded(event), "event: update\nid: 7\nretry: 3000\ndata: 42\n\n");
+    }
+
+    #[test]
+    fn multi_line_data_gets_a_field_per_line() {
+        assert_eq!(encoded(Event::new("one\ntwo\r\nthree")), "data: one\ndata: two\ndata: three\n\n");
+        assert_eq!(encoded(Event::new("")), "data: \n\n");
+    }
+
+    #[test]
+    fn line_breaks_cant_forge_fields() {
+        let event = Event::new("x").with_event("a\ndata: forged").with_id("1\r\nretry: 0");
+        assert_eq!(encoded(event), "event: a data: forged\nid: 1  retry: 0\ndata: x\n\n");
+    }
+
+    #[tokio::test]
+    async fn stream_sends_events_until_the_senders_are_gone() {
+        let (sender, receiver) = tokio::sync::mpsc::channel(4);
+        let content = SseContent::new(receiver);
+        sender.send(Event::new("first").with_id("1")).await.unwrap();
+        sender.send(Event::new("second\nline").with_id("2")).await.unwrap();
+        drop(sender);
+
+        let mut stream = String::new();
+        content.content().unwrap().read_to_string(&mut stream).await.unwrap();
+        assert_eq!(stream, "id: 1\ndata: first\n\nid: 2\ndata: second\ndata: line\n\n");
+        assert!(content.content().is_err());
+    }
+}
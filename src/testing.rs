@@ -0,0 +1,193 @@
+//! Helpers for tests that run requests through the whole server.
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use clap::Parser;
+use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, DnType, IsCa, KeyPair};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Mutex, MutexGuard};
+use tokio_rustls::client::TlsStream as ClientTlsStream;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::server::TlsStream as ServerTlsStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use crate::counting::CountingReader;
+use crate::{handle_connection, read_request_head, Args, ConnectionReader, ConnectionWriter, RequestContext, CONFIG};
+
+/// The configuration is global, so tests depending on it take turns.
+static CONFIG_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// Configures the server as if started with `args`, until the guard is dropped.
+pub async fn configure(args: &[&str]) -> MutexGuard<'static, ()> {
+    let guard = CONFIG_LOCK.lock().await;
+    *CONFIG.write().await = Args::parse_from(std::iter::once(env!("CARGO_PKG_NAME")).chain(args.iter().copied()));
+    guard
+}
+
+pub fn remote_addr() -> SocketAddr {
+    "127.0.0.1:54321".parse().unwrap()
+}
+
+/// The context the server would build for `request`, with whatever follows its head as the
+/// body and nowhere for a response to go.
+pub async fn context(request: &[u8]) -> RequestContext {
+    let mut reader: ConnectionReader = CountingReader::new(BufReader::new(Box::new(io::Cursor::new(request.to_vec()))));
+    let head = read_request_head(&mut reader).await.unwrap().unwrap();
+    let writer: ConnectionWriter = BufWriter::new(Box::new(tokio::io::sink()));
+    RequestContext::new(reader, writer, head, remote_addr(), None)
+}
+
+/// Sends `request` over a fresh connection, closes the sending side and returns everything
+/// the server wrote back before closing its own.
+pub async fn exchange(request: &[u8]) -> String {
+    String::from_utf8_lossy(&exchange_bytes(request).await).into_owned()
+}
+
+/// `exchange` for responses that aren't all text.
+pub async fn exchange_bytes(request: &[u8]) -> Vec<u8> {
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let server = tokio::spawn(handle_connection(remote_addr(), server, None));
+
+    let (mut reader, mut writer) = tokio::io::split(client);
+    let request = request.to_vec();
+    // Written alongside reading, so a long response can't stall a long request
+    let sender = tokio::spawn(async move {
+        writer.write_all(&request).await.unwrap();
+        writer.shutdown().await.unwrap();
+    });
+    let mut response = Vec::new();
+    reader.read_to_end(&mut response).await.unwrap();
+    sender.await.unwrap();
+    server.await.unwrap();
+    response
+}
+
+/// An empty directory of the calling test's own.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("{}-{}-{}", env!("CARGO_PKG_NAME"), std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// The value of the first `name` header in a response, however it's capitalized.
+pub fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    let head = response.split("\r\n\r\n").next()?;
+    head.lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(field, _)| field.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+/// The body following the head of a response.
+pub fn body(response: &str) -> &str {
+    response.split_once("\r\n\r\n").map_or("", |(_, body)| body)
+}
+
+/// A CA with a certificate for `localhost` and one for a client, written to `dir` as PEM files
+/// for the `--tls-*` options.
+pub struct TestPki {
+    pub dir: PathBuf,
+    issuer: CertifiedIssuer<'static, KeyPair>,
+    ca: CertificateDer<'static>,
+    client_cert: CertificateDer<'static>,
+    client_key: PrivatePkcs8KeyDer<'static>,
+}
+
+impl TestPki {
+    pub fn new(name: &str) -> Self {
+        let dir = temp_dir(name);
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params.distinguished_name.push(DnType::CommonName, "Test CA");
+        let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+
+        let server_key = KeyPair::generate().unwrap();
+        let server_cert = CertificateParams::new(vec!["localhost".to_string()]).unwrap().signed_by(&server_key, &ca).unwrap();
+
+        let client_key = KeyPair::generate().unwrap();
+        let mut client_params = CertificateParams::new(vec!["client.example".to_string()]).unwrap();
+        client_params.distinguished_name.push(DnType::CommonName, "alice");
+        let client_cert = client_params.signed_by(&client_key, &ca).unwrap();
+
+        std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+        std::fs::write(dir.join("cert.pem"), server_cert.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), server_key.serialize_pem()).unwrap();
+        Self {
+            dir,
+            ca: ca.der().clone(),
+            issuer: ca,
+            client_cert: client_cert.der().clone(),
+            client_key: PrivatePkcs8KeyDer::from(client_key.serialize_der()),
+        }
+    }
+
+    /// The `--tls-*` options serving the `localhost` certificate, also trusting the CA for
+    /// client certificates if `client_ca` is set.
+    pub fn args(&self, client_ca: bool) -> Vec<String> {
+        let path = |file: &str| self.dir.join(file).to_string_lossy().into_owned();
+        let mut args = vec!["--tls-cert".to_string(), path("cert.pem"), "--tls-key".to_string(), path("key.pem")];
+        if client_ca {
+            args.extend(["--tls-client-ca".to_string(), path("ca.pem")]);
+        }
+        args
+    }
+
+    /// Writes a certificate for `name` and its key as `NAME.pem` and `NAME-key.pem`, returning
+    /// their paths.
+    pub fn host_cert(&self, name: &str) -> (String, String) {
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec![name.to_string()]).unwrap().signed_by(&key, &self.issuer).unwrap();
+        let (cert_path, key_path) = (self.dir.join(format!("{}.pem", name)), self.dir.join(format!("{}-key.pem", name)));
+        std::fs::write(&cert_path, cert.pem()).unwrap();
+        std::fs::write(&key_path, key.serialize_pem()).unwrap();
+        (cert_path.to_string_lossy().into_owned(), key_path.to_string_lossy().into_owned())
+    }
+
+    /// A connector trusting the CA, presenting the client certificate if `client_cert` is set.
+    pub fn connector(&self, client_cert: bool) -> TlsConnector {
+        TlsConnector::from(Arc::new(self.client_config(client_cert)))
+    }
+
+    /// A connector trusting the CA that offers `protocols` through ALPN.
+    pub fn alpn_connector(&self, protocols: &[&[u8]]) -> TlsConnector {
+        let mut config = self.client_config(false);
+        config.alpn_protocols = protocols.iter().map(|protocol| protocol.to_vec()).collect();
+        TlsConnector::from(Arc::new(config))
+    }
+
+    fn client_config(&self, client_cert: bool) -> ClientConfig {
+        let mut roots = RootCertStore::empty();
+        roots.add(self.ca.clone()).unwrap();
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+        match client_cert {
+            true => builder.with_client_auth_cert(vec![self.client_cert.clone()], self.client_key.clone_key().into()).unwrap(),
+            false => builder.with_no_client_auth(),
+        }
+    }
+}
+
+/// Connects to a fresh local listener through `connector` and hands back both ends of the
+/// handshake, the server's as `acceptor` completed it.
+pub async fn tls_handshake(acceptor: TlsAcceptor, connector: TlsConnector) -> (io::Result<ServerTlsStream<TcpStream>>, io::Result<ClientTlsStream<TcpStream>>) {
+    tls_handshake_with(acceptor, connector, "localhost").await
+}
+
+/// `tls_handshake` asking for `server_name` through SNI.
+pub async fn tls_handshake_with(acceptor: TlsAcceptor, connector: TlsConnector, server_name: &str) -> (io::Result<ServerTlsStream<TcpStream>>, io::Result<ClientTlsStream<TcpStream>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = async {
+        let (stream, _) = listener.accept().await.unwrap();
+        acceptor.accept(stream).await
+    };
+    let client = async {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        connector.connect(ServerName::try_from(server_name.to_string()).unwrap(), stream).await
+    };
+    tokio::join!(server, client)
+}
@@ -0,0 +1,76 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::AsyncWrite;
+use tokio::time::Sleep;
+
+/// Fails a write, flush or shutdown with `TimedOut` when the peer doesn't accept any bytes
+/// for `timeout`. The deadline restarts on every call that makes progress, so large bodies
+/// streamed in many chunks aren't bounded as a whole.
+pub struct TimeoutWriter<W> {
+    inner: W,
+    timeout: Duration,
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl<W> TimeoutWriter<W> {
+    pub fn new(inner: W, timeout: Duration) -> Self {
+        Self { inner, timeout, deadline: None }
+    }
+
+    fn poll_deadline<T>(&mut self, cx: &mut Context<'_>, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        if poll.is_ready() {
+            self.deadline = None;
+            return poll;
+        }
+
+        let timeout = self.timeout;
+        let deadline = self.deadline.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        if deadline.as_mut().poll(cx).is_ready() {
+            self.deadline = None;
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "write timed out")));
+        }
+
+        Poll::Pending
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for TimeoutWriter<W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.poll_deadline(cx, poll)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_flush(cx);
+        self.poll_deadline(cx, poll)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_shutdown(cx);
+        self.poll_deadline(cx, poll)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use tokio::io::AsyncWriteExt;
+    use super::*;
+
+    #[tokio::test]
+    async fn stalled_reader_times_out_each_write() {
+        let (_client, server) = tokio::io::duplex(16);
+        let mut writer = TimeoutWriter::new(server, Duration::from_millis(100));
+
+        // Progress restarts the deadline, however long the whole body takes
+        writer.write_all(&[0; 16]).await.unwrap();
+
+        let started = Instant::now();
+        let error = writer.write_all(&[0; 16]).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(1), "{:?}", started.elapsed());
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::IpAddr;
+use std::sync::Arc;
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::crypto::CryptoProvider;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use x509_parser::extensions::GeneralName;
+use crate::{acme, CONFIG};
+
+const ALPN_H2: &[u8] = b"h2";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ClientAuth {
+    Optional,
+    Required,
+}
+
+#[allow(unused)]
+#[derive(Clone, Debug)]
+pub struct CertificateSubject {
+    pub common_name: Option<String>,
+    pub subject_alt_names: Vec<String>,
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to read certificates from {}", path))?;
+    if certs.is_empty() {
+        bail!("No certificates found in {}", path);
+    }
+
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to read private key from {}", path))?
+        .with_context(|| format!("No private key found in {}", path))
+}
+
+/// Builds the TLS acceptor from the `--tls-*` options, or `None` when TLS isn't configured.
+pub async fn acceptor() -> Result<Option<TlsAcceptor>> {
+    Ok(server_config().await?.map(|mut server_config| {
+        server_config.alpn_protocols = vec![ALPN_H2.to_vec(), b"http/1.1".to_vec()];
+        TlsAcceptor::from(Arc::new(server_config))
+    }))
+}
+
+/// Whether the client picked HTTP/2 during the handshake.
+pub fn negotiated_h2(stream: &TlsStream<TcpStream>) -> bool {
+    let (_, connection) = stream.get_ref();
+    connection.alpn_protocol() == Some(ALPN_H2)
+}
+
+/// Builds the rustls configuration from the `--tls-*` options, or `None` when TLS isn't configured.
+pub async fn server_config() -> Result<Option<ServerConfig>> {
+    let config = CONFIG.read().await;
+    let default_paths = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert_path), Some(key_path)) => Some((cert_path, key_path)),
+        // The certificate is provided by the ACME subsystem instead
+        (None, None) if !config.acme_domains.is_empty() => None,
+        (None, None) if config.tls_client_ca.is_some() => bail!("--tls-client-ca requires --tls-cert and --tls-key"),
+        (None, None) if config.vhosts.iter().any(|vhost| vhost.cert.is_some()) => {
+            bail!("--vhost certificates require --tls-cert and --tls-key for clients without SNI")
+        }
+        (None, None) => return Ok(None),
+        _ => bail!("--tls-cert and --tls-key must be given together"),
+    };
+
+    let builder = ServerConfig::builder();
+    let provider = builder.crypto_provider().clone();
+    let builder = match &config.tls_client_ca {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_path)? {
+                roots.add(cert)?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+            let verifier = match config.tls_client_auth {
+                ClientAuth::Optional => verifier.allow_unauthenticated(),
+                ClientAuth::Required => verifier,
+            };
+            builder.with_client_cert_verifier(verifier.build()?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let default = match default_paths {
+        Some((cert_path, key_path)) => Some(Arc::new(load_certified_key(cert_path, key_path, &provider)?)),
+        None => None,
+    };
+    let mut resolver = SniCertResolver {
+        default,
+        hosts: HashMap::new(),
+    };
+    for vhost in &config.vhosts {
+        if let (Some(cert_path), Some(key_path)) = (&vhost.cert, &vhost.key) {
+            let key = load_certified_key(cert_path, key_path, &provider)?;
+            resolver.hosts.insert(vhost.name.clone(), Arc::new(key));
+        }
+    }
+
+    Ok(Some(builder.with_cert_resolver(Arc::new(resolver))))
+}
+
+fn load_certified_key(cert_path: &str, key_path: &str, provider: &CryptoProvider) -> Result<CertifiedKey> {
+    CertifiedKey::from_der(load_certs(cert_path)?, load_key(key_path)?, provider)
+        .with_context(|| format!("{} doesn't match the certificate in {}", key_path, cert_path))
+}
+
+/// Picks the certificate of the `--vhost` named by the client's SNI, or else the `--tls-cert`
+/// one, or the current ACME certificate when there's no `--tls-cert`.
+#[derive(Debug)]
+struct SniCertResolver {
+    default: Option<Arc<CertifiedKey>>,
+    hosts: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for SniCertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        client_hello.server_name()
+            .and_then(|name| self.hosts.get(&name.to_ascii_lowercase()))
+            .or(self.default.as_ref())
+            .cloned()
+            .or_else(acme::certificate)
+    }
+}
+
+pub fn client_cert_subject(stream: &TlsStream<TcpStream>) -> Option<CertificateSubject> {
+    let (_, connection) = stream.get_ref();
+    certificate_subject(connection.peer_certificates()?)
+}
+
+/// Extracts the subject of the first (leaf) certificate in a peer's chain.
+pub fn certificate_subject(certs: &[CertificateDer]) -> Option<CertificateSubject> {
+    let (_, cert) = x509_parser::parse_x509_certificate(certs.first()?).ok()?;
+
+    let common_name = cert.subject()
+        .iter_common_name()
+        .next()
+        .and_then(|name| name.as_str().ok())
+        .map(str::to_string);
+
+    let subject_alt_names = match cert.subject_alternative_name() {
+        Ok(Some(names)) => names.value.general_names.iter().filter_map(general_name_to_string).collect(),
+        _ => Vec::new(),
+    };
+
+    Some(CertificateSubject { common_name, subject_alt_names })
+}
+
+fn general_name_to_string(name: &GeneralName) -> Option<String> {
+    match name {
+        GeneralName::DNSName(name) | GeneralName::RFC822Name(name) | GeneralName::URI(name) => Some(name.to_string()),
+        GeneralName::IPAddress(bytes) => {
+            let ip = match bytes.len() {
+                4 => IpAddr::from(<[u8; 4]>::try_from(*bytes).ok()?),
+                16 => IpAddr::from(<[u8; 16]>::try_from(*bytes).ok()?),
+                _ => return None,
+            };
+            Some(ip.to_string())
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use crate::testing::{configure, tls_handshake, tls_handshake_with, TestPki};
+    use super::*;
+
+    #[tokio::test]
+    async fn client_certificate_subject_is_read_from_the_handshake() {
+        let pki = TestPki::new("tls-client-subject");
+        let args = pki.args(true);
+        let _config = configure(&args.iter().map(String::as_str).collect::<Vec<_>>()).await;
+        let acceptor = acceptor().await.unwrap().unwrap();
+
+        let (server, client) = tls_handshake(acceptor, pki.connector(true)).await;
+        client.unwrap();
+        let subject = client_cert_subject(&server.unwrap()).unwrap();
+        assert_eq!(subject.common_name.as_deref(), Some("alice"));
+        assert_eq!(subject.subject_alt_names, vec!["client.example".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn required_client_certificate_is_enforced() {
+        let pki = TestPki::new("tls-client-required");
+        let args = pki.args(true);
+        let _config = configure(&args.iter().map(String::as_str).collect::<Vec<_>>()).await;
+
+        let (server, _) = tls_handshake(acceptor().await.unwrap().unwrap(), pki.connector(false)).await;
+        assert!(server.is_err());
+
+        let (server, client) = tls_handshake(acceptor().await.unwrap().unwrap(), pki.connector(true)).await;
+        assert!(server.is_ok() && client.is_ok());
+    }
+
+    #[tokio::test]
+    async fn optional_client_certificate_may_be_left_out() {
+        let pki = TestPki::new("tls-client-optional");
+        let mut args = pki.args(true);
+        args.extend(["--tls-client-auth".to_string(), "optional".to_string()]);
+        let _config = configure(&args.iter().map(String::as_str).collect::<Vec<_>>()).await;
+
+        let (server, client) = tls_handshake(acceptor().await.unwrap().unwrap(), pki.connector(false)).await;
+        client.unwrap();
+        assert!(client_cert_subject(&server.unwrap()).is_none());
+    }
+
+    /// The names the certificate the client was sent is for.
+    fn served_names(client: &tokio_rustls::client::TlsStream<TcpStream>) -> Vec<String> {
+        let (_, connection) = client.get_ref();
+        certificate_subject(connection.peer_certificates().unwrap()).unwrap().subject_alt_names
+    }
+
+    #[tokio::test]
+    async fn sni_picks_the_vhost_certificate_or_the_default() {
+        let pki = TestPki::new("tls-sni");
+        let (cert, key) = pki.host_cert("shop.example");
+        let vhost = format!("shop.example={},{},{}", pki.dir.display(), cert, key);
+        let mut args = pki.args(false);
+        args.extend(["--vhost".to_string(), vhost]);
+        let _config = configure(&args.iter().map(String::as_str).collect::<Vec<_>>()).await;
+
+        let (_, client) = tls_handshake_with(acceptor().await.unwrap().unwrap(), pki.connector(false), "shop.example").await;
+        assert_eq!(served_names(&client.unwrap()), ["shop.example"]);
+        let (_, client) = tls_handshake_with(acceptor().await.unwrap().unwrap(), pki.connector(false), "SHOP.example").await;
+        assert_eq!(served_names(&client.unwrap()), ["shop.example"]);
+
+        // Names without a certificate of their own get the default one
+        let (_, client) = tls_handshake(acceptor().await.unwrap().unwrap(), pki.connector(false)).await;
+        assert_eq!(served_names(&client.unwrap()), ["localhost"]);
+        let (_, client) = tls_handshake_with(acceptor().await.unwrap().unwrap(), pki.connector(false), "blog.example").await;
+        assert!(client.is_err());
+    }
+
+    #[tokio::test]
+    async fn alpn_picks_h2_when_the_client_offers_it() {
+        let pki = TestPki::new("tls-alpn");
+        let args = pki.args(false);
+        let _config = configure(&args.iter().map(String::as_str).collect::<Vec<_>>()).await;
+
+        for (offered, h2) in [(&[&b"h2"[..], b"http/1.1"][..], true), (&[&b"http/1.1"[..]][..], false), (&[][..], false)] {
+            let (server, client) = tls_handshake(acceptor().await.unwrap().unwrap(), pki.alpn_connector(offered)).await;
+            client.unwrap();
+            assert_eq!(negotiated_h2(&server.unwrap()), h2, "{:?}", offered);
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use anyhow::{bail, Result};
+use crate::error::{HttpError, HttpResult};
+use crate::headers::HeaderMap;
+
+/// A `--vhost NAME=DIR[,CERT,KEY]` entry. Requests whose Host is `name` are served from
+/// `directory`, and TLS clients asking for `name` through SNI are sent `cert` instead of
+/// the default certificate.
+#[derive(Clone, Debug)]
+pub struct VirtualHost {
+    pub name: String,
+    pub directory: String,
+    pub cert: Option<String>,
+    pub key: Option<String>,
+}
+
+impl VirtualHost {
+    pub fn parse(value: &str) -> Result<Self> {
+        let Some((name, rest)) = value.split_once('=') else {
+            bail!("Expected NAME=DIR[,CERT,KEY] but got {}", value);
+        };
+        if name.is_empty() {
+            bail!("Missing host name in {}", value);
+        }
+
+        let parts: Vec<_> = rest.split(',').collect();
+        let (directory, cert, key) = match parts.as_slice() {
+            [directory] => (directory, None, None),
+            [directory, cert, key] => (directory, Some(cert.to_string()), Some(key.to_string())),
+            _ => bail!("Expected NAME=DIR[,CERT,KEY] but got {}", value),
+        };
+
+        Ok(Self { name: name.to_ascii_lowercase(), directory: directory.to_string(), cert, key })
+    }
+}
+
+/// Strips the port from a Host header value, keeping IPv6 literals such as `[::1]` intact.
+pub fn host_name(host: &str) -> &str {
+    let host = host.trim();
+    if host.starts_with('[') {
+        return host.split_once(']').map_or(host, |(address, _)| &host[..address.len() + 1]);
+    }
+
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    }
+}
+
+/// A `--vhost-routes NAME=ROUTE[,ROUTE...]` entry limiting the host called `name` to the
+/// listed routes, named by their pattern such as `/files/{*path}` or the part ahead of its
+/// first parameter such as `/files/`.
+#[derive(Clone, Debug)]
+pub struct VhostRoutes {
+    pub name: String,
+    pub routes: Vec<String>,
+}
+
+impl VhostRoutes {
+    pub fn parse(value: &str) -> Result<Self> {
+        let Some((name, routes)) = value.split_once('=') else {
+            bail!("Expected NAME=ROUTE[,ROUTE...] but got {}", value);
+        };
+        if name.is_empty() {
+            bail!("Missing host name in {}", value);
+        }
+
+        let routes: Vec<_> = routes.split(',').filter(|route| !route.is_empty()).map(str::to_string).collect();
+        if let Some(route) = routes.iter().find(|route| !route.starts_with('/')) {
+            bail!("Route {} doesn't start with /", route);
+        }
+
+        Ok(Self { name: name.to_ascii_lowercase(), routes })
+    }
+}
+
+/// Checks the request's Host field: HTTP/1.1 requests need exactly one, and it mustn't hold
+/// anything that couldn't be a host and port.
+pub fn validate_host(headers: &HeaderMap, required: bool) -> HttpResult<()> {
+    let mut hosts = headers.get_all("Host");
+    match (hosts.next(), hosts.next()) {
+        (None, _) if required => Err(HttpError::BadRequest("Missing Host".to_string())),
+        (Some(_), Some(_)) => Err(HttpError::BadRequest("Multiple Host fields".to_string())),
+        (Some(host), None) if host.bytes().any(|b| b.is_ascii_whitespace() || b"/?#@\\".contains(&b)) => {
+            Err(HttpError::BadRequest(format!("Invalid Host {}", host)))
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,402 @@
+use std::future::Future;
+use std::pin::Pin;
+use anyhow::{anyhow, bail, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::debug;
+use crate::{ConnectionReader, ConnectionWriter, HttpMethod, HttpVersion, RequestContext};
+use crate::error::{HttpError, HttpResult};
+use crate::http::{HttpResponse, HttpStatus};
+
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_FRAME_PAYLOAD: u64 = 16 * 1024 * 1024;
+/// Control frames can't be fragmented and carry at most this much (RFC 6455 section 5.5)
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_INVALID_DATA: u16 = 1007;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+pub type WebSocketHandler = fn(WebSocket) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+/// Answers the upgrade request with a 101 and has the connection handed over to `handler`
+/// once it's sent, or with an error response if the request can't be upgraded. Being an
+/// ordinary route handler, it only runs once authentication and the other middleware let the
+/// request through.
+pub fn accept(ctx: &mut RequestContext, handler: WebSocketHandler) -> HttpResult<HttpResponse> {
+    let wants_upgrade = ctx.headers.get("Upgrade")
+        .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+    // Only HTTP/1.1 can switch protocols on a connection
+    if ctx.method != HttpMethod::Get || ctx.http_version != HttpVersion::Http11 || !wants_upgrade {
+        return Ok(HttpResponse::new(HttpStatus::UpgradeRequired).with_header("Upgrade", "websocket".to_string()));
+    }
+
+    let response = handshake_response(ctx)?;
+    if matches!(response.status(), HttpStatus::SwitchingProtocols) {
+        ctx.upgrade = Some(handler);
+    }
+    Ok(response)
+}
+
+fn handshake_response(ctx: &RequestContext) -> HttpResult<HttpResponse> {
+    let has_upgrade_token = ctx.headers.get("Connection")
+        .is_some_and(|connection| connection.split(',').any(|token| token.trim().eq_ignore_ascii_case("upgrade")));
+    if !has_upgrade_token {
+        return Err(HttpError::BadRequest("WebSocket upgrade without Connection: Upgrade".to_string()));
+    }
+
+    if ctx.headers.get("Sec-WebSocket-Version").map(|version| version.trim()) != Some("13") {
+        return Ok(HttpResponse::new(HttpStatus::UpgradeRequired)
+            .with_header("Sec-WebSocket-Version", "13".to_string()));
+    }
+
+    let key = ctx.headers.get("Sec-WebSocket-Key")
+        .map(|key| key.trim())
+        .filter(|key| BASE64.decode(key).is_ok_and(|decoded| decoded.len() == 16))
+        .ok_or_else(|| HttpError::BadRequest("Missing or invalid Sec-WebSocket-Key".to_string()))?;
+
+    Ok(HttpResponse::new(HttpStatus::SwitchingProtocols)
+        .with_header("Upgrade", "websocket".to_string())
+        .with_header("Connection", "Upgrade".to_string())
+        .with_header("Sec-WebSocket-Accept", accept_key(key)))
+}
+
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(WEBSOCKET_GUID.as_bytes());
+    BASE64.encode(sha1.finalize())
+}
+
+/// Hands the connection over to the handler that accepted the upgrade, after the 101 went out.
+pub async fn take_over(ctx: RequestContext, handler: WebSocketHandler) -> Result<()> {
+    let RequestContext { reader, writer, .. } = ctx;
+    handler(WebSocket { reader, writer, closed: false }).await
+}
+
+/// A complete data message, reassembled from its fragments.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// The connection after a successful upgrade. Pings are answered and the closing handshake is
+/// completed while receiving, so handlers only deal with data messages.
+pub struct WebSocket {
+    reader: ConnectionReader,
+    writer: ConnectionWriter,
+    closed: bool,
+}
+
+impl WebSocket {
+    /// Waits for the next message, or `None` once the peer has closed the connection.
+    pub async fn recv(&mut self) -> Result<Option<Message>> {
+        let mut message: Option<(Opcode, Vec<u8>)> = None;
+        while !self.closed {
+            let frame = match read_frame(&mut self.reader).await {
+                Ok(frame) => frame,
+                Err(e) => return self.fail(CLOSE_PROTOCOL_ERROR, e).await,
+            };
+
+            match frame.opcode {
+                Opcode::Ping => write_frame(&mut self.writer, true, Opcode::Pong, &frame.payload).await?,
+                Opcode::Pong => {}
+                Opcode::Close => {
+                    debug!("WebSocket closed by peer");
+                    // Echo the status code back, as the closing handshake expects
+                    let code = frame.payload.get(..2).map(|code| code.to_vec()).unwrap_or_default();
+                    self.closed = true;
+                    write_frame(&mut self.writer, true, Opcode::Close, &code).await?;
+                }
+                Opcode::Text | Opcode::Binary if message.is_some() => {
+                    return self.fail(CLOSE_PROTOCOL_ERROR, anyhow!("New WebSocket message before the last one ended")).await;
+                }
+                Opcode::Continuation if message.is_none() => {
+                    return self.fail(CLOSE_PROTOCOL_ERROR, anyhow!("WebSocket continuation without a message")).await;
+                }
+                opcode => {
+                    let (kind, payload) = message.get_or_insert_with(|| (opcode, Vec::new()));
+                    if (payload.len() + frame.payload.len()) as u64 > MAX_FRAME_PAYLOAD {
+                        return self.fail(CLOSE_TOO_BIG, anyhow!("WebSocket message exceeds {} bytes", MAX_FRAME_PAYLOAD)).await;
+                    }
+                    payload.extend_from_slice(&frame.payload);
+                    if !frame.fin {
+                        continue;
+                    }
+
+                    let (kind, payload) = (*kind, std::mem::take(payload));
+                    return match kind {
+                        Opcode::Text => match String::from_utf8(payload) {
+                            Ok(text) => Ok(Some(Message::Text(text))),
+                            Err(_) => self.fail(CLOSE_INVALID_DATA, anyhow!("WebSocket text message isn't UTF-8")).await,
+                        },
+                        _ => Ok(Some(Message::Binary(payload))),
+                    };
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub async fn send(&mut self, message: Message) -> Result<()> {
+        match message {
+            Message::Text(text) => write_frame(&mut self.writer, true, Opcode::Text, text.as_bytes()).await,
+            Message::Binary(data) => write_frame(&mut self.writer, true, Opcode::Binary, &data).await,
+        }
+    }
+
+    /// Starts the closing handshake from this side.
+    #[allow(unused)]
+    pub async fn close(mut self, reason: &str) -> Result<()> {
+        self.send_close(CLOSE_NORMAL, reason).await
+    }
+
+    async fn send_close(&mut self, code: u16, reason: &str) -> Result<()> {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend(reason.bytes().take(MAX_CONTROL_PAYLOAD - payload.len()));
+        self.closed = true;
+        write_frame(&mut self.writer, true, Opcode::Close, &payload).await
+    }
+
+    /// Closes the connection with `code` after a protocol violation and reports it.
+    async fn fail<T>(&mut self, code: u16, e: anyhow::Error) -> Result<T> {
+        // The peer may well be gone already, which changes nothing about the outcome
+        let _ = self.send_close(code, "").await;
+        Err(e)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl TryFrom<u8> for Opcode {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        Ok(match value {
+            0x0 => Self::Continuation,
+            0x1 => Self::Text,
+            0x2 => Self::Binary,
+            0x8 => Self::Close,
+            0x9 => Self::Ping,
+            0xA => Self::Pong,
+            _ => bail!("Unknown WebSocket opcode {:#x}", value),
+        })
+    }
+}
+
+impl From<Opcode> for u8 {
+    fn from(opcode: Opcode) -> Self {
+        match opcode {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA,
+        }
+    }
+}
+
+struct Frame {
+    fin: bool,
+    opcode: Opcode,
+    payload: Vec<u8>,
+}
+
+async fn read_frame(reader: &mut ConnectionReader) -> Result<Frame> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header).await?;
+
+    let fin = header[0] & 0x80 != 0;
+    if header[0] & 0x70 != 0 {
+        bail!("Reserved WebSocket frame bits set without a negotiated extension");
+    }
+    let opcode = Opcode::try_from(header[0] & 0x0F)?;
+    if header[1] & 0x80 == 0 {
+        bail!("Client WebSocket frames must be masked");
+    }
+
+    let len = match header[1] & 0x7F {
+        126 => reader.read_u16().await? as u64,
+        127 => reader.read_u64().await?,
+        len => len as u64,
+    };
+    if len > MAX_FRAME_PAYLOAD {
+        bail!("WebSocket frame of {} bytes exceeds the {} byte limit", len, MAX_FRAME_PAYLOAD);
+    }
+    if matches!(opcode, Opcode::Close | Opcode::Ping | Opcode::Pong) && (!fin || len > MAX_CONTROL_PAYLOAD as u64) {
+        bail!("WebSocket control frames must be unfragmented and at most {} bytes", MAX_CONTROL_PAYLOAD);
+    }
+
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask).await?;
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+
+    Ok(Frame { fin, opcode, payload })
+}
+
+async fn write_frame(writer: &mut ConnectionWriter, fin: bool, opcode: Opcode, payload: &[u8]) -> Result<()> {
+    let mut header = vec![u8::from(opcode) | if fin { 0x80 } else { 0 }];
+    match payload.len() {
+        len if len < 126 => header.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            header.push(126);
+            header.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            header.push(127);
+            header.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+
+    writer.write_all(&header).await?;
+    writer.write_all(payload).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// `/ws/echo`, sending every message straight back.
+pub async fn echo_route(ctx: &mut RequestContext) -> HttpResult<HttpResponse> {
+    accept(ctx, echo)
+}
+
+fn echo(ws: WebSocket) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+    Box::pin(echo_messages(ws))
+}
+
+async fn echo_messages(mut ws: WebSocket) -> Result<()> {
+    while let Some(message) = ws.recv().await? {
+        ws.send(message).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use crate::testing::{configure, exchange, exchange_bytes, header, temp_dir};
+    use super::*;
+
+    const UPGRADE: &str = concat!(
+        "GET /ws/echo HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n",
+        "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+    );
+
+    /// A frame as a client has to send it, masked.
+    fn client_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn accept_key_matches_rfc_6455() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[tokio::test]
+    async fn echo_sends_a_text_frame_back() {
+        let _config = configure(&[]).await;
+        let mut request = UPGRADE.as_bytes().to_vec();
+        request.extend(client_frame(0x1, b"hello"));
+        request.extend(client_frame(0x8, &CLOSE_NORMAL.to_be_bytes()));
+
+        let response = exchange_bytes(&request).await;
+        let head_end = response.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+        let (head, frames) = response.split_at(head_end);
+        let head = String::from_utf8_lossy(head);
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{}", head);
+        assert_eq!(header(&head, "Sec-WebSocket-Accept"), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        assert_eq!(frames, b"\x81\x05hello\x88\x02\x03\xe8");
+    }
+
+    /// The frames the server sends back for `frames` sent after the upgrade.
+    async fn echoed(frames: &[Vec<u8>]) -> Vec<u8> {
+        let mut request = UPGRADE.as_bytes().to_vec();
+        request.extend(frames.concat());
+        let response = exchange_bytes(&request).await;
+        let head_end = response.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+        response[head_end..].to_vec()
+    }
+
+    #[tokio::test]
+    async fn long_payloads_use_the_extended_lengths() {
+        let _config = configure(&[]).await;
+        let medium: Vec<u8> = (0..300u32).map(|i| i as u8).collect();
+        let large: Vec<u8> = (0..70_000u32).map(|i| (i % 251) as u8).collect();
+        let close = client_frame(0x8, &CLOSE_NORMAL.to_be_bytes());
+
+        let frames = echoed(&[client_frame(0x2, &medium), close.clone()]).await;
+        let (header, rest) = frames.split_at(4);
+        assert_eq!(header, [0x82, 126, 0x01, 0x2c]);
+        assert_eq!(&rest[..300], medium.as_slice());
+
+        let frames = echoed(&[client_frame(0x2, &large), close]).await;
+        let (header, rest) = frames.split_at(10);
+        assert_eq!(header[..2], [0x82, 127]);
+        assert_eq!(u64::from_be_bytes(header[2..].try_into().unwrap()), 70_000);
+        assert!(rest[..70_000] == large[..]);
+        assert_eq!(&rest[70_000..], b"\x88\x02\x03\xe8");
+    }
+
+    #[tokio::test]
+    async fn close_status_is_echoed_back() {
+        let _config = configure(&[]).await;
+        let mut close = 1001u16.to_be_bytes().to_vec();
+        close.extend_from_slice(b"going away");
+
+        // Nothing after the close is read as another message
+        let frames = echoed(&[client_frame(0x8, &close), client_frame(0x1, b"late")]).await;
+        assert_eq!(frames, b"\x88\x02\x03\xe9");
+    }
+
+    #[tokio::test]
+    async fn plain_get_is_told_to_upgrade() {
+        let _config = configure(&[]).await;
+
+        let response = exchange(b"GET /ws/echo HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"), "{}", response);
+        assert_eq!(header(&response, "Upgrade"), Some("websocket"));
+    }
+
+    #[tokio::test]
+    async fn upgrade_is_authenticated_first() {
+        let htpasswd = temp_dir("websocket-auth").join("htpasswd");
+        std::fs::write(&htpasswd, "user:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\n").unwrap();
+        let rule = format!("/ws/={}", htpasswd.display());
+        let _config = configure(&["--basic-auth", &rule]).await;
+
+        let response = exchange(UPGRADE.as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{}", response);
+    }
+}